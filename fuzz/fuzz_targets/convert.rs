@@ -0,0 +1,13 @@
+// Feeds arbitrary input to both converters, which may reject it (with
+// an error) but must not panic. Run with `cargo fuzz run convert`.
+#![no_main]
+#[macro_use] extern crate libfuzzer_sys;
+extern crate tango;
+
+use tango::Playground;
+
+fuzz_target!(|data: &[u8]| {
+    let playground = Playground::default();
+    let _ = tango::md2rs(&playground, data, &mut Vec::new());
+    let _ = tango::rs2md(&playground, data, &mut Vec::new());
+});
@@ -0,0 +1,281 @@
+// Noweb-style named chunks, for presenting code in narrative order
+// rather than the order the compiler needs it in.
+//
+// A Rust code block whose first line is `<<name>>=` defines the chunk
+// `name` instead of contributing to the file directly; a later block
+// that starts `<<name>>=` (or `<<name>>+=`) appends to it. Within any
+// code block, a line consisting of just `<<name>>` (plus indentation)
+// is replaced by the whole chunk, each line indented to match.
+//
+// `md2rs` first writes the `.rs` in narrative order, with each
+// definition's body bracketed by its `//@@<<name>>=` header and a
+// `//@@>>` line, and then `tangle` moves the bodies to where they are
+// used, leaving just the headers behind:
+//
+//     //@ The main program:
+//
+//     fn main() {
+//         //@@<<helpers>>
+//         fn helper() {}
+//         //@@+
+//         fn other_helper() {}
+//         //@@>>
+//         helper();
+//     }
+//
+//     //@ The helpers come later:
+//
+//     //@@<<helpers>>=
+//
+//     //@ And one more:
+//
+//     //@@<<helpers>>+=
+//
+// Each piece of an expansion (separated by `//@@+`) belongs to the
+// corresponding header, in order, so `untangle` can put the code,
+// edited or not, back where the narrative had it before `rs2md` runs.
+//
+// A chunk that is never used keeps its code where it is defined, as
+// if it were an ordinary block, between its header and `//@@>>`.
+
+use std::collections::BTreeMap;
+
+use super::Warning;
+
+pub const CLOSE: &'static str = "//@@>>";
+pub const SEPARATOR: &'static str = "//@@+";
+
+// If `line` is a definition header (`<<name>>=` or `<<name>>+=`),
+// returns the name and the `=` or `+=`.
+pub fn header(line: &str) -> Option<(&str, &str)> {
+    let line = line.trim();
+    let (rest, op) = if line.ends_with(">>+=") {
+        (&line[..line.len() - 2], "+=")
+    } else if line.ends_with(">>=") {
+        (&line[..line.len() - 1], "=")
+    } else {
+        return None;
+    };
+    reference(rest).map(|name| (name, op))
+}
+
+// If `line` (ignoring indentation) is a reference `<<name>>`, returns
+// the name.
+pub fn reference(line: &str) -> Option<&str> {
+    let line = line.trim();
+    if line.len() > 4 && line.starts_with("<<") && line.ends_with(">>") {
+        let name = &line[2..line.len() - 2];
+        if !name.contains("<<") && !name.contains(">>") && !name.trim().is_empty() {
+            return Some(name);
+        }
+    }
+    None
+}
+
+// True if `line` opens the expansion of a chunk in the `.rs`.
+pub fn opens(line: &str) -> bool {
+    after(line.trim_left(), "//@@").and_then(reference).is_some()
+}
+
+fn indentation(line: &str) -> &str {
+    &line[..line.len() - line.trim_left().len()]
+}
+
+fn join(lines: &[String], text: &str) -> String {
+    let mut result = lines.join("\n");
+    if text.ends_with('\n') {
+        result.push('\n');
+    }
+    result
+}
+
+// Turns the narrative-order `.rs` text written by `md2rs` into the
+// compiled order, as described above.
+pub fn tangle(text: &str) -> (String, Vec<Warning>) {
+    if !text.lines().any(|l| l.starts_with("//@@<<")) {
+        return (text.to_string(), vec![]);
+    }
+    let mut chunks = Chunks { order: vec![], pieces: BTreeMap::new() };
+    let mut outer = Vec::new();
+    let mut lines = text.lines();
+    while let Some(line) = lines.next() {
+        outer.push(line.to_string());
+        let name = match after(line, "//@@").and_then(header) {
+            Some((name, _)) => name.to_string(),
+            None => continue,
+        };
+        let body = lines.by_ref().take_while(|&l| l != CLOSE).map(|l| l.to_string()).collect();
+        if !chunks.pieces.contains_key(&name) {
+            chunks.order.push(name.clone());
+        }
+        chunks.pieces.entry(name).or_insert_with(Vec::new).push(body);
+    }
+
+    // How often each chunk is referred to, anywhere.
+    let mut uses: BTreeMap<&str, usize> = BTreeMap::new();
+    for line in outer.iter().chain(chunks.pieces.values().flat_map(|p| p.iter().flat_map(|b| b))) {
+        if let Some(name) = reference(line) {
+            *uses.entry(name).or_insert(0) += 1;
+        }
+    }
+
+    let mut warnings = Vec::new();
+    let mut result = Vec::new();
+    let mut seen: BTreeMap<String, usize> = BTreeMap::new();
+    for line in &outer {
+        let name = match after(line, "//@@").and_then(header) {
+            Some((name, _)) => name.to_string(),
+            None => {
+                result.extend(chunks.expand(&[line.clone()], &mut vec![], &mut warnings));
+                continue;
+            }
+        };
+        result.push(line.clone());
+        let k = seen.entry(name.clone()).or_insert(0);
+        if !uses.contains_key(&name[..]) {
+            // An unused chunk stays where it is defined.
+            let piece = &chunks.pieces[&name][*k];
+            result.extend(chunks.expand(piece, &mut vec![name.clone()], &mut warnings));
+            result.push(CLOSE.to_string());
+        }
+        *k += 1;
+    }
+    for name in &chunks.order {
+        match uses.get(&name[..]).cloned().unwrap_or(0) {
+            0 => warnings.push(Warning::UnusedChunk { name: name.clone() }),
+            1 => {}
+            _ => warnings.push(Warning::ReusedChunk { name: name.clone() }),
+        }
+    }
+    (join(&result, text), warnings)
+}
+
+struct Chunks {
+    // Chunk names, in order of first definition.
+    order: Vec<String>,
+    pieces: BTreeMap<String, Vec<Vec<String>>>,
+}
+
+impl Chunks {
+    // Expands every reference within `lines`; `active` holds the
+    // chunks being expanded already.
+    fn expand(&self, lines: &[String], active: &mut Vec<String>,
+              warnings: &mut Vec<Warning>) -> Vec<String> {
+        let mut result = Vec::new();
+        for line in lines {
+            let name = match reference(line) {
+                Some(name) => name.to_string(),
+                None => {
+                    result.push(line.clone());
+                    continue;
+                }
+            };
+            let pieces = match self.pieces.get(&name) {
+                Some(pieces) if !active.contains(&name) => pieces,
+                Some(_) => {
+                    warnings.push(Warning::RecursiveChunk { name: name });
+                    result.push(line.clone());
+                    continue;
+                }
+                None => {
+                    warnings.push(Warning::UndefinedChunk { name: name });
+                    result.push(line.clone());
+                    continue;
+                }
+            };
+            let indent = indentation(line);
+            result.push(format!("{}//@@<<{}>>", indent, name));
+            active.push(name);
+            for (i, piece) in pieces.iter().enumerate() {
+                if i > 0 {
+                    result.push(format!("{}{}", indent, SEPARATOR));
+                }
+                for l in self.expand(piece, active, warnings) {
+                    result.push(if l.is_empty() { l } else { format!("{}{}", indent, l) });
+                }
+            }
+            active.pop();
+            result.push(format!("{}{}", indent, CLOSE));
+        }
+        result
+    }
+}
+
+// The inverse of `tangle`: puts each piece of code back after its
+// definition header, and each expansion back to a reference.
+pub fn untangle(text: &str) -> String {
+    let lines: Vec<&str> = text.lines().collect();
+    let mut pieces = BTreeMap::new();
+    let outer = collapse(&lines, &mut pieces);
+    if pieces.is_empty() && !lines.iter().any(|l| l.trim_left().starts_with("//@@<<")) {
+        return text.to_string();
+    }
+    let mut seen: BTreeMap<String, usize> = BTreeMap::new();
+    let mut result = Vec::new();
+    for line in outer {
+        let name = after(line.trim_left(), "//@@")
+            .and_then(header).map(|(name, _)| name.to_string());
+        result.push(line);
+        // A chunk expanded nowhere still has its code (and closing
+        // line) right here.
+        let name = match name { Some(name) => name, None => continue };
+        let found = match pieces.get(&name) { Some(found) => found, None => continue };
+        let k = seen.entry(name).or_insert(0);
+        if let Some(piece) = found.get(*k) {
+            result.extend(piece.iter().cloned());
+        }
+        *k += 1;
+        result.push(CLOSE.to_string());
+    }
+    join(&result, text)
+}
+
+// Replaces each (outermost) expansion in `lines` by a reference,
+// recording the pieces of the first expansion of each chunk.
+fn collapse(lines: &[&str], pieces: &mut BTreeMap<String, Vec<Vec<String>>>) -> Vec<String> {
+    let mut result = Vec::new();
+    let mut i = 0;
+    while i < lines.len() {
+        let line = lines[i];
+        i += 1;
+        let name = match after(line.trim_left(), "//@@").and_then(reference) {
+            Some(name) => name.to_string(),
+            None => {
+                result.push(line.to_string());
+                continue;
+            }
+        };
+        let indent = indentation(line);
+        let mut found: Vec<Vec<String>> = vec![vec![]];
+        let mut depth = 0;
+        while i < lines.len() {
+            let l = lines[i];
+            i += 1;
+            let t = l.trim_left();
+            if t == CLOSE && depth == 0 {
+                break;
+            } else if t == CLOSE {
+                depth -= 1;
+            } else if t == SEPARATOR && depth == 0 {
+                found.push(vec![]);
+                continue;
+            } else if opens(t) {
+                depth += 1;
+            }
+            let l = if l.starts_with(indent) { &l[indent.len()..] } else { t };
+            found.last_mut().unwrap().push(l.to_string());
+        }
+        let found = found.iter().map(|piece| {
+            let piece: Vec<&str> = piece.iter().map(|l| &l[..]).collect();
+            collapse(&piece, pieces)
+        }).collect();
+        pieces.entry(name.clone()).or_insert(found);
+        result.push(format!("{}<<{}>>", indent, name));
+    }
+    result
+}
+
+// The rest of `line` after `prefix`, if it starts with it.
+fn after<'a>(line: &'a str, prefix: &str) -> Option<&'a str> {
+    if line.starts_with(prefix) { Some(&line[prefix.len()..]) } else { None }
+}
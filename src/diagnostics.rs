@@ -0,0 +1,447 @@
+// `tango diagnostics`: reads the output of
+// `cargo build --message-format=json` and reports each diagnostic
+// against the `.md` that the offending code came from, rather than
+// the `.rs` that rustc compiled.
+//
+// Every span of every compiler message is rewritten (those of child
+// diagnostics, suggestions and macro expansions included): its file,
+// lines and columns, its byte offsets and the source text it quotes.
+// The result is written either as rustc-style text or as JSON again,
+// in the same form cargo wrote it, with the `rendered` text redone to
+// match; other messages are passed through (or, as text, dropped).
+//
+// A span is mapped with a `SourceMap`, built by converting the `.md`
+// once more with every line of code tagged with its origin (see
+// `md2rs::ORIGIN`). A span in a `.rs` that does not correspond to its
+// `.md` (because one was edited since tango last ran), or that does not
+// fall on code from the `.md` at both ends, is left alone.
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufRead, Read, Write};
+use std::path::{Path, PathBuf};
+
+use serde_json::Value;
+
+use super::{encoding, files, md2rs, relative_name, Config, MdPath, Result, Root, RsPath};
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Format { Human, Json }
+
+pub fn map_diagnostics<R: BufRead, W: Write>(config: &Config, input: R, mut output: W,
+                                             format: Format) -> Result<()> {
+    let mut maps = Maps::new(config);
+    for line in input.lines() {
+        let line = try!(line);
+        let mut value: Value = match ::serde_json::from_str(&line) {
+            Ok(value) => value,
+            Err(_) => {
+                try!(writeln!(output, "{}", line));
+                continue;
+            }
+        };
+        if value["reason"] != "compiler-message" || !value["message"].is_object() {
+            if format == Format::Json {
+                try!(writeln!(output, "{}", line));
+            }
+            continue;
+        }
+        let rendered = if maps.map_diagnostic(&mut value["message"]) {
+            let rendered = render(&value["message"]);
+            value["message"]["rendered"] = Value::String(rendered.clone());
+            rendered
+        } else {
+            str_of(&value["message"]["rendered"]).to_string()
+        };
+        match format {
+            Format::Json => try!(writeln!(output, "{}", value)),
+            Format::Human => try!(write!(output, "{}", rendered)),
+        }
+    }
+    Ok(())
+}
+
+// Where each line of a `.rs` (or of a file produced from a `.md`, see
+// `files`) came from.
+pub struct SourceMap {
+    pub md: PathBuf,
+    md_lines: Vec<String>,
+    line_starts: Vec<usize>,
+    rs_lines: Vec<String>,
+    // For each line of the `.rs`, the line of the `.md` (from zero).
+    origins: Vec<Option<usize>>,
+}
+
+impl SourceMap {
+    // Maps the `.rs` file `file` (the primary if `None`) that `md`,
+    // known as `md_name` and holding `md_text`, produces; returns
+    // `None` if that is not what `rs_text` holds.
+    pub fn new(config: &Config, md: &Path, md_name: &str, md_text: &str,
+               file: Option<&str>, rs_text: &str) -> Option<SourceMap> {
+        // The converter sees the text without its BOM and with `\n`
+        // endings, as when the `.rs` was written (see `encoding`); but
+        // byte offsets are within the `.md` as it is.
+        let raw_text = md_text;
+        let (style, md_text, _) = encoding::strip(raw_text);
+        let converter = md2rs::Converter::new(config.playground.clone()).with_origins();
+        let mut tagged = Vec::new();
+        match converter.convert(md_text.as_bytes(), &mut tagged) {
+            Ok(()) | Err(md2rs::Exception::Warnings(_)) => {}
+            Err(md2rs::Exception::IoError(_)) | Err(md2rs::Exception::Invalid(_)) => return None,
+        }
+        let (primary, produced) = match files::split(&String::from_utf8_lossy(&tagged), md_name) {
+            Ok(split) => split,
+            Err(_) => return None,
+        };
+        let content = match file {
+            None => primary,
+            Some(file) => match produced.into_iter().find(|p| p.0 == file) {
+                Some((_, content)) => content,
+                None => return None,
+            },
+        };
+
+        let mut rs_lines = Vec::new();
+        let mut origins = Vec::new();
+        for line in content.lines() {
+            match line.find(md2rs::ORIGIN) {
+                Some(i) => {
+                    rs_lines.push(line[..i].to_string());
+                    origins.push(line[i + md2rs::ORIGIN.len()..].parse().ok());
+                }
+                None => {
+                    rs_lines.push(line.to_string());
+                    origins.push(None);
+                }
+            }
+        }
+        if rs_lines.iter().map(|l| &l[..]).ne(encoding::strip(rs_text).1.lines()) {
+            return None;
+        }
+        fill_gaps(&mut origins);
+
+        let mut line_starts = vec![if style.bom { '\u{feff}'.len_utf8() } else { 0 }];
+        line_starts.extend(raw_text.match_indices('\n').map(|(i, _)| i + 1));
+        Some(SourceMap {
+            md: md.to_path_buf(),
+            md_lines: md_text.lines().map(|l| l.to_string()).collect(),
+            line_starts: line_starts,
+            rs_lines: rs_lines,
+            origins: origins,
+        })
+    }
+
+    // The line and column in the `.md` (both from one, as rustc counts
+    // them) of the given line and column in the `.rs`.
+    pub fn locate(&self, line: usize, column: usize) -> Option<(usize, usize)> {
+        let md_line = match self.origins.get(line.wrapping_sub(1)) {
+            Some(&Some(md_line)) => md_line,
+            _ => return None,
+        };
+        // What a line gains or loses on its way to the `.rs` (chunk
+        // indentation, a blockquote prefix, a hidden line's `#`) is
+        // all at its start.
+        let rs_len = self.rs_lines[line - 1].chars().count();
+        let md_len = self.md_lines[md_line].chars().count();
+        let column = (column + md_len).saturating_sub(rs_len);
+        Some((md_line + 1, ::std::cmp::max(column, 1)))
+    }
+
+    // What line `line` of the `.rs` has in place of the start of the
+    // `.md` line it came from (say, the indentation of a chunk), and
+    // what that `.md` line has instead (say, a blockquote's `> `).
+    pub fn prefixes(&self, line: usize) -> Option<(&str, &str)> {
+        let md_line = match self.origins.get(line.wrapping_sub(1)) {
+            Some(&Some(md_line)) => md_line,
+            _ => return None,
+        };
+        let (rs, md) = (&self.rs_lines[line - 1], &self.md_lines[md_line]);
+        let common: usize = rs.chars().rev().zip(md.chars().rev())
+            .take_while(|&(a, b)| a == b)
+            .map(|(a, _)| a.len_utf8())
+            .sum();
+        Some((&rs[..rs.len() - common], &md[..md.len() - common]))
+    }
+
+    // The line of the `.md`, from one.
+    pub fn line(&self, line: usize) -> &str {
+        &self.md_lines[line - 1]
+    }
+
+    // The byte offset within the `.md` of a line and column from
+    // `locate`.
+    pub fn offset(&self, line: usize, column: usize) -> usize {
+        let text = &self.md_lines[line - 1];
+        let within = text.char_indices().nth(column - 1).map_or(text.len(), |(i, _)| i);
+        self.line_starts[line - 1] + within
+    }
+}
+
+// Gives a line with no origin of its own (say, a blank line within a
+// block) the one its neighbours imply, if they agree on it.
+fn fill_gaps(origins: &mut [Option<usize>]) {
+    let known: Vec<(usize, usize)> = origins.iter().enumerate()
+        .filter_map(|(i, o)| o.map(|o| (i, o)))
+        .collect();
+    for pair in known.windows(2) {
+        let ((i, a), (j, b)) = (pair[0], pair[1]);
+        if j - i > 1 && b > a && b - a == j - i {
+            for k in i + 1..j {
+                origins[k] = Some(a + (k - i));
+            }
+        }
+    }
+}
+
+// The source maps built so far, by the path as cargo gave it.
+pub struct Maps<'a> {
+    config: &'a Config,
+    maps: BTreeMap<String, Option<SourceMap>>,
+}
+
+impl<'a> Maps<'a> {
+    pub fn new(config: &'a Config) -> Maps<'a> {
+        Maps { config: config, maps: BTreeMap::new() }
+    }
+
+    pub fn get(&mut self, file_name: &str) -> Option<&SourceMap> {
+        if !self.maps.contains_key(file_name) {
+            let map = self.load(file_name);
+            self.maps.insert(file_name.to_string(), map);
+        }
+        self.maps[file_name].as_ref()
+    }
+
+    fn load(&self, file_name: &str) -> Option<SourceMap> {
+        let config = self.config;
+        let rs = files::normalize(&config.root.join(file_name));
+        let rs_text = match read(&rs) { Some(text) => text, None => return None };
+        let (md, file) = match files::produced_from(&rs) {
+            Ok(Some(md_name)) => {
+                let md = files::normalize(&config.root.join(md_name));
+                let primary = match md_to_rs(config, &md) { Some(p) => p, None => return None };
+                let dir = primary.parent().unwrap_or(Path::new("")).to_path_buf();
+                let primary_text = match read(&primary) { Some(text) => text, None => return None };
+                let file = files::placeholders(&primary_text).into_iter()
+                    .find(|f| files::normalize(&dir.join(f)) == rs);
+                match file {
+                    Some(file) => (md, Some(file)),
+                    None => return None,
+                }
+            }
+            Ok(None) => match rs_to_md(config, &rs) {
+                Some(md) => (md, None),
+                None => return None,
+            },
+            Err(_) => return None,
+        };
+        let md_text = match read(&md) { Some(text) => text, None => return None };
+        let md_name = relative_name(&config.root, &md);
+        SourceMap::new(config, &md, &md_name, &md_text, file.as_ref().map(|f| &f[..]), &rs_text)
+    }
+
+    // Maps every span within `diagnostic`, returning true if any of
+    // them moved to a `.md`.
+    fn map_diagnostic(&mut self, diagnostic: &mut Value) -> bool {
+        let mut mapped = false;
+        if let Some(spans) = diagnostic["spans"].as_array_mut() {
+            for span in spans {
+                mapped |= self.map_span(span);
+            }
+        }
+        if let Some(children) = diagnostic["children"].as_array_mut() {
+            for child in children {
+                mapped |= self.map_diagnostic(child);
+            }
+        }
+        mapped
+    }
+
+    fn map_span(&mut self, span: &mut Value) -> bool {
+        let mut mapped = false;
+        if span["expansion"].is_object() {
+            mapped |= self.map_span(&mut span["expansion"]["span"]);
+            mapped |= self.map_span(&mut span["expansion"]["def_site_span"]);
+        }
+        let relative = match span["file_name"].as_str() {
+            Some(f) => Path::new(f).is_relative(),
+            None => return mapped,
+        };
+        let numbers = ["line_start", "column_start", "line_end", "column_end"].iter()
+            .map(|k| span[*k].as_u64().map(|n| n as usize))
+            .collect::<Option<Vec<usize>>>();
+        let n = match numbers { Some(n) => n, None => return mapped };
+        let root = self.config.root.clone();
+        let map = match span["file_name"].as_str().and_then(|f| self.get(f)) {
+            Some(map) => map,
+            None => return mapped,
+        };
+        let (start, end) = match (map.locate(n[0], n[1]), map.locate(n[2], n[3])) {
+            (Some(start), Some(end)) if start <= end => (start, end),
+            _ => return mapped,
+        };
+        let text: Vec<Value> = (start.0..end.0 + 1).map(|l| {
+            let line = map.line(l);
+            json!({
+                "text": line,
+                "highlight_start": if l == start.0 { start.1 } else { 1 },
+                "highlight_end": if l == end.0 { end.1 } else { line.chars().count() + 1 },
+            })
+        }).collect();
+        span["file_name"] = Value::String(if relative {
+            relative_name(&root, &map.md)
+        } else {
+            map.md.display().to_string()
+        });
+        span["line_start"] = json!(start.0);
+        span["column_start"] = json!(start.1);
+        span["line_end"] = json!(end.0);
+        span["column_end"] = json!(end.1);
+        span["byte_start"] = json!(map.offset(start.0, start.1));
+        span["byte_end"] = json!(map.offset(end.0, end.1));
+        span["text"] = Value::Array(text);
+        true
+    }
+}
+
+fn read(p: &Path) -> Option<String> {
+    let mut text = String::new();
+    match File::open(p).and_then(|mut f| f.read_to_string(&mut text)) {
+        Ok(_) => Some(text),
+        Err(_) => None,
+    }
+}
+
+fn rs_to_md(config: &Config, rs: &Path) -> Option<PathBuf> {
+    Root::all(config).into_iter()
+        .find(|r| rs.starts_with(&r.src) && rs.extension().map_or(false, |e| e == "rs"))
+        .and_then(|r| RsPath::new(rs.to_path_buf(), r).and_then(|rs| rs.to_md()).ok())
+        .map(|md| md.0)
+        .and_then(|md| if md.exists() { Some(md) } else { None })
+}
+
+fn md_to_rs(config: &Config, md: &Path) -> Option<PathBuf> {
+    Root::all(config).into_iter()
+        .find(|r| md.starts_with(&r.lit) && md.extension().map_or(false, |e| e == "md"))
+        .and_then(|r| MdPath::new(md.to_path_buf(), r).and_then(|md| md.to_rs()).ok())
+        .map(|rs| rs.0)
+}
+
+// Renders `diagnostic` the way rustc would (if more plainly), as the
+// `rendered` text of cargo's JSON.
+pub fn render(diagnostic: &Value) -> String {
+    let mut out = String::new();
+    let spans = spans_of(diagnostic);
+    let width = gutter(diagnostic);
+    heading(&mut out, diagnostic);
+    snippets(&mut out, &spans, width);
+    if let Some(children) = diagnostic["children"].as_array() {
+        for child in children {
+            let spans = spans_of(child);
+            if spans.is_empty() {
+                out.push_str(&format!("{:w$} = {}: {}\n", "", str_of(&child["level"]),
+                                      str_of(&child["message"]), w = width));
+                continue;
+            }
+            let suggestions: Vec<&str> = spans.iter()
+                .filter_map(|s| s["suggested_replacement"].as_str())
+                .collect();
+            if suggestions.is_empty() {
+                heading(&mut out, child);
+                snippets(&mut out, &spans, width);
+            } else {
+                out.push_str(&format!("{}: {}: `{}`\n", str_of(&child["level"]),
+                                      str_of(&child["message"]), suggestions.join("`, `")));
+                location(&mut out, spans[0], width);
+            }
+        }
+    }
+    out.push('\n');
+    out
+}
+
+fn str_of(v: &Value) -> &str {
+    v.as_str().unwrap_or("")
+}
+
+fn spans_of(diagnostic: &Value) -> Vec<&Value> {
+    let mut spans: Vec<&Value> = diagnostic["spans"].as_array()
+        .map_or(vec![], |s| s.iter().collect());
+    // The primary span first, as rustc puts it in the `-->` line.
+    spans.sort_by_key(|s| !s["is_primary"].as_bool().unwrap_or(false));
+    spans
+}
+
+// The width of the widest line number `diagnostic` will show.
+fn gutter(diagnostic: &Value) -> usize {
+    let mut widest = 0;
+    let mut all = vec![diagnostic];
+    if let Some(children) = diagnostic["children"].as_array() {
+        all.extend(children.iter());
+    }
+    for d in all {
+        for s in spans_of(d) {
+            widest = ::std::cmp::max(widest, s["line_start"].as_u64().unwrap_or(0));
+        }
+    }
+    widest.to_string().len()
+}
+
+fn heading(out: &mut String, diagnostic: &Value) {
+    let level = str_of(&diagnostic["level"]);
+    match diagnostic["code"]["code"].as_str() {
+        Some(code) => out.push_str(&format!("{}[{}]: ", level, code)),
+        None => out.push_str(&format!("{}: ", level)),
+    }
+    out.push_str(str_of(&diagnostic["message"]));
+    out.push('\n');
+}
+
+fn location(out: &mut String, span: &Value, width: usize) {
+    out.push_str(&format!("{:w$}--> {}:{}:{}\n", "", str_of(&span["file_name"]),
+                          span["line_start"], span["column_start"], w = width));
+}
+
+// Quotes the line of each span, marked up, in order of file and line
+// (the primary span's file first), with a line that several spans
+// share quoted once.
+fn snippets(out: &mut String, spans: &[&Value], width: usize) {
+    if spans.is_empty() {
+        return;
+    }
+    let primary_file = str_of(&spans[0]["file_name"]);
+    let mut sorted = spans.to_vec();
+    sorted.sort_by_key(|s| (str_of(&s["file_name"]) != primary_file, str_of(&s["file_name"]),
+                            s["line_start"].as_u64()));
+    location(out, spans[0], width);
+    out.push_str(&format!("{:w$} |\n", "", w = width));
+    let mut previous: Option<(&str, u64)> = None;
+    for span in sorted {
+        let first = &span["text"][0];
+        let line = match first["text"].as_str() { Some(line) => line, None => continue };
+        let here = (str_of(&span["file_name"]), span["line_start"].as_u64().unwrap_or(0));
+        if previous.map_or(false, |p| p.0 != here.0) {
+            out.push_str(&format!("{:w$}::: {}:{}:{}\n", "", here.0,
+                                  span["line_start"], span["column_start"], w = width));
+        }
+        if previous != Some(here) {
+            out.push_str(&format!("{:>w$} | {}\n", here.1, line, w = width));
+        }
+        previous = Some(here);
+        let start = first["highlight_start"].as_u64().unwrap_or(1) as usize;
+        let end = first["highlight_end"].as_u64().unwrap_or(start as u64) as usize;
+        let mark = if span["is_primary"].as_bool().unwrap_or(false) { "^" } else { "-" };
+        out.push_str(&format!("{:w$} | {}{}", "",
+                              line.chars().take(start.saturating_sub(1))
+                                  .map(|c| if c == '\t' { '\t' } else { ' ' })
+                                  .collect::<String>(),
+                              mark.repeat(::std::cmp::max(end.saturating_sub(start), 1)),
+                              w = width));
+        if let Some(label) = span["label"].as_str() {
+            out.push(' ');
+            out.push_str(label);
+        }
+        out.push('\n');
+    }
+    out.push_str(&format!("{:w$} |\n", "", w = width));
+}
@@ -0,0 +1,112 @@
+// How each file's text is laid out, apart from what it says: its line
+// endings (`\n` or `\r\n`) and whether it starts with a UTF-8 byte
+// order mark. The converters only ever see `\n` endings and no BOM;
+// what they write is laid out the way their input was, unless the
+// `line-endings` setting asks for one kind of ending everywhere:
+//
+//     line-endings = "lf"
+//
+// A file must be UTF-8. One that is not is an `Error::InvalidInput`
+// naming the line (and column) of the first byte that is out of place.
+
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+use super::md2rs::Invalid;
+use super::{Error, Result};
+
+const BOM: char = '\u{feff}';
+
+// What the `line-endings` setting asks for.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum LineEndings { Preserve, Lf, Crlf }
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Style {
+    pub crlf: bool,
+    pub bom: bool,
+}
+
+impl Style {
+    // The style of `text`, whose endings are taken to be those of its
+    // first line.
+    pub fn of(text: &str) -> Style {
+        Style {
+            crlf: text.find('\n').map_or(false, |i| text[..i].ends_with('\r')),
+            bom: text.starts_with(BOM),
+        }
+    }
+
+    // This style, with the line endings that `endings` asks for.
+    pub fn with(self, endings: LineEndings) -> Style {
+        match endings {
+            LineEndings::Preserve => self,
+            LineEndings::Lf => Style { crlf: false, ..self },
+            LineEndings::Crlf => Style { crlf: true, ..self },
+        }
+    }
+
+    // `text`, which has `\n` endings and no BOM, laid out in this style.
+    pub fn apply(&self, text: &str) -> String {
+        let mut result = String::new();
+        if self.bom {
+            result.push(BOM);
+        }
+        if self.crlf {
+            result.push_str(&text.replace('\n', "\r\n"));
+        } else {
+            result.push_str(text);
+        }
+        result
+    }
+}
+
+// `text` with `\n` endings and no BOM, along with the style it had;
+// and the first line (from zero) whose ending does not match that
+// style, if its endings are mixed.
+pub fn strip(text: &str) -> (Style, String, Option<usize>) {
+    let style = Style::of(text);
+    let body = if style.bom { &text[BOM.len_utf8()..] } else { text };
+    let stripped = body.replace("\r\n", "\n");
+    let endings = Style { bom: false, ..style }.apply(&stripped);
+    let mixed = body.split('\n').zip(endings.split('\n')).position(|(a, b)| a != b);
+    (style, stripped, mixed)
+}
+
+// `text` (laid out in any style) with the line endings that
+// `endings` asks for.
+pub fn restyle(text: &str, endings: LineEndings) -> String {
+    if endings == LineEndings::Preserve {
+        return text.to_string();
+    }
+    let (style, stripped, _) = strip(text);
+    style.with(endings).apply(&stripped)
+}
+
+// The text of a file whose content is `bytes`.
+pub fn decode(bytes: Vec<u8>) -> ::std::result::Result<String, Invalid> {
+    if bytes.starts_with(&[0xff, 0xfe]) || bytes.starts_with(&[0xfe, 0xff]) {
+        return Err(Invalid {
+            line: 1,
+            message: "is UTF-16, which tango does not read; save it as UTF-8".to_string(),
+        });
+    }
+    String::from_utf8(bytes).map_err(|e| {
+        let valid = &e.as_bytes()[..e.utf8_error().valid_up_to()];
+        let byte = e.as_bytes()[valid.len()];
+        let start = valid.iter().rposition(|&b| b == b'\n').map_or(0, |i| i + 1);
+        Invalid {
+            line: valid.iter().filter(|&&b| b == b'\n').count() + 1,
+            message: format!("is not valid UTF-8 (byte 0x{:02x} at column {})", byte,
+                             String::from_utf8_lossy(&valid[start..]).chars().count() + 1),
+        }
+    })
+}
+
+// Reads the text file at `p`.
+pub fn read(p: &Path) -> Result<String> {
+    let mut bytes = Vec::new();
+    try!(try!(File::open(p)).read_to_end(&mut bytes));
+    decode(bytes).map_err(|i| Error::from(i).at(p))
+}
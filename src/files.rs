@@ -0,0 +1,442 @@
+// Code blocks with a `file` attribute, through which one document can
+// drive a whole module tree.
+//
+// `{.rust file="parser/lexer.rs"}` sends the code of a block to
+// `parser/lexer.rs`, relative to the directory of the document's own
+// `.rs` (its primary); later blocks for the same file append to it.
+// `md2rs` writes such code in narrative order, between a
+// `//@@file parser/lexer.rs` line and a `//@@>>` line, and `split` then
+// moves it out, leaving the `//@@file` line behind as a placeholder.
+// Each file produced this way starts with a `//@@tangled from` line
+// naming the document, and separates the code of successive blocks
+// with `//@@+`, so that `join` can put everything back before `rs2md`
+// runs:
+//
+//     //@@tangled from src/parser.md
+//     pub fn lex(s: &str) -> Vec<Token> { ... }
+//     //@@+
+//     fn is_ident(c: char) -> bool { ... }
+//
+// The `mod` declarations for the produced files are generated too,
+// each with a `#[path]` (which, outside of inline modules, is relative
+// to the declaring file's directory), so that it does not matter where
+// in the module tree the declaring file sits. A file is declared by
+// the produced file of its parent module (`parser.rs` or
+// `parser/mod.rs` for `parser/lexer.rs`) if there is one, and by the
+// primary otherwise, in a region at the end that `join` drops again:
+//
+//     //@@mods
+//     #[path = "parser/lexer.rs"]
+//     mod lexer;
+//     //@@>>
+//
+// A file whose name does not end in `.rs` is an asset instead, such
+// as `Cargo.toml`, a SQL migration or a test fixture, produced from a
+// block in some other language (see `md2rs::Info`). It holds exactly
+// what its one block holds, with no header, since there is no telling
+// how to write a comment in it; and it may be anywhere within the crate
+// (e.g. `../Cargo.toml`), where a `.rs` file must be below the primary
+// for its `mod` declaration to make sense. Assets and `.rs` files
+// alike take part in the staleness checks of the primary, through
+// `companions`.
+//
+// Nothing is ever written outside the crate.
+
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, BufRead, Read, Write};
+use std::path::{Component, Path, PathBuf};
+
+use super::chunks::{self, CLOSE, SEPARATOR};
+use super::encoding;
+use super::{Error, Result};
+
+pub const HEADER: &'static str = "//@@tangled from ";
+const PLACEHOLDER: &'static str = "//@@file ";
+const MODS: &'static str = "//@@mods";
+
+fn is_asset(file: &str) -> bool {
+    Path::new(file).extension().and_then(|e| e.to_str()) != Some("rs")
+}
+
+// Checks that the block attribute `file="..."` names a file we may
+// write; where it lands is checked by `write`.
+fn check(file: &str) -> ::std::result::Result<(), String> {
+    let p = Path::new(file);
+    let relative = p.components().all(|c| match c {
+        Component::Normal(_) | Component::CurDir | Component::ParentDir => true,
+        Component::RootDir | Component::Prefix(_) => false,
+    });
+    if !relative || p.file_name().is_none() {
+        return Err("must be a relative path to a file within the crate".to_string());
+    }
+    if is_asset(file) {
+        return Ok(());
+    }
+    let plain = p.components().all(|c| match c {
+        Component::Normal(_) => true,
+        _ => false,
+    });
+    if !plain {
+        return Err("must be a path below the document's `.rs`, without `.` or `..`".to_string());
+    }
+    match module_path(file) {
+        Some(_) => Ok(()),
+        None => Err("does not name a Rust module".to_string()),
+    }
+}
+
+// `p` with each `.` and `..` resolved, without consulting the
+// filesystem; a `..` that cannot be resolved is kept.
+pub fn normalize(p: &Path) -> PathBuf {
+    let mut result = PathBuf::new();
+    for c in p.components() {
+        match c {
+            Component::CurDir => {}
+            Component::ParentDir => match result.components().next_back() {
+                Some(Component::Normal(_)) => { result.pop(); }
+                Some(Component::RootDir) | Some(Component::Prefix(_)) => {}
+                _ => result.push(".."),
+            },
+            c => result.push(c.as_os_str()),
+        }
+    }
+    result
+}
+
+// The path of modules that `file` holds, relative to the primary:
+// `a/b.rs` and `a/b/mod.rs` both hold `a::b`.
+fn module_path(file: &str) -> Option<Vec<String>> {
+    let p = Path::new(file);
+    let mut names: Vec<String> = match p.parent() {
+        Some(dir) => dir.components().map(|c| c.as_os_str().to_string_lossy().into_owned()).collect(),
+        None => vec![],
+    };
+    match p.file_stem().and_then(|s| s.to_str()) {
+        Some("mod") => {}
+        Some(stem) => names.push(stem.to_string()),
+        None => return None,
+    }
+    let ident = |s: &String| {
+        s != "mod" && s.chars().next().map_or(false, |c| c.is_alphabetic() || c == '_') &&
+            s.chars().all(|c| c.is_alphanumeric() || c == '_')
+    };
+    if names.is_empty() || !names.iter().all(ident) {
+        None
+    } else {
+        Some(names)
+    }
+}
+
+// The files named by `//@@file` lines in `text`, in order of first
+// appearance.
+pub fn placeholders(text: &str) -> Vec<String> {
+    let mut files: Vec<String> = Vec::new();
+    for line in text.lines() {
+        if line.starts_with(PLACEHOLDER) {
+            let file = line[PLACEHOLDER.len()..].to_string();
+            if !files.contains(&file) {
+                files.push(file);
+            }
+        }
+    }
+    files
+}
+
+// Lines up to the `//@@>>` that closes the current region, skipping
+// over the expansions of any chunks within it.
+fn region<'a, I: Iterator<Item=&'a str>>(lines: &mut I) -> Vec<String> {
+    let mut body = Vec::new();
+    let mut depth = 0;
+    for l in lines {
+        let t = l.trim_left();
+        if t == CLOSE {
+            if depth == 0 {
+                break;
+            }
+            depth -= 1;
+        } else if chunks::opens(t) {
+            depth += 1;
+        }
+        body.push(l.to_string());
+    }
+    body
+}
+
+// `lines`, less any `//@@mods` regions.
+fn without_mods<'a, I: Iterator<Item=&'a str>>(lines: I) -> Vec<&'a str> {
+    let mut result = Vec::new();
+    let mut in_mods = false;
+    for l in lines {
+        if l == MODS {
+            in_mods = true;
+        } else if in_mods {
+            in_mods = l != CLOSE;
+        } else {
+            result.push(l);
+        }
+    }
+    result
+}
+
+fn text_of(lines: &[String], newline: bool) -> String {
+    let mut text = lines.join("\n");
+    if newline {
+        text.push('\n');
+    }
+    text
+}
+
+// Splits the text `md2rs` wrote for the document `md_name` into the
+// primary and the content of each produced file, as described above.
+// On failure, returns the offending `file` and what is wrong with it.
+pub fn split(text: &str, md_name: &str)
+             -> ::std::result::Result<(String, Vec<(String, String)>), (String, String)> {
+    if !text.lines().any(|l| l.starts_with(PLACEHOLDER)) {
+        return Ok((text.to_string(), vec![]));
+    }
+    let mut primary = Vec::new();
+    let mut order = Vec::new();
+    let mut pieces: BTreeMap<String, Vec<Vec<String>>> = BTreeMap::new();
+    let mut lines = text.lines();
+    while let Some(line) = lines.next() {
+        primary.push(line.to_string());
+        if !line.starts_with(PLACEHOLDER) {
+            continue;
+        }
+        let file = line[PLACEHOLDER.len()..].to_string();
+        if let Err(message) = check(&file) {
+            return Err((file, message));
+        }
+        let body = region(&mut lines);
+        if !pieces.contains_key(&file) {
+            order.push(file.clone());
+        } else if is_asset(&file) {
+            return Err((file, "is the target of more than one block, \
+                               which only a `.rs` file may be".to_string()));
+        }
+        pieces.entry(file).or_insert_with(Vec::new).push(body);
+    }
+
+    let modules: Vec<String> = order.iter().filter(|f| !is_asset(f)).cloned().collect();
+    let mut decls = declarations(&modules);
+    primary.extend(mods(decls.remove("")));
+    let mut files = Vec::new();
+    for file in order {
+        if is_asset(&file) {
+            let content = text_of(&pieces[&file][0], true);
+            files.push((file, content));
+            continue;
+        }
+        let mut lines = vec![format!("{}{}", HEADER, md_name)];
+        for (i, piece) in pieces[&file].iter().enumerate() {
+            if i > 0 {
+                lines.push(SEPARATOR.to_string());
+            }
+            lines.extend(piece.iter().cloned());
+        }
+        lines.extend(mods(decls.remove(&file)));
+        files.push((file, text_of(&lines, true)));
+    }
+    Ok((text_of(&primary, text.ends_with('\n')), files))
+}
+
+// For each declaring file (with "" for the primary), the `#[path]` and
+// name of each module it declares.
+fn declarations(files: &[String]) -> BTreeMap<String, Vec<(String, String)>> {
+    let mut decls = BTreeMap::new();
+    for file in files {
+        let mut module = module_path(file).expect("checked by `split`");
+        let name = module.pop().expect("checked by `split`");
+        let parent = files.iter().find(|f| *f != file && module_path(f) == Some(module.clone()));
+        let (declarer, path) = match parent {
+            Some(parent) => {
+                let dir = Path::new(parent).parent().unwrap_or(Path::new(""));
+                let path = Path::new(file).strip_prefix(dir).expect("parent module is above");
+                (parent.clone(), path.to_path_buf())
+            }
+            None => (String::new(), PathBuf::from(file)),
+        };
+        let path = path.components()
+            .map(|c| c.as_os_str().to_string_lossy().into_owned())
+            .collect::<Vec<_>>()
+            .join("/");
+        decls.entry(declarer).or_insert_with(Vec::new).push((path, name));
+    }
+    decls
+}
+
+fn mods(decls: Option<Vec<(String, String)>>) -> Vec<String> {
+    let decls = match decls {
+        Some(decls) => decls,
+        None => return vec![],
+    };
+    let mut lines = vec![MODS.to_string()];
+    for (path, name) in decls {
+        lines.push(format!("#[path = \"{}\"]", path));
+        lines.push(format!("mod {};", name));
+    }
+    lines.push(CLOSE.to_string());
+    lines
+}
+
+// The inverse of `split`: given the primary, and a way to `load` each
+// file it names, rebuilds the text `md2rs` wrote.
+pub fn join<F>(primary: &str, mut load: F) -> io::Result<String>
+    where F: FnMut(&str) -> io::Result<String>
+{
+    if !primary.lines().any(|l| l.starts_with(PLACEHOLDER) || l == MODS) {
+        return Ok(primary.to_string());
+    }
+    let mut pieces: BTreeMap<String, Vec<Vec<String>>> = BTreeMap::new();
+    let mut seen: BTreeMap<String, usize> = BTreeMap::new();
+    let mut result = Vec::new();
+    for line in without_mods(primary.lines()) {
+        result.push(line.to_string());
+        if !line.starts_with(PLACEHOLDER) {
+            continue;
+        }
+        let file = &line[PLACEHOLDER.len()..];
+        if !pieces.contains_key(file) {
+            let content = try!(load(file));
+            let found = if is_asset(file) {
+                vec![content.lines().map(|l| l.to_string()).collect()]
+            } else {
+                pieces_of(&content)
+            };
+            pieces.insert(file.to_string(), found);
+        }
+        let k = seen.entry(file.to_string()).or_insert(0);
+        if let Some(piece) = pieces[file].get(*k) {
+            result.extend(piece.iter().cloned());
+        }
+        *k += 1;
+        result.push(CLOSE.to_string());
+    }
+    Ok(text_of(&result, primary.ends_with('\n')))
+}
+
+// The code of each block sent to a produced file.
+fn pieces_of(content: &str) -> Vec<Vec<String>> {
+    let mut pieces = vec![vec![]];
+    let mut depth = 0;
+    for l in without_mods(content.lines().filter(|l| !l.starts_with(HEADER))) {
+        let t = l.trim_left();
+        if t == SEPARATOR && depth == 0 {
+            pieces.push(vec![]);
+            continue;
+        } else if t == CLOSE {
+            depth -= 1;
+        } else if chunks::opens(t) {
+            depth += 1;
+        }
+        pieces.last_mut().unwrap().push(l.to_string());
+    }
+    pieces
+}
+
+// The files that the `.rs` at `rs` sends code to.
+pub fn companions(rs: &Path) -> io::Result<Vec<PathBuf>> {
+    if !rs.exists() {
+        return Ok(vec![]);
+    }
+    let mut text = String::new();
+    try!(try!(File::open(rs)).read_to_string(&mut text));
+    let dir = rs.parent().unwrap_or(Path::new(""));
+    Ok(placeholders(&text).iter().map(|f| normalize(&dir.join(f))).collect())
+}
+
+// The first line of `p`, if it starts with the `//@@tangled from` line
+// of a produced file, names the document it was produced from.
+pub fn produced_from(p: &Path) -> io::Result<Option<String>> {
+    let mut first = String::new();
+    try!(io::BufReader::new(try!(File::open(p))).read_line(&mut first));
+    let first = first.trim_right();
+    if first.starts_with(HEADER) {
+        Ok(Some(first[HEADER.len()..].to_string()))
+    } else {
+        Ok(None)
+    }
+}
+
+// Reads the `.rs` at `rs`, along with every file its code was sent to,
+// as the one text that `rs2md` expects.
+// It is laid out the way the `.rs` itself is (see `encoding`).
+pub fn read(rs: &Path) -> Result<String> {
+    let (style, text, _) = encoding::strip(&try!(encoding::read(rs)));
+    let dir = rs.parent().unwrap_or(Path::new(""));
+    let mut invalid = None;
+    let joined = try!(join(&text, |file| {
+        let p = dir.join(file);
+        if !p.exists() {
+            return Err(io::Error::new(io::ErrorKind::NotFound, format!(
+                "{} (named by a `//@@file` line of {}) does not exist", p.display(), rs.display())));
+        }
+        match encoding::read(&p) {
+            Ok(content) => Ok(encoding::strip(&content).1),
+            Err(e) => {
+                let message = e.to_string();
+                invalid = Some(e);
+                Err(io::Error::new(io::ErrorKind::InvalidData, message))
+            }
+        }
+    }).map_err(|e| invalid.take().unwrap_or(Error::IoError(e))));
+    Ok(style.apply(&joined))
+}
+
+// Writes `text`, which `md2rs` produced from `md`, to `rs` and the
+// files its `file` blocks name, all within the crate at `root`.
+// Returns every path written.
+// Each file is laid out the way `text` is (see `encoding`).
+pub fn write(root: &Path, rs: &Path, md: &Path, text: &str) -> Result<Vec<PathBuf>> {
+    let invalid = |file: &str, message: &str| Error::InvalidFileBlock {
+        md: md.to_path_buf(), file: file.to_string(), message: message.to_string(),
+    };
+    let md_name = super::relative_name(root, md);
+    let (style, text, _) = encoding::strip(text);
+    let (primary, files) = try!(split(&text, &md_name).map_err(|(f, m)| invalid(&f, &m)));
+    let dir = rs.parent().unwrap_or(Path::new(""));
+
+    // Refuse before writing anything, rather than halfway through. We
+    // only ever overwrite what we wrote before: a `.rs` file that says
+    // it came from this document, or an asset that the `.rs` already
+    // named. An asset that is there already, say the crate's own
+    // `Cargo.toml`, is adopted by a block that holds just what it does
+    // (line endings aside); from then on, the `.rs` names it.
+    let previous = try!(companions(rs));
+    let mut paths = Vec::new();
+    for &(ref file, ref content) in &files {
+        let p = normalize(&dir.join(file));
+        let escapes = p.components().any(|c| c == Component::ParentDir);
+        if escapes || !p.starts_with(normalize(root)) {
+            return Err(invalid(file, "is outside of the crate"));
+        }
+        let ours = p != rs && (!p.exists() || if is_asset(file) {
+            previous.contains(&p) || encoding::strip(&try!(encoding::read(&p))).1 == *content
+        } else {
+            try!(produced_from(&p)) == Some(md_name.clone())
+        });
+        if !ours {
+            let message = if is_asset(file) {
+                "already exists with other content, and was not produced from this document"
+            } else {
+                "already exists, and was not produced from this document"
+            };
+            return Err(invalid(file, message));
+        }
+        paths.push(p);
+    }
+
+    try!(try!(File::create(rs)).write_all(style.apply(&primary).as_bytes()));
+    let mut written = vec![rs.to_path_buf()];
+    for ((_, content), p) in files.into_iter().zip(paths) {
+        if let Some(parent) = p.parent() {
+            try!(fs::create_dir_all(parent));
+        }
+        // A BOM belongs at the start of the document's own `.rs` alone.
+        let style = encoding::Style { bom: false, ..style };
+        try!(try!(File::create(&p)).write_all(style.apply(&content).as_bytes()));
+        written.push(p);
+    }
+    Ok(written)
+}
@@ -0,0 +1,253 @@
+// `tango fix`: applies the machine-applicable suggestions in the output
+// of `cargo build --message-format=json` (or `cargo clippy`) to the
+// `.md` code blocks the code came from, and then syncs the `.rs` from
+// them, the usual way.
+//
+// Letting `cargo fix` edit the generated `.rs` instead would make the
+// `.rs` the newer side of its pair, and any edit made to the `.md` in
+// the meantime would then have to be merged with it (or worse). Here
+// the `.md` stays the side that was edited.
+//
+// A suggestion is located with the same `SourceMap`s that
+// `tango diagnostics` uses, and is skipped if any of its spans cannot
+// be: if it lies outside of the code from a `.md`, or in a `.rs` that
+// no longer corresponds to its `.md`. A suggestion that overlaps one
+// already taken is skipped too, as `cargo fix` would; running the
+// build and `tango fix` again picks it up.
+//
+// `tango --fix-links` is the same idea for the playground links of
+// `md2rs`: a link that no longer matches the code block before it is
+// rewritten in the `.md` with the URL it should have, before the run.
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufRead, Write};
+use std::path::PathBuf;
+
+use serde_json::Value;
+
+use super::diagnostics::Maps;
+use super::status::PairState;
+use super::{encoding, md2rs, process_paths_with_config, relative_name, status_with_config};
+use super::{Config, Error, Result, Warning};
+
+#[derive(Debug)]
+struct Edit {
+    start: usize,
+    end: usize,
+    replacement: String,
+    // Where, and why, for the report.
+    line: usize,
+    column: usize,
+    message: String,
+}
+
+pub fn fix<R: BufRead, W: Write>(config: &Config, input: R, mut output: W) -> Result<()> {
+    let mut edits: BTreeMap<PathBuf, Vec<Edit>> = BTreeMap::new();
+    let mut skipped = 0;
+    {
+        let mut maps = Maps::new(config);
+        for line in input.lines() {
+            let line = try!(line);
+            let value: Value = match ::serde_json::from_str(&line) {
+                Ok(value) => value,
+                Err(_) => continue,
+            };
+            if value["reason"] != "compiler-message" {
+                continue;
+            }
+            let mut groups = Vec::new();
+            suggestions(&value["message"], &mut groups);
+            for (message, spans) in groups {
+                match locate(&mut maps, message, &spans) {
+                    Some(located) => for (md, edit) in located {
+                        edits.entry(md).or_insert_with(Vec::new).push(edit);
+                    },
+                    None => skipped += 1,
+                }
+            }
+        }
+    }
+
+    let mut fixed = Vec::new();
+    let mut applied = 0;
+    for (md, mut edits) in edits {
+        edits.sort_by(|a, b| (a.start, a.end).cmp(&(b.start, b.end)));
+        let mut kept: Vec<Edit> = Vec::new();
+        for edit in edits {
+            let clash = match kept.last() {
+                Some(k) if (k.start, k.end, &k.replacement) == (edit.start, edit.end, &edit.replacement) => {
+                    // The same suggestion twice (say, for a chunk that
+                    // is used twice) is made once.
+                    continue;
+                }
+                Some(k) => edit.start < k.end || (edit.start == k.start && k.start == k.end),
+                None => false,
+            };
+            if clash {
+                skipped += 1;
+            } else {
+                kept.push(edit);
+            }
+        }
+
+        let mut text = try!(encoding::read(&md));
+        for edit in kept.iter().rev() {
+            text = format!("{}{}{}", &text[..edit.start], edit.replacement, &text[edit.end..]);
+        }
+        try!(try!(File::create(&md)).write_all(text.as_bytes()));
+        for edit in &kept {
+            try!(writeln!(output, "fixed {}:{}:{}: {}", md.display(), edit.line, edit.column,
+                          edit.message));
+        }
+        applied += kept.len();
+        fixed.push(md);
+    }
+    try!(writeln!(output, "applied {} suggestion{} to {} file{}; skipped {}",
+                  applied, if applied == 1 { "" } else { "s" },
+                  fixed.len(), if fixed.len() == 1 { "" } else { "s" }, skipped));
+    if fixed.is_empty() {
+        return Ok(());
+    }
+    process_paths_with_config(&fixed, config)
+}
+
+// Gathers the machine-applicable suggestions within `diagnostic`, each
+// with its message and the spans that must all be replaced together.
+fn suggestions<'v>(diagnostic: &'v Value, groups: &mut Vec<(&'v str, Vec<&'v Value>)>) {
+    let spans: Vec<&Value> = diagnostic["spans"].as_array().map_or(vec![], |spans| {
+        spans.iter()
+            .filter(|s| s["suggestion_applicability"] == "MachineApplicable" &&
+                        s["suggested_replacement"].is_string())
+            .collect()
+    });
+    if !spans.is_empty() {
+        groups.push((diagnostic["message"].as_str().unwrap_or(""), spans));
+    }
+    if let Some(children) = diagnostic["children"].as_array() {
+        for child in children {
+            suggestions(child, groups);
+        }
+    }
+}
+
+// The edits to the `.md` files that make the suggestion `spans`, or
+// `None` if any of them cannot be located.
+fn locate(maps: &mut Maps, message: &str, spans: &[&Value]) -> Option<Vec<(PathBuf, Edit)>> {
+    let mut result = Vec::new();
+    for span in spans {
+        let n = match ["line_start", "column_start", "line_end", "column_end"].iter()
+            .map(|k| span[*k].as_u64().map(|n| n as usize))
+            .collect::<Option<Vec<usize>>>() {
+            Some(n) => n,
+            None => return None,
+        };
+        let map = match span["file_name"].as_str().and_then(|f| maps.get(f)) {
+            Some(map) => map,
+            None => return None,
+        };
+        let (start, end) = match (map.locate(n[0], n[1]), map.locate(n[2], n[3])) {
+            (Some(start), Some(end)) if start <= end => (start, end),
+            _ => return None,
+        };
+        let mut replacement = span["suggested_replacement"].as_str().unwrap_or("").to_string();
+        if replacement.contains('\n') {
+            // Later lines of the replacement are laid out for the
+            // `.rs`, and have to be laid out for the `.md` instead.
+            let (rs_prefix, md_prefix) = match map.prefixes(n[0]) {
+                Some(prefixes) => prefixes,
+                None => return None,
+            };
+            if md_prefix.chars().any(|c| c != '>' && !c.is_whitespace()) {
+                // Say, a hidden line, which cannot go on for more lines.
+                return None;
+            }
+            let mut lines = replacement.split('\n');
+            let mut relaid = lines.next().unwrap_or("").to_string();
+            for line in lines {
+                relaid.push('\n');
+                if line.starts_with(rs_prefix) {
+                    relaid.push_str(md_prefix);
+                    relaid.push_str(&line[rs_prefix.len()..]);
+                } else if line.is_empty() {
+                    relaid.push_str(md_prefix.trim_right());
+                } else {
+                    relaid.push_str(md_prefix);
+                    relaid.push_str(line);
+                }
+            }
+            replacement = relaid;
+        }
+        result.push((map.md.clone(), Edit {
+            start: map.offset(start.0, start.1),
+            end: map.offset(end.0, end.1),
+            replacement: replacement,
+            line: start.0,
+            column: start.1,
+            message: message.to_string(),
+        }));
+    }
+    Some(result)
+}
+
+// Rewrites every stale playground link in the `.md` files that a run
+// would read from (those of pairs in sync, or to be synced from the
+// `.md`), reporting each one to `output`, and returns the files it
+// changed. Nothing else in them changes, down to the byte; a `.md`
+// that a run would overwrite is left alone, as its links are about to
+// be written afresh anyway.
+pub fn fix_links<W: Write>(config: &Config, mut output: W) -> Result<Vec<PathBuf>> {
+    let status = try!(status_with_config(config));
+    let mut fixed = Vec::new();
+    let mut links = 0;
+    for pair in &status.pairs {
+        if pair.state != PairState::InSync && pair.state != PairState::MdToRs {
+            continue;
+        }
+        let md = config.root.join(&pair.md);
+        let text = try!(encoding::read(&md));
+        let warnings = match md2rs(&config.playground, text.as_bytes(), &mut Vec::new()) {
+            Ok(()) => continue,
+            Err(Error::Warnings(ws)) => ws,
+            Err(e) => return Err(e),
+        };
+        // The warnings locate links in the text after any BOM, which
+        // is all that `md2rs` sees.
+        let bom = if text.starts_with('\u{feff}') { '\u{feff}'.len_utf8() } else { 0 };
+        let mut lines: Vec<String> = text[bom..].split('\n').map(|l| l.to_string()).collect();
+        let mut count = 0;
+        for d in warnings {
+            let expect = match d.warning {
+                Warning::EncodedUrlMismatch { ref expect, .. } |
+                Warning::LegacyUrlEncoding { ref expect, .. } => expect,
+                _ => continue,
+            };
+            let line = &mut lines[d.line - 1];
+            let start = line.char_indices().nth(d.columns.start - 1).map_or(line.len(), |(i, _)| i);
+            let end = line.trim_right_matches('\r').len();
+            let name = link_name(&line[..start]).to_string();
+            *line = format!("{}{}{}", &line[..start], expect, &line[end..]);
+            try!(writeln!(output, "fixed link [{}] at {}:{}",
+                          name, relative_name(&config.root, &md), d.line));
+            count += 1;
+        }
+        if count > 0 {
+            let fixed_text = format!("{}{}", &text[..bom], lines.join("\n"));
+            try!(try!(File::create(&md)).write_all(fixed_text.as_bytes()));
+            fixed.push(md);
+            links += count;
+        }
+    }
+    try!(writeln!(output, "fixed {} link{} in {} file{}",
+                  links, if links == 1 { "" } else { "s" },
+                  fixed.len(), if fixed.len() == 1 { "" } else { "s" }));
+    Ok(fixed)
+}
+
+// The name of the link whose definition starts with `prefix`, as in
+// `[name]: `.
+fn link_name(prefix: &str) -> &str {
+    let open = prefix.find('[').map_or(0, |i| i + 1);
+    let close = prefix.rfind("]:").unwrap_or(prefix.len());
+    if open <= close { &prefix[open..close] } else { "" }
+}
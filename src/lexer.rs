@@ -0,0 +1,98 @@
+// Just enough of Rust's lexical structure to tell whether a line of
+// code starts out in plain code, or within a literal or comment that
+// some earlier line opened and has yet to close. Only a line that
+// starts in plain code can be a `//@` comment (or a `//@@` marker); in
+//
+//     const SNIPPET: &'static str = r#"
+//     //@ Prose, in the `.md` this string came from.
+//     "#;
+//
+// the second line is part of the string, and `rs2md` keeps it as code.
+//
+// A line of code that starts in plain code and does look like a `//@`
+// comment is tangled after a `//@@code` marker (see `md2rs`), which
+// tells `rs2md` that it is code all the same. A block that leaves a
+// literal or comment open (code that would not compile) ends with a
+// `//@@end` marker, which `rs2md` heeds wherever the lexer is.
+
+pub const ESCAPE: &'static str = "//@@code";
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Lexer {
+    Code,
+    // Within a string (or byte string) literal.
+    Str,
+    // Within a raw string literal, closed by `"` and this many `#`.
+    RawStr(usize),
+    // Within this many nested block comments.
+    Comment(usize),
+}
+
+impl Lexer {
+    // Where the lexer is after `line`, having been here before it.
+    pub fn after(self, line: &str) -> Lexer {
+        let cs: Vec<char> = line.chars().collect();
+        let at = |i: usize| cs.get(i).cloned();
+        let mut state = self;
+        let mut i = 0;
+        while i < cs.len() {
+            match state {
+                Lexer::Code => match (cs[i], at(i + 1)) {
+                    ('/', Some('/')) => return Lexer::Code,
+                    ('/', Some('*')) => { state = Lexer::Comment(1); i += 2; }
+                    ('"', _) => { state = Lexer::Str; i += 1; }
+                    ('\'', Some('\\')) => {
+                        // An escape, up to the closing quote.
+                        i += 3;
+                        while i < cs.len() && cs[i] != '\'' {
+                            i += 1;
+                        }
+                        i += 1;
+                    }
+                    // A character literal, or else a lifetime.
+                    ('\'', _) if at(i + 2) == Some('\'') => i += 3,
+                    (c, _) if c.is_alphanumeric() || c == '_' => {
+                        let start = i;
+                        while i < cs.len() && (cs[i].is_alphanumeric() || cs[i] == '_') {
+                            i += 1;
+                        }
+                        let word: String = cs[start..i].iter().cloned().collect();
+                        if word == "r" || word == "br" || word == "cr" {
+                            let hashes = cs[i..].iter().take_while(|&&c| c == '#').count();
+                            if at(i + hashes) == Some('"') {
+                                state = Lexer::RawStr(hashes);
+                                i += hashes + 1;
+                            }
+                        }
+                    }
+                    _ => i += 1,
+                },
+                Lexer::Str => match cs[i] {
+                    '\\' => i += 2,
+                    '"' => { state = Lexer::Code; i += 1; }
+                    _ => i += 1,
+                },
+                Lexer::RawStr(hashes) => {
+                    if cs[i] == '"' && cs[i + 1..].iter().take_while(|&&c| c == '#').count() >= hashes {
+                        state = Lexer::Code;
+                        i += hashes;
+                    }
+                    i += 1;
+                }
+                Lexer::Comment(depth) => match (cs[i], at(i + 1)) {
+                    ('/', Some('*')) => { state = Lexer::Comment(depth + 1); i += 2; }
+                    ('*', Some('/')) if depth == 1 => { state = Lexer::Code; i += 2; }
+                    ('*', Some('/')) => { state = Lexer::Comment(depth - 1); i += 2; }
+                    _ => i += 1,
+                },
+            }
+        }
+        state
+    }
+
+    // Whether `line`, coming with the lexer here, has to follow an
+    // `ESCAPE` not to be taken for a `//@` comment.
+    pub fn needs_escape(self, line: &str) -> bool {
+        self == Lexer::Code && line.trim_left().starts_with("//@")
+    }
+}
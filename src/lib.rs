@@ -3,12 +3,17 @@
 // extern crate env_logger;
 
 extern crate filetime;
+extern crate pulldown_cmark;
+#[macro_use]
+extern crate serde_json;
+extern crate toml;
 extern crate url;
 extern crate walkdir;
 
 use filetime::set_file_times;
 use walkdir::{WalkDir};
 
+use std::collections::{BTreeMap, HashSet};
 use std::convert;
 use std::env;
 use std::error::Error as ErrorTrait;
@@ -18,11 +23,35 @@ use std::io::{self, Read, Write};
 use std::ops;
 use std::path::{Path, PathBuf};
 
+use self::manifest::Manifest;
+use self::report::{Diagnostic, MessageFormat, Severity};
+use self::status::Status;
 use self::timestamp::{Timestamp, Timestamped};
 
 pub mod timestamp;
+pub mod status;
+pub mod watch;
+pub mod diagnostics;
+pub mod fix;
+pub mod report;
+mod manifest;
+mod merge;
+mod settings;
 
 pub const STAMP: &'static str = "tango.stamp";
+// Kept in the directory of the stamp (the crate root, unless `stamp`
+// is configured to be elsewhere), as is `BASE_DIR`.
+pub const MANIFEST: &'static str = "tango.manifest";
+
+// Where project configuration is read from; see `settings`.
+pub const TANGO_TOML: &'static str = "tango.toml";
+pub const CARGO_TOML: &'static str = "Cargo.toml";
+
+// Snapshots of every `.md` as of the last successful run, used as the
+// merge base when both sides of a pair have since been edited;
+// relative to the directory of the stamp.
+pub const BASE_DIR: &'static str = "target/tango/base";
+
 pub const SRC_DIR: &'static str = "src";
 
 // pnkfelix wanted the `LIT_DIR` to be `lit/`, but `cargo build`
@@ -30,26 +59,202 @@ pub const SRC_DIR: &'static str = "src";
 // is easier for now to just have the two directories be the same.
 pub const LIT_DIR: &'static str = "src";
 
+// The places tango looks for `.rs`/`.md` pairs unless told otherwise:
+// the standard Cargo target directories, plus the build script.
+pub const DEFAULT_ROOTS: &'static [&'static str] =
+    &[SRC_DIR, "tests", "examples", "benches", "build.rs"];
+
+pub use playground::{LinkContext, LinkFormat, Playground};
+pub use playground::{COMPILER_EXPLORER_URL, PLAYGROUND_URL};
+pub use encoding::LineEndings;
+
+// What to do when converting a `.md` raises warnings (such as a stale
+// playground link): `Deny` fails the run (once every pair has been
+// looked at, leaving the `.rs` of that `.md` as it was), `Warn`
+// reports them at the end of the run, and `Allow` carries on
+// silently. Each kind of warning can have a policy of its own.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum WarningPolicy { Deny, Warn, Allow }
 
 pub struct Config {
     root: PathBuf,
     rerun_if: bool,
+    roots: Vec<PathBuf>,
+    src_dir: PathBuf,
+    lit_dir: PathBuf,
+    include: Vec<String>,
+    exclude: Vec<String>,
+    stamp: PathBuf,
+    playground: Playground,
+    warnings: WarningPolicy,
+    // Policies for particular kinds of warnings, by `Warning::code`.
+    warning_kinds: BTreeMap<String, WarningPolicy>,
+    message_format: MessageFormat,
+    line_endings: LineEndings,
 }
 
 impl Config {
 
     pub fn new() -> Config {
         Config {
-            root: env::current_dir().unwrap(),
+            root: env::current_dir().unwrap_or_else(|_| PathBuf::from(".")),
             rerun_if: false,
+            roots: DEFAULT_ROOTS.iter().map(PathBuf::from).collect(),
+            src_dir: PathBuf::from(SRC_DIR),
+            lit_dir: PathBuf::from(LIT_DIR),
+            include: Vec::new(),
+            exclude: Vec::new(),
+            stamp: PathBuf::from(STAMP),
+            playground: Playground::default(),
+            warnings: WarningPolicy::Deny,
+            // Content that tango cannot carry over exactly is still
+            // converted, and links from older versions of tango do not
+            // stop the build, unless these are denied by name.
+            warning_kinds: vec![("lossy-round-trip".to_string(), WarningPolicy::Warn),
+                                ("legacy-url-encoding".to_string(), WarningPolicy::Warn)]
+                .into_iter().collect(),
+            message_format: MessageFormat::Human,
+            line_endings: LineEndings::Preserve,
         }
     }
 
+    // The defaults, overridden by the `tango.toml` (or the
+    // `[package.metadata.tango]` table of `Cargo.toml`) found at
+    // `root`, if any.
+    pub fn load<P: AsRef<Path>>(root: P) -> Result<Config> {
+        let mut config = Config::new();
+        config.root(root.as_ref());
+        try!(settings::load(root.as_ref(), &mut config));
+        Ok(config)
+    }
+
+    // Sets the crate root that every other path is relative to. It
+    // defaults to the current directory, but nothing tango does
+    // depends on the current directory beyond that default, so
+    // several roots can be processed at once from different threads.
+    pub fn root<P: AsRef<Path>>(&mut self, root: P) -> &mut Config {
+        self.root = root.as_ref().to_path_buf();
+        self
+    }
+
     pub fn emit_rerun_if(&mut self) -> &mut Config {
         self.rerun_if = true;
         self
     }
 
+    // Replaces the list of directories (or single files, such as
+    // `build.rs`) that are searched for pairs. Paths are relative to
+    // the crate root.
+    pub fn roots<I, P>(&mut self, roots: I) -> &mut Config
+        where I: IntoIterator<Item=P>, P: AsRef<Path>
+    {
+        self.roots = roots.into_iter().map(|p| p.as_ref().to_path_buf()).collect();
+        self
+    }
+
+    pub fn add_root<P: AsRef<Path>>(&mut self, root: P) -> &mut Config {
+        self.roots.push(root.as_ref().to_path_buf());
+        self
+    }
+
+    // Sets the directory holding the `.rs` side of the main pairs
+    // (`src` by default), which is also searched in place of the old
+    // one if that was among the roots.
+    pub fn src_dir<P: AsRef<Path>>(&mut self, dir: P) -> &mut Config {
+        let dir = dir.as_ref().to_path_buf();
+        for r in &mut self.roots {
+            if *r == self.src_dir {
+                *r = dir.clone();
+            }
+        }
+        self.src_dir = dir;
+        self
+    }
+
+    // Sets the directory holding the `.md` side of the pairs whose
+    // `.rs` lives in the `src_dir`.
+    pub fn lit_dir<P: AsRef<Path>>(&mut self, dir: P) -> &mut Config {
+        self.lit_dir = dir.as_ref().to_path_buf();
+        self
+    }
+
+    // Restricts tango to pairs whose paths (relative to the crate
+    // root) match one of the glob patterns given this way.
+    pub fn include(&mut self, pattern: &str) -> &mut Config {
+        self.include.push(pattern.to_string());
+        self
+    }
+
+    // Leaves alone any pair with a path matching this glob pattern.
+    pub fn exclude(&mut self, pattern: &str) -> &mut Config {
+        self.exclude.push(pattern.to_string());
+        self
+    }
+
+    pub fn stamp<P: AsRef<Path>>(&mut self, stamp: P) -> &mut Config {
+        self.stamp = stamp.as_ref().to_path_buf();
+        self
+    }
+
+    pub fn playground(&mut self, playground: Playground) -> &mut Config {
+        self.playground = playground;
+        self
+    }
+
+    // Sets the policy for every kind of warning, other than those
+    // given one of their own with `warning`.
+    pub fn warnings(&mut self, policy: WarningPolicy) -> &mut Config {
+        self.warnings = policy;
+        self
+    }
+
+    // Sets the policy for the kind of warning with the given code (one
+    // of `WARNING_CODES`).
+    pub fn warning(&mut self, code: &str, policy: WarningPolicy) -> &mut Config {
+        self.warning_kinds.insert(code.to_string(), policy);
+        self
+    }
+
+    // How warnings are written out: as rustc writes them, or as JSON
+    // lines (see `report`).
+    pub fn message_format(&mut self, format: MessageFormat) -> &mut Config {
+        self.message_format = format;
+        self
+    }
+
+    // Makes every file tango writes end its lines as `endings` says,
+    // instead of the way the file it was generated from does.
+    pub fn line_endings(&mut self, endings: LineEndings) -> &mut Config {
+        self.line_endings = endings;
+        self
+    }
+
+}
+
+// One place searched for pairs. `src` holds the `.rs` side and `lit`
+// the `.md` side; for a directory they are usually the same, and for
+// a single file like `build.rs` they are the file and its `build.md`
+// counterpart.
+#[derive(Clone, Debug)]
+struct Root {
+    src: PathBuf,
+    lit: PathBuf,
+}
+
+impl Root {
+    fn new(config: &Config, p: &Path) -> Root {
+        let base = &config.root;
+        if p == config.src_dir {
+            Root { src: base.join(&config.src_dir), lit: base.join(&config.lit_dir) }
+        } else if p.rs_extension() {
+            Root { src: base.join(p), lit: base.join(p.with_extension("md")) }
+        } else {
+            Root { src: base.join(p), lit: base.join(p) }
+        }
+    }
+    fn all(config: &Config) -> Vec<Root> {
+        config.roots.iter().map(|p| Root::new(config, p)).collect()
+    }
 }
 
 
@@ -59,12 +264,72 @@ pub enum Error {
     CheckInputError { error: check::Error },
     MtimeError(PathBuf),
     ConcurrentUpdate { path_buf: PathBuf, old_time: mtime, new_time: mtime },
-    Warnings(Vec<Warning>),
+    // Warnings that the `WarningPolicy` denies; they have been written
+    // out already.
+    Warnings(Vec<Diagnostic>),
+    MergeConflicts(Vec<PathBuf>),
+    // A problem with the project configuration in `file`, at `key`
+    // (empty when the file could not be parsed at all).
+    ConfigError { file: PathBuf, key: String, message: String },
+    // A code block in `md` whose `file` attribute cannot be honoured.
+    InvalidFileBlock { md: PathBuf, file: String, message: String },
+    // A path that tango was asked to treat as one side of a pair, but
+    // that cannot be one.
+    InvalidPath { path_buf: PathBuf, message: String },
+    // A file that was there when the run started, and is gone.
+    Vanished(PathBuf),
+    // A generated file whose timestamp did not take the value it was
+    // set to, so the next run would misjudge which side is newer.
+    BackdateFailed { path_buf: PathBuf, expect: mtime, actual: mtime },
+    // Input that a converter could not make sense of, at `line` (from
+    // one) of `path_buf`.
+    InvalidInput { path_buf: PathBuf, line: usize, message: String },
 }
 
+// Something in a `.md` that tango can convert, but suspects is not
+// what the author meant. See `report` for how it is located.
 #[derive(Debug)]
 pub enum Warning {
-    EncodedUrlMismatch { actual: String, expect: String }
+    EncodedUrlMismatch { actual: String, expect: String },
+    // A link that differs from the expected one only in encoding its
+    // code the way tango did before `&`, `+` and `%` were encoded too
+    // (see `playground::legacy`).
+    LegacyUrlEncoding { actual: String, expect: String },
+    // Problems with the named chunks of `chunks::tangle`.
+    UndefinedChunk { name: String },
+    UnusedChunk { name: String },
+    ReusedChunk { name: String },
+    RecursiveChunk { name: String },
+    // A line that does not come back the same way it went out, once
+    // converted to the `.rs` (or `.md`) named by `via` and back.
+    LossyRoundTrip { via: String, reason: String },
+}
+
+// The codes of every kind of `Warning`.
+pub const WARNING_CODES: &'static [&'static str] = &[
+    "encoded-url-mismatch",
+    "legacy-url-encoding",
+    "undefined-chunk",
+    "unused-chunk",
+    "reused-chunk",
+    "recursive-chunk",
+    "lossy-round-trip",
+];
+
+impl Warning {
+    // A name for the kind of warning that stays the same from release
+    // to release, for tools to match on.
+    pub fn code(&self) -> &'static str {
+        match *self {
+            Warning::EncodedUrlMismatch { .. } => "encoded-url-mismatch",
+            Warning::LegacyUrlEncoding { .. } => "legacy-url-encoding",
+            Warning::UndefinedChunk { .. } => "undefined-chunk",
+            Warning::UnusedChunk { .. } => "unused-chunk",
+            Warning::ReusedChunk { .. } => "reused-chunk",
+            Warning::RecursiveChunk { .. } => "recursive-chunk",
+            Warning::LossyRoundTrip { .. } => "lossy-round-trip",
+        }
+    }
 }
 
 impl fmt::Display for Warning {
@@ -74,6 +339,27 @@ impl fmt::Display for Warning {
                 write!(w, "mismatch between encoded url, expect: {} actual: {}",
                        expect, actual)
             }
+            Warning::LegacyUrlEncoding { .. } => {
+                write!(w, "link encodes its code as older versions of tango did, which cuts it \
+                           short at `&` and garbles `+`; `tango --fix-links` updates it")
+            }
+            Warning::UndefinedChunk { ref name } => {
+                write!(w, "reference to undefined chunk <<{}>>", name)
+            }
+            Warning::UnusedChunk { ref name } => {
+                write!(w, "chunk <<{}>> is never used; leaving its code where it is defined",
+                       name)
+            }
+            Warning::ReusedChunk { ref name } => {
+                write!(w, "chunk <<{}>> is used more than once; \
+                           only the first use is read back from the .rs", name)
+            }
+            Warning::RecursiveChunk { ref name } => {
+                write!(w, "chunk <<{}>> refers to itself", name)
+            }
+            Warning::LossyRoundTrip { ref via, ref reason } => {
+                write!(w, "not preserved by converting to .{} and back: {}", via, reason)
+            }
         }
     }
 }
@@ -83,6 +369,24 @@ impl From<md2rs::Exception> for Error {
         match e {
             md2rs::Exception::IoError(e) => Error::IoError(e),
             md2rs::Exception::Warnings(w) => Error::Warnings(w),
+            md2rs::Exception::Invalid(i) => i.into(),
+        }
+    }
+}
+
+impl From<md2rs::Invalid> for Error {
+    fn from(i: md2rs::Invalid) -> Self {
+        Error::InvalidInput { path_buf: PathBuf::new(), line: i.line, message: i.message }
+    }
+}
+
+impl Error {
+    // Says which file an `InvalidInput` came from.
+    fn at(self, p: &Path) -> Error {
+        match self {
+            Error::InvalidInput { line, message, .. } =>
+                Error::InvalidInput { path_buf: p.to_path_buf(), line: line, message: message },
+            e => e,
         }
     }
 }
@@ -100,12 +404,36 @@ impl fmt::Display for Error {
             Error::ConcurrentUpdate { ref path_buf, .. } =>
                 write!(w, "concurrent update during `tango` to source file {}",
                        path_buf.to_string_lossy()),
-            Error::Warnings(ref warnings) => {
-                for warn in warnings {
-                    try!(write!(w, "WARNING: {}", warn));
+            Error::Warnings(ref warnings) =>
+                write!(w, "{} warning{} denied by the `warnings` setting",
+                       warnings.len(), if warnings.len() == 1 { "" } else { "s" }),
+            Error::MergeConflicts(ref paths) => {
+                try!(write!(w, "merge conflicts written to"));
+                for p in paths {
+                    try!(write!(w, " {}", p.display()));
                 }
                 Ok(())
             }
+            Error::ConfigError { ref file, ref key, ref message } => {
+                if key.is_empty() {
+                    write!(w, "invalid configuration in {}: {}", file.display(), message)
+                } else {
+                    write!(w, "invalid configuration in {}: `{}` {}",
+                           file.display(), key, message)
+                }
+            }
+            Error::InvalidFileBlock { ref md, ref file, ref message } =>
+                write!(w, "invalid code block in {}: file=\"{}\" {}", md.display(), file, message),
+            Error::InvalidPath { ref path_buf, ref message } =>
+                write!(w, "cannot sync {}: {}", path_buf.display(), message),
+            Error::Vanished(ref p) =>
+                write!(w, "{} disappeared while `tango` was running", p.display()),
+            Error::BackdateFailed { ref path_buf, ref expect, ref actual } =>
+                write!(w, "could not set the modification time of {} to {}; it is {}",
+                       path_buf.display(), expect.date_fulltime_badly(),
+                       actual.date_fulltime_badly()),
+            Error::InvalidInput { ref path_buf, line, ref message } =>
+                write!(w, "cannot convert {}:{}: {}", path_buf.display(), line, message),
         }
     }
 }
@@ -120,6 +448,13 @@ impl ErrorTrait for Error {
             Error::MtimeError(_) => "Modification time check error",
             Error::ConcurrentUpdate { .. } => "concurrent update",
             Error::Warnings(_) => "warnings",
+            Error::MergeConflicts(_) => "merge conflicts",
+            Error::ConfigError { .. } => "invalid configuration",
+            Error::InvalidFileBlock { .. } => "invalid file attribute on code block",
+            Error::InvalidPath { .. } => "invalid path",
+            Error::Vanished(_) => "file vanished",
+            Error::BackdateFailed { .. } => "could not set modification time",
+            Error::InvalidInput { .. } => "invalid input",
         }
     }
     fn cause(&self) -> Option<&ErrorTrait> {
@@ -129,6 +464,13 @@ impl ErrorTrait for Error {
                 Some(error)
             }
             Error::Warnings(_) |
+            Error::MergeConflicts(_) |
+            Error::ConfigError { .. } |
+            Error::InvalidFileBlock { .. } |
+            Error::InvalidPath { .. } |
+            Error::Vanished(_) |
+            Error::BackdateFailed { .. } |
+            Error::InvalidInput { .. } |
             Error::MtimeError(_) |
             Error::ConcurrentUpdate { .. } => None,
         }
@@ -178,10 +520,21 @@ impl Mtime for fs::DirEntry {
     }
 }
 impl Mtime for RsPath {
+    // An `.rs` counts as modified when any file it sent code to was
+    // (see `files`).
     fn modified(&self) -> Result<MtimeResult> {
         if self.0.exists() {
             let f = try!(File::open(&self.0));
-            f.modified()
+            let mut newest = try!(f.modified());
+            for p in try!(files::companions(&self.0)) {
+                if let Ok(m) = p.metadata() {
+                    match newest {
+                        MtimeResult::Modified(t) if t >= m.timestamp() => {}
+                        _ => newest = MtimeResult::Modified(m.timestamp()),
+                    }
+                }
+            }
+            Ok(newest)
         } else {
             Ok(MtimeResult::NonExistant)
         }
@@ -199,31 +552,42 @@ impl Mtime for MdPath {
 }
 
 pub fn process_root_with_config(config: Config) -> Result<()> {
-    let emit_rerun_if = config.rerun_if;
-    let root = config.root;
-    //println!("Tango is running from: {:?}", root);
-    env::set_current_dir(root).unwrap();
-
-    let stamp_path = Path::new(STAMP);
+    //println!("Tango is running from: {:?}", config.root);
+    let stamp_path = config.root.join(&config.stamp);
     if stamp_path.exists() {
-        process_with_stamp(try!(File::open(stamp_path)), emit_rerun_if)
+        process_with_stamp(try!(File::open(stamp_path)), &config)
     } else {
-        process_without_stamp(emit_rerun_if)
+        process_without_stamp(&config)
     }
 }
 
 
 pub fn process_root() -> Result<()> {
-    let _root = try!(env::current_dir());
-    //println!("Tango is running from: {:?}", _root);
+    process_root_with_config(try!(Config::load(try!(env::current_dir()))))
+}
 
-    let emit_rerun_if = false;
-    let stamp_path = Path::new(STAMP);
-    if stamp_path.exists() {
-        process_with_stamp(try!(File::open(stamp_path)), emit_rerun_if)
+// Reports what `process_root_with_config` would do, without writing
+// anything.
+pub fn status_root_with_config(config: Config) -> Result<Status> {
+    status_with_config(&config)
+}
+
+fn status_with_config(config: &Config) -> Result<Status> {
+    let stamp_path = config.root.join(&config.stamp);
+    let stamp = if stamp_path.exists() {
+        Some(try!(File::open(stamp_path)))
     } else {
-        process_without_stamp(emit_rerun_if)
-    }
+        None
+    };
+    let mut c = try!(Context::new(config, stamp));
+    c.emit_rerun_if = false;
+    c.plan = Some(Vec::new());
+    try!(c.gather_inputs());
+    Ok(Status::from_plan(c.plan.take().unwrap_or(Vec::new())))
+}
+
+pub fn status_root() -> Result<Status> {
+    status_root_with_config(try!(Config::load(try!(env::current_dir()))))
 }
 
 // Both of the functions below have the same basic outline:
@@ -234,12 +598,22 @@ pub fn process_root() -> Result<()> {
 // 2. generate_content(): Apply each transform in turn, *iff* the
 //    source is newer than target.
 //
-// 3. check_input_timestamps(): Ensure no input was concurrently
+// 3. merge_content(): Three-way merge every pair whose source and
+//    target were both edited since the last run (see `merge.rs`).
+//
+// 4. check_input_timestamps(): Ensure no input was concurrently
 //    modified while tango ran.
 //
-// 4. adjust_stamp_timestamp(): Update the `tango.stamp` file to the
+// 5. adjust_stamp_timestamp(): Update the `tango.stamp` file to the
 //    youngest timestamp we saw, creating the file if necessary.
 //
+// 6. update_manifest(): Record the content hash of every file we saw
+//    in `tango.manifest`, so that the next run can tell which side of
+//    each pair was actually edited. (A pre-existing bare
+//    `tango.stamp` is migrated here on the first run that has no
+//    manifest yet.) This also snapshots each `.md` under `BASE_DIR`
+//    to serve as the base of future merges.
+//
 // The reason there are two functions is that in one case we have a
 // pre-existing `tango.stamp` that we want to compare against during
 // `generate_content()` (to guard against diverging {source, target}
@@ -249,46 +623,110 @@ pub fn process_root() -> Result<()> {
 // (It probably wouldn't be hard to unify the two functions into a
 //  single method on the `Context`, though.)
 
-fn process_with_stamp(stamp: File, emit_rerun_if: bool) -> Result<()> {
-    println!("\n\nemit rerun if: {:?}\n\n", emit_rerun_if);
-    if let Ok(MtimeResult::Modified(ts)) = stamp.modified() {
-        println!("Rerunning tango; last recorded run was stamped: {}",
-                 ts.date_fulltime_badly());
-    } else {
-        panic!("why are we trying to process_with_stamp when given: {:?}", stamp);
+fn process_with_stamp(stamp: File, config: &Config) -> Result<()> {
+    println!("\n\nemit rerun if: {:?}\n\n", config.rerun_if);
+    match try!(stamp.modified()) {
+        MtimeResult::Modified(ts) => println!("Rerunning tango; last recorded run was stamped: {}",
+                                              ts.date_fulltime_badly()),
+        MtimeResult::NonExistant => return Err(Error::Vanished(config.root.join(&config.stamp))),
     }
-    let mut c = try!(Context::new(Some(stamp)));
-    c.emit_rerun_if = emit_rerun_if;
+    let mut c = try!(Context::new(config, Some(stamp)));
     try!(c.gather_inputs());
     try!(c.generate_content());
+    try!(c.merge_content());
+    try!(c.report_warnings());
     try!(c.check_input_timestamps());
     try!(c.adjust_stamp_timestamp());
+    try!(c.update_manifest());
+    try!(c.report_conflicts());
     // try!(c.report_dir(Path::new(".")));
     Ok(())
 }
 
-fn process_without_stamp(emit_rerun_if: bool) -> Result<()> {
+fn process_without_stamp(config: &Config) -> Result<()> {
     println!("Running tango; no previously recorded run");
-    println!("\n\nemit rerun if: {:?}\n\n", emit_rerun_if);
-    let mut c = try!(Context::new(None));
-    c.emit_rerun_if = emit_rerun_if;
+    println!("\n\nemit rerun if: {:?}\n\n", config.rerun_if);
+    let mut c = try!(Context::new(config, None));
     try!(c.gather_inputs());
     try!(c.generate_content());
+    try!(c.merge_content());
+    try!(c.report_warnings());
     try!(c.check_input_timestamps());
     try!(c.create_stamp());
     try!(c.adjust_stamp_timestamp());
+    try!(c.update_manifest());
+    try!(c.report_conflicts());
     // try!(c.report_dir(Path::new(".")));
     Ok(())
 }
 
+// Syncs only the pairs that have `paths` as either their `.rs` or
+// their `.md` side, leaving every other pair alone. This is what
+// `tango watch` runs after each burst of edits. Relative paths are
+// taken relative to the configured root.
+pub fn process_paths(paths: &[PathBuf]) -> Result<()> {
+    process_paths_with_config(paths, &try!(Config::load(try!(env::current_dir()))))
+}
+
+pub fn process_paths_with_config(paths: &[PathBuf], config: &Config) -> Result<()> {
+    process_paths_writing(paths, config, &mut Vec::new())
+}
+
+// Like `process_paths_with_config`, but adds every file it writes to
+// `written`, even when it fails partway, so that `watch` can tell
+// tango's own writes from the user's.
+fn process_paths_writing(paths: &[PathBuf], config: &Config, written: &mut Vec<PathBuf>) -> Result<()> {
+    let stamp_path = config.root.join(&config.stamp);
+    let stamp = if stamp_path.exists() {
+        Some(try!(File::open(stamp_path)))
+    } else {
+        None
+    };
+    let fresh = stamp.is_none();
+    let mut c = try!(Context::new(config, stamp));
+    c.emit_rerun_if = false;
+    c.only = Some(paths.iter().map(|p| config.root.join(p)).collect());
+    let result = c.sync_paths(fresh);
+    written.extend(c.written.drain(..));
+    result
+}
+
 #[derive(Debug)]
-struct RsPath(PathBuf);
+struct RsPath(PathBuf, Root);
 #[derive(Debug)]
-struct MdPath(PathBuf);
+struct MdPath(PathBuf, Root);
 
 
 struct Context {
+    // The crate root; every path below is joined onto it.
+    base: PathBuf,
     orig_stamp: Option<(File, mtime)>,
+    manifest: Option<Manifest>,
+    seen: Vec<PathBuf>,
+    merges: Vec<Merge>,
+    conflicts: Vec<PathBuf>,
+    // Every file written so far (see `process_paths_writing`).
+    written: Vec<PathBuf>,
+    // When set, `gather_inputs` records each decision here (for
+    // `tango status`) instead of scheduling or failing.
+    plan: Option<Vec<status::Planned>>,
+    // When set, only pairs with one of these paths on either side are
+    // considered (see `process_paths`).
+    only: Option<Vec<PathBuf>>,
+    roots: Vec<Root>,
+    stamp: PathBuf,
+    // The directory of the stamp, which holds `MANIFEST` and `BASE_DIR`.
+    state: PathBuf,
+    // Glob patterns over crate-relative paths (see `Config::include`).
+    include: Vec<String>,
+    exclude: Vec<String>,
+    playground: Playground,
+    warnings: WarningPolicy,
+    warning_kinds: BTreeMap<String, WarningPolicy>,
+    message_format: MessageFormat,
+    line_endings: LineEndings,
+    // The warnings raised so far, to be reported at the end of the run.
+    diagnostics: Vec<Diagnostic>,
     src_inputs: Vec<Transform<RsPath, MdPath>>,
     lit_inputs: Vec<Transform<MdPath, RsPath>>,
     newest_stamp: Option<mtime>,
@@ -319,37 +757,52 @@ impl ops::Deref for MdPath {
     type Target = Path; fn deref(&self) -> &Path { &self.0 }
 }
 
-fn check_path(typename: &str, p: &Path, ext: &str, root: &str) {
-    println!("\n in check_path, the root is: {:?}", root);
-    if Extensions::extension(p) != Some(ext) { panic!("{t} requires `.{ext}` extension; path: {p:?}", t=typename, ext=ext, p=p); }
-    if !p.starts_with(root) { panic!("{t} must be rooted at `{root}/`; path: {p:?}", t=typename, root=root, p=p); }
+fn check_path(p: &Path, ext: &str, root: &Path) -> Result<()> {
+    if Extensions::extension(p) != Some(ext) {
+        return Err(Error::InvalidPath {
+            path_buf: p.to_path_buf(),
+            message: format!("expected a `.{}` file", ext),
+        });
+    }
+    if !p.starts_with(root) {
+        return Err(Error::InvalidPath {
+            path_buf: p.to_path_buf(),
+            message: format!("not under `{}`", root.display()),
+        });
+    }
+    Ok(())
+}
+
+// Moves `p` from under `from` to the same place under `to`.
+fn reroot(p: &Path, from: &Path, to: &Path) -> PathBuf {
+    let mut q = to.to_path_buf();
+    for c in p.components().skip(from.components().count()) {
+        q.push(c.as_os_str());
+    }
+    q
 }
 
 impl RsPath {
-    fn new(p: PathBuf) -> RsPath {
-        check_path("RsPath", &p, "rs", SRC_DIR);
-        RsPath(p)
-    }
-    fn to_md(&self) -> MdPath {
-        let mut p = PathBuf::new();
-        p.push(LIT_DIR);
-        for c in self.0.components().skip(1) { p.push(c.as_ref().to_str().expect("how else can I replace root?")); }
+    fn new(p: PathBuf, root: Root) -> Result<RsPath> {
+        try!(check_path(&p, "rs", &root.src));
+        Ok(RsPath(p, root))
+    }
+    fn to_md(&self) -> Result<MdPath> {
+        let mut p = reroot(&self.0, &self.1.src, &self.1.lit);
         p.set_extension("md");
-        MdPath::new(p)
+        MdPath::new(p, self.1.clone())
     }
 }
 
 impl MdPath {
-    fn new(p: PathBuf) -> MdPath {
-        check_path("MdPath", &p, "md", LIT_DIR);
-        MdPath(p)
-    }
-    fn to_rs(&self) -> RsPath {
-        let mut p = PathBuf::new();
-        p.push(SRC_DIR);
-        for c in self.0.components().skip(1) { p.push(c.as_ref().to_str().expect("how else can I replace root?")); }
+    fn new(p: PathBuf, root: Root) -> Result<MdPath> {
+        try!(check_path(&p, "md", &root.lit));
+        Ok(MdPath(p, root))
+    }
+    fn to_rs(&self) -> Result<RsPath> {
+        let mut p = reroot(&self.0, &self.1.lit, &self.1.src);
         p.set_extension("rs");
-        RsPath::new(p)
+        RsPath::new(p, self.1.clone())
     }
 }
 
@@ -357,7 +810,9 @@ trait Transforms: Sized + Mtime + fmt::Debug {
     type Target: Mtime + fmt::Debug;
 
     // Computes path to desired target based on self's (source) path.
-    fn target(&self) -> Self::Target;
+    fn target(&self) -> Result<Self::Target>;
+
+    fn path(&self) -> &Path;
 
     // Constructs a transform for generating the target from self
     // (which is a path to the source), gathering the current
@@ -365,14 +820,14 @@ trait Transforms: Sized + Mtime + fmt::Debug {
     fn transform(self) -> Result<Transform<Self, Self::Target>> {
         let source_time = match self.modified() {
             Ok(MtimeResult::Modified(t)) => t,
-            Ok(MtimeResult::NonExistant) => panic!("impossible for {:?} to be NonExistant", self),
+            Ok(MtimeResult::NonExistant) => return Err(Error::Vanished(self.path().to_path_buf())),
             Err(e) => {
                 println!("failure to extract mtime on source {:?}", self);
                 return Err(e);
             }
         };
 
-        let target = self.target();
+        let target = try!(self.target());
         let target_time = match target.modified() {
             Ok(t) => t,
             Err(e) => {
@@ -390,12 +845,14 @@ trait Transforms: Sized + Mtime + fmt::Debug {
 
 impl Transforms for RsPath {
     type Target = MdPath;
-    fn target(&self) -> MdPath { self.to_md() }
+    fn target(&self) -> Result<MdPath> { self.to_md() }
+    fn path(&self) -> &Path { &self.0 }
 }
 
 impl Transforms for MdPath {
     type Target = RsPath;
-    fn target(&self) -> RsPath { self.to_rs() }
+    fn target(&self) -> Result<RsPath> { self.to_rs() }
+    fn path(&self) -> &Path { &self.0 }
 }
 
 #[derive(Debug)]
@@ -419,6 +876,7 @@ pub mod check {
         TargetYoungerThanOriginal { tgt: String, src: String },
         NoTangoStampExists { tgt: String, src: String },
         TangoStampOlderThanTarget { tgt: String },
+        ContentsDivergedSinceSync { src: String, tgt: String },
     }
     #[derive(Debug)]
     pub struct Error(ErrorKind, PathTransform);
@@ -440,6 +898,11 @@ pub mod check {
                                therefore we assume source and target have diverged since last tango run.",
                            tgt)
                 }
+                ErrorKind::ContentsDivergedSinceSync { ref src, ref tgt } => {
+                    write!(w, "both source `{}` and target `{}` were edited since the last \
+                               tango run recorded in `tango.manifest`",
+                           src, tgt)
+                }
             }
         }
     }
@@ -458,12 +921,32 @@ pub mod check {
                     "`tango.stamp` is older than target; \
                      therefore we assume source and target have diverged since last tango run."
                 }
+                ErrorKind::ContentsDivergedSinceSync { .. } => {
+                    "both source and target were edited since the last tango run"
+                }
             }
         }
     }
 
     pub type Result<X> = result::Result<X, Error>;
 
+    impl Error {
+        pub fn kind(&self) -> &ErrorKind {
+            &self.0
+        }
+
+        // True if the error indicates that both source and target were
+        // edited since the last run (as opposed to a missing stamp).
+        pub fn diverged(&self) -> bool {
+            match self.0 {
+                ErrorKind::TangoStampOlderThanTarget { .. } |
+                ErrorKind::ContentsDivergedSinceSync { .. } => true,
+                ErrorKind::TargetYoungerThanOriginal { .. } |
+                ErrorKind::NoTangoStampExists { .. } => false,
+            }
+        }
+    }
+
     impl<X,Y> Transform<X, Y>
         where X: ops::Deref<Target=Path>, Y: ops::Deref<Target=Path>
     {
@@ -480,29 +963,130 @@ pub mod check {
 
 enum TransformNeed { Needed, Unneeded, }
 
+// A pair whose `.rs` and `.md` have diverged, scheduled for a
+// three-way merge against the snapshot in `BASE_DIR`.
+#[derive(Debug)]
+struct Merge {
+    rs: PathBuf,
+    md: PathBuf,
+    base: PathBuf,
+}
+
 impl Context {
-    fn new(opt_stamp: Option<File>) -> Result<Context> {
+    // The steps of `process_paths_writing`, after the inputs are set.
+    fn sync_paths(&mut self, fresh: bool) -> Result<()> {
+        try!(self.gather_inputs());
+        try!(self.generate_content());
+        try!(self.merge_content());
+        try!(self.report_warnings());
+        try!(self.check_input_timestamps());
+        if fresh {
+            try!(self.create_stamp());
+        }
+        try!(self.adjust_stamp_timestamp());
+        try!(self.update_manifest());
+        self.report_conflicts()
+    }
+
+    fn new(config: &Config, opt_stamp: Option<File>) -> Result<Context> {
         let stamp_modified = match opt_stamp {
             None => None,
             Some(stamp) => {
                 let mtime = try!(stamp.modified());
                 let mtime = match mtime {
-                    MtimeResult::NonExistant => panic!("impossible"),
+                    MtimeResult::NonExistant => return Err(Error::Vanished(config.root.join(&config.stamp))),
                     MtimeResult::Modified(t) => t,
                 };
                 Some((stamp, mtime))
             }
         };
+        let stamp = config.root.join(&config.stamp);
+        let state = stamp.parent().map_or(config.root.clone(), |d| d.to_path_buf());
+        let manifest = try!(Manifest::load(state.join(MANIFEST), &config.root));
         let c = Context {
+            base: config.root.clone(),
             orig_stamp: stamp_modified,
+            manifest: manifest,
+            seen: Vec::new(),
+            merges: Vec::new(),
+            conflicts: Vec::new(),
+            written: Vec::new(),
+            plan: None,
+            only: None,
+            roots: Root::all(config),
+            stamp: stamp,
+            state: state,
+            include: config.include.clone(),
+            exclude: config.exclude.clone(),
+            playground: config.playground.clone(),
+            warnings: config.warnings,
+            warning_kinds: config.warning_kinds.clone(),
+            message_format: config.message_format,
+            line_endings: config.line_endings,
+            diagnostics: Vec::new(),
             src_inputs: Vec::new(),
             lit_inputs: Vec::new(),
             newest_stamp: None,
-            emit_rerun_if: true,
+            emit_rerun_if: config.rerun_if,
         };
         Ok(c)
     }
 
+    // `p` relative to the crate root, for reporting.
+    fn rel<'a>(&self, p: &'a Path) -> &'a Path {
+        p.strip_prefix(&self.base).unwrap_or(p)
+    }
+
+    fn base_path(&self, md: &Path) -> PathBuf {
+        self.state.join(BASE_DIR).join(self.rel(md))
+    }
+
+    // Decides whether `t` is needed by comparing the current content
+    // of its source and target against the hashes recorded in
+    // `tango.manifest`. Returns `None` when there is no manifest, or
+    // when either side lacks a record (e.g. a freshly added file), in
+    // which case the caller falls back on `check_transform`. Sides that
+    // were both edited out of agreement come back as `Some(Err(..))`.
+    fn check_contents<X, Y>(&self, t: &Transform<X, Y>) -> Result<Option<check::Result<TransformNeed>>>
+        where X: ops::Deref<Target=Path> + Mtime,
+              Y: ops::Deref<Target=Path> + Mtime,
+    {
+        use self::check::ErrorKind::*;
+
+        let manifest = match self.manifest {
+            Some(ref m) => m,
+            None => return Ok(None),
+        };
+        if let MtimeResult::NonExistant = t.target_time {
+            return Ok(None);
+        }
+        let src_changed = try!(changed(manifest, &t.original));
+        let tgt_changed = try!(changed(manifest, &t.generate));
+        match (src_changed, tgt_changed) {
+            (None, _) | (_, None) => Ok(None),
+            (Some(false), _) => Ok(Some(Ok(TransformNeed::Unneeded))),
+            (Some(true), Some(false)) => Ok(Some(Ok(TransformNeed::Needed))),
+            (Some(true), Some(true)) => {
+                // Both sides were edited; that is fine as long as they
+                // were edited into agreement (e.g. `git checkout` of a
+                // commit that updated both).
+                let in_sync = if t.original.rs_extension() {
+                    try!(corresponds(&self.playground, self.line_endings, &t.generate, &t.original))
+                } else {
+                    try!(corresponds(&self.playground, self.line_endings, &t.original, &t.generate))
+                };
+                if in_sync {
+                    Ok(Some(Ok(TransformNeed::Unneeded)))
+                } else {
+                    Ok(Some(Err(t.error(ContentsDivergedSinceSync {
+                        src: t.original.display().to_string(),
+                        tgt: t.generate.display().to_string(),
+                    }))))
+                }
+            }
+        }
+    }
+
     fn check_transform<X, Y>(&self, t: &Transform<X, Y>) -> check::Result<TransformNeed>
         where X: ops::Deref<Target=Path> + Mtime,
               Y: ops::Deref<Target=Path> + Mtime,
@@ -513,10 +1097,7 @@ impl Context {
 
         let t_mod = match t.target_time {
             MtimeResult::Modified(t) => t,
-            MtimeResult::NonExistant => {
-                assert!(!t.generate.exists());
-                return Ok(TransformNeed::Needed);
-            }
+            MtimeResult::NonExistant => return Ok(TransformNeed::Needed),
         };
         // let src = t.original.display().to_string();
         // let tgt = t.generate.display().to_string();
@@ -626,10 +1207,27 @@ impl Context {
         self.lit_inputs.push(t);
     }
 
+    fn wanted(&self, rs: &Path, md: &Path) -> bool {
+        let only = match self.only {
+            None => true,
+            Some(ref paths) => paths.iter().any(|p| p == rs || p == md) || {
+                let companions = files::companions(rs).unwrap_or(vec![]);
+                paths.iter().any(|p| companions.contains(p))
+            },
+        };
+        only && self.matches(rs) && self.matches(md)
+    }
+
+    // Checks `p` against the configured include and exclude patterns.
+    fn matches(&self, p: &Path) -> bool {
+        let p = self.rel(p).to_string_lossy().replace('\\', "/");
+        let included = self.include.is_empty() ||
+            self.include.iter().any(|pat| settings::glob_match(pat, &p));
+        included && !self.exclude.iter().any(|pat| settings::glob_match(pat, &p))
+    }
+
     fn gather_inputs(&mut self) -> Result<()> {
         // println!("gather_inputs");
-        let src_path = Path::new(SRC_DIR);
-        let lit_path = Path::new(LIT_DIR);
 
         fn keep_file_name(p: &Path) -> std::result::Result<(), &'static str> {
             match p.file_name().and_then(|x|x.to_str()) {
@@ -668,33 +1266,70 @@ impl Context {
         // corresponding target .md files.
 
         // println!("gather-rs");
-        for ent in WalkDir::new(src_path).into_iter() {
-            let ent = try!(ent);
-            let p = ent.path();
-            if let Err(why) = keep_file_name(p) {
-                println!("skipping {}; {}", p.display(), why);
+        let roots = self.roots.clone();
+        let mut visited = HashSet::new();
+        for root in &roots {
+            if !root.src.exists() {
                 continue;
             }
-            if !p.rs_extension() {
-                // println!("gather-rs skip {} due to non .rs", p.display());
-                continue;
-            }
-            let rs = RsPath::new(p.to_path_buf());
-            try!(warn_if_nonexistant(&rs));
-
             if self.emit_rerun_if {
-                println!("cargo:rerun-if-changed={}", &rs.display());
+                // Covers files added to the root later on.
+                println!("cargo:rerun-if-changed={}", root.src.display());
             }
+            for ent in WalkDir::new(&root.src).into_iter() {
+                let ent = try!(ent);
+                let p = ent.path();
+                if let Err(why) = keep_file_name(p) {
+                    if self.plan.is_none() {
+                        println!("skipping {}; {}", p.display(), why);
+                    }
+                    continue;
+                }
+                if !p.rs_extension() {
+                    // println!("gather-rs skip {} due to non .rs", p.display());
+                    continue;
+                }
+                if try!(files::produced_from(p)).is_some() {
+                    // Synced along with the `.rs` that names it.
+                    continue;
+                }
+                if !visited.insert(p.to_path_buf()) {
+                    // Already gathered via an enclosing root.
+                    continue;
+                }
+                let rs = try!(RsPath::new(p.to_path_buf(), root.clone()));
+                if !self.wanted(&rs, &try!(rs.target())) {
+                    continue;
+                }
+                try!(warn_if_nonexistant(&rs));
 
-            let t = try!(rs.transform());
-            match self.check_transform(&t) {
-                Ok(TransformNeed::Needed) => self.push_src(t),
-                Ok(TransformNeed::Unneeded) => {}
-                Err(e) => {
-                    println!("gather_inputs err: {}", e.description());
-                    return Err(Error::CheckInputError {
-                        error: e,
-                    })
+                if self.emit_rerun_if {
+                    println!("cargo:rerun-if-changed={}", &rs.display());
+                }
+
+                let t = try!(rs.transform());
+                self.seen.push(t.original.to_path_buf());
+                self.seen.push(t.generate.to_path_buf());
+                let need = match try!(self.check_contents(&t)) {
+                    Some(need) => need,
+                    None => self.check_transform(&t),
+                };
+                if self.plan.is_some() {
+                    try!(self.plan_transform(&t, &need));
+                    continue;
+                }
+                match need {
+                    Ok(TransformNeed::Needed) => self.push_src(t),
+                    Ok(TransformNeed::Unneeded) => {}
+                    Err(e) => {
+                        if e.diverged() && try!(self.schedule_merge(&t)) {
+                            continue;
+                        }
+                        println!("gather_inputs err: {}", e.description());
+                        return Err(Error::CheckInputError {
+                            error: e,
+                        })
+                    }
                 }
             }
         }
@@ -704,38 +1339,67 @@ impl Context {
         // corresponding target .rs files.
 
         // println!("gather-md");
-        for ent in WalkDir::new(lit_path).into_iter() {
-            let ent = try!(ent);
-            let p = ent.path();
-            if let Err(why) = keep_file_name(p) {
-                println!("skipping {}; {}", p.display(), why);
-                continue;
-            }
-            if !p.md_extension() {
-                // println!("gather-md skip {} due to non .md", p.display());
+        for root in &roots {
+            if !root.lit.exists() {
                 continue;
             }
-            let md = MdPath::new(p.to_path_buf());
-            try!(warn_if_nonexistant(&md));
-
-            if self.emit_rerun_if {
-                println!("cargo:rerun-if-changed={}", &md.display());
+            if self.emit_rerun_if && root.lit != root.src {
+                println!("cargo:rerun-if-changed={}", root.lit.display());
             }
+            for ent in WalkDir::new(&root.lit).into_iter() {
+                let ent = try!(ent);
+                let p = ent.path();
+                if let Err(why) = keep_file_name(p) {
+                    if self.plan.is_none() {
+                        println!("skipping {}; {}", p.display(), why);
+                    }
+                    continue;
+                }
+                if !p.md_extension() {
+                    // println!("gather-md skip {} due to non .md", p.display());
+                    continue;
+                }
+                if !visited.insert(p.to_path_buf()) {
+                    continue;
+                }
+                let md = try!(MdPath::new(p.to_path_buf(), root.clone()));
+                if !self.wanted(&try!(md.target()), &md) {
+                    continue;
+                }
+                try!(warn_if_nonexistant(&md));
 
-            let t = try!(md.transform());
-            match self.check_transform(&t) {
-                Ok(TransformNeed::Needed) => {
-                    // println!("gather-md add {:?}", t);;
-                    self.push_lit(t)
+                if self.emit_rerun_if {
+                    println!("cargo:rerun-if-changed={}", &md.display());
                 }
-                Ok(TransformNeed::Unneeded) => {
-                    // println!("gather-md discard unneeded {:?}", t);;
+
+                let t = try!(md.transform());
+                self.seen.push(t.original.to_path_buf());
+                self.seen.push(t.generate.to_path_buf());
+                let need = match try!(self.check_contents(&t)) {
+                    Some(need) => need,
+                    None => self.check_transform(&t),
+                };
+                if self.plan.is_some() {
+                    try!(self.plan_transform(&t, &need));
+                    continue;
                 }
-                Err(e) => {
-                    println!("gather_inputs err: {}", e.description());
-                    return Err(Error::CheckInputError {
-                        error: e,
-                    })
+                match need {
+                    Ok(TransformNeed::Needed) => {
+                        // println!("gather-md add {:?}", t);;
+                        self.push_lit(t)
+                    }
+                    Ok(TransformNeed::Unneeded) => {
+                        // println!("gather-md discard unneeded {:?}", t);;
+                    }
+                    Err(e) => {
+                        if e.diverged() && try!(self.schedule_merge(&t)) {
+                            continue;
+                        }
+                        println!("gather_inputs err: {}", e.description());
+                        return Err(Error::CheckInputError {
+                            error: e,
+                        })
+                    }
                 }
             }
         }
@@ -750,42 +1414,74 @@ impl Context {
     }
     fn generate_content(&mut self) -> Result<()> {
         for &Transform { ref original, ref generate, source_time, .. } in &self.src_inputs {
-            let source = try!(File::open(&original.0));
-            let target = try!(File::create(&generate.0));
-            assert!(source_time > 0);
+            let source = try!(files::read(&original.0));
+            let mut target = Vec::new();
+            if source_time == 0u64 {
+                return Err(Error::MtimeError(original.to_path_buf()));
+            }
             println!("generating lit {:?}", &generate.0);
-            try!(rs2md(source, target));
+            match rs2md(&self.playground, source.as_bytes(), &mut target).map_err(|e| e.at(original)) {
+                Ok(()) => {}
+                Err(Error::Warnings(ws)) => {
+                    let file = relative_name(&self.base, original);
+                    if judge(ws, &file, self.warnings, &self.warning_kinds, &mut self.diagnostics) {
+                        println!("not generating lit {:?}, due to denied warnings", &generate.0);
+                        continue;
+                    }
+                }
+                Err(e) => return Err(e),
+            }
+            let target = encoding::restyle(&String::from_utf8_lossy(&target), self.line_endings);
+            try!(try!(File::create(&generate.0)).write_all(target.as_bytes()));
+            self.written.push(generate.0.clone());
             let timestamp = source_time.to_filetime();
             println!("backdating lit {:?} to {}", &generate.0, source_time.date_fulltime_badly());
             try!(set_file_times(&generate.0, timestamp, timestamp));
         }
         for &mut Transform { ref original, ref generate, ref mut source_time, .. } in &mut self.lit_inputs {
             let source = try!(File::open(&original.0));
-            let target = try!(File::create(&generate.0));
-            assert!(*source_time > 0);
+            let mut target = Vec::new();
+            if *source_time == 0u64 {
+                return Err(Error::MtimeError(original.to_path_buf()));
+            }
             println!("generating src {:?}", &generate.0);
-            try!(md2rs(source, target));
-            println!("backdating src {:?} to {}", &generate.0, source_time.date_fulltime_badly());
-            try!(set_file_times(&generate.0,
-                                source_time.to_filetime(),
-                                source_time.to_filetime()));
+            match md2rs(&self.playground, source, &mut target).map_err(|e| e.at(original)) {
+                Ok(()) => {}
+                Err(Error::Warnings(ws)) => {
+                    let file = relative_name(&self.base, original);
+                    if judge(ws, &file, self.warnings, &self.warning_kinds, &mut self.diagnostics) {
+                        println!("not generating src {:?}, due to denied warnings", &generate.0);
+                        continue;
+                    }
+                }
+                Err(e) => return Err(e),
+            }
+            let target = encoding::restyle(&String::from_utf8_lossy(&target), self.line_endings);
+            let written = try!(files::write(&self.base, &generate.0, original, &target));
+            self.written.extend(written.iter().cloned());
+            for p in written {
+                println!("backdating src {:?} to {}", p, source_time.date_fulltime_badly());
+                try!(set_file_times(&p, source_time.to_filetime(), source_time.to_filetime()));
+            }
             let source = try!(File::open(&original.0));
             let target = try!(File::open(&generate.0));
-            match (source.modified(), target.modified()) {
-                (Ok(MtimeResult::Modified(src_time)),
-                 Ok(MtimeResult::Modified(tgt_time))) => {
-                    // At this point, we would *like* to assert this:
-                    #[cfg(not_possible_right_now)] assert_eq!(src_time, tgt_time);
-                    // but it does not work, due to this bug:
-                    // https://github.com/alexcrichton/filetime/issues/9
-
-                    assert_eq!(src_time.to_ms(), tgt_time.to_ms());
-                }
-                (Ok(MtimeResult::NonExistant), _) => panic!("how could source not exist"),
-                (_, Ok(MtimeResult::NonExistant)) => panic!("how could target not exist"),
-                (Err(_), Err(_)) => panic!("errored looking up both source and target times"),
-                (Err(_), _) => panic!("errored looking up source time"),
-                (_, Err(_)) => panic!("errored looking up target time"),
+            let src_time = match source.modified() {
+                Ok(MtimeResult::Modified(t)) => t,
+                _ => return Err(Error::MtimeError(original.to_path_buf())),
+            };
+            let tgt_time = match target.modified() {
+                Ok(MtimeResult::Modified(t)) => t,
+                _ => return Err(Error::MtimeError(generate.to_path_buf())),
+            };
+            // At this point, we would *like* to check that the times
+            // are equal, but that does not work, due to this bug:
+            // https://github.com/alexcrichton/filetime/issues/9
+            if src_time.to_ms() != tgt_time.to_ms() {
+                return Err(Error::BackdateFailed {
+                    path_buf: generate.to_path_buf(),
+                    expect: src_time,
+                    actual: tgt_time,
+                });
             }
         }
         Ok(())
@@ -816,15 +1512,222 @@ impl Context {
         Ok(())
     }
     fn create_stamp(&mut self) -> Result<()> {
-        let _f = try!(File::create(STAMP));
+        let _f = try!(File::create(&self.stamp));
+        Ok(())
+    }
+    fn plan_transform<X, Y>(&mut self, t: &Transform<X, Y>,
+                            need: &check::Result<TransformNeed>) -> Result<()>
+        where X: ops::Deref<Target=Path>, Y: ops::Deref<Target=Path>,
+    {
+        let recorded = |p: &Path| self.manifest.as_ref().and_then(|m| m.get(p)).map(|r| *r);
+        let describe = |p: &Path| -> Result<String> {
+            let current = try!(manifest::hash_file(p));
+            Ok(match recorded(p) {
+                Some(r) => format!("`{}` hash {:016x} (recorded {:016x})",
+                                   p.display(), current, r.hash),
+                None => format!("`{}` hash {:016x} (unrecorded)", p.display(), current),
+            })
+        };
+        let (basis, orphaned) = match t.target_time {
+            MtimeResult::NonExistant if recorded(&t.generate).is_some() => {
+                (format!("target `{}` is recorded in `{}` but missing",
+                         t.generate.display(), MANIFEST), true)
+            }
+            MtimeResult::NonExistant => {
+                (format!("target `{}` does not exist", t.generate.display()), false)
+            }
+            MtimeResult::Modified(tgt_time) => {
+                if recorded(&t.original).is_some() && recorded(&t.generate).is_some() {
+                    (format!("content: source {}; target {}",
+                             try!(describe(&t.original)), try!(describe(&t.generate))),
+                     false)
+                } else {
+                    let stamp = match self.orig_stamp {
+                        Some((_, ts)) => ts.date_fulltime_badly(),
+                        None => "absent".to_string(),
+                    };
+                    (format!("mtime: source {}; target {}; `{}` {}",
+                             t.source_time.date_fulltime_badly(),
+                             tgt_time.date_fulltime_badly(), self.rel(&self.stamp).display(), stamp),
+                     false)
+                }
+            }
+        };
+        let outcome = match *need {
+            _ if orphaned => status::Outcome::Orphaned,
+            Ok(TransformNeed::Needed) => status::Outcome::Needed,
+            Ok(TransformNeed::Unneeded) => status::Outcome::Unneeded,
+            Err(ref e) => status::Outcome::Failed {
+                kind: format!("{:?}", e.kind()),
+                diverged: e.diverged(),
+            },
+        };
+        let planned = status::Planned {
+            source: self.rel(&t.original).to_path_buf(),
+            target: self.rel(&t.generate).to_path_buf(),
+            rs_to_md: t.original.rs_extension(),
+            outcome: outcome,
+            basis: basis,
+        };
+        if let Some(ref mut plan) = self.plan {
+            plan.push(planned);
+        }
+        Ok(())
+    }
+
+    // Schedules a merge for the pair underlying `t` if we have a merge
+    // base for it; returns false if we do not, in which case the
+    // divergence remains an error.
+    fn schedule_merge<X, Y>(&mut self, t: &Transform<X, Y>) -> Result<bool>
+        where X: ops::Deref<Target=Path>, Y: ops::Deref<Target=Path>,
+    {
+        let (rs, md) = if t.original.rs_extension() {
+            (t.original.to_path_buf(), t.generate.to_path_buf())
+        } else {
+            (t.generate.to_path_buf(), t.original.to_path_buf())
+        };
+        if self.merges.iter().any(|m| m.md == md) {
+            return Ok(true);
+        }
+        let base = self.base_path(&md);
+        if !base.exists() {
+            println!("no merge base for {}; cannot merge", md.display());
+            return Ok(false);
+        }
+        println!("both {} and {} changed; scheduling merge", rs.display(), md.display());
+        self.merges.push(Merge { rs: rs, md: md, base: base });
+        Ok(true)
+    }
+    // Writes out every warning raised during the run, and fails it if
+    // any of them was denied.
+    fn report_warnings(&mut self) -> Result<()> {
+        let stdout = io::stdout();
+        for d in &self.diagnostics {
+            try!(d.write(self.message_format, stdout.lock()));
+        }
+        let (denied, _): (Vec<Diagnostic>, _) = self.diagnostics.drain(..)
+            .partition(|d| d.severity == Severity::Error);
+        if denied.is_empty() {
+            Ok(())
+        } else {
+            Err(Error::Warnings(denied))
+        }
+    }
+
+    fn merge_content(&mut self) -> Result<()> {
+        for m in &self.merges {
+            // The merge is of the text alone; it comes out laid out
+            // the way the `.md` was.
+            let (_, base, _) = encoding::strip(&try!(encoding::read(&m.base)));
+            let (style, ours, _) = encoding::strip(&try!(encoding::read(&m.md)));
+            let style = style.with(self.line_endings);
+            // What the two sides raise is reported as for a plain
+            // generate; the base is an old snapshot, and is not.
+            let mut theirs = Vec::new();
+            match rs2md(&self.playground, try!(files::read(&m.rs)).as_bytes(), &mut theirs).map_err(|e| e.at(&m.rs)) {
+                Ok(()) => {}
+                Err(Error::Warnings(ws)) => {
+                    let file = relative_name(&self.base, &m.rs);
+                    if judge(ws, &file, self.warnings, &self.warning_kinds, &mut self.diagnostics) {
+                        println!("not merging {} and {}, due to denied warnings", m.md.display(), m.rs.display());
+                        continue;
+                    }
+                }
+                Err(e) => return Err(e),
+            }
+            let (_, theirs, _) = encoding::strip(&String::from_utf8_lossy(&theirs));
+            let mut base_rs = Vec::new();
+            match md2rs(&self.playground, base.as_bytes(), &mut base_rs) {
+                Ok(()) | Err(Error::Warnings(_)) => {}
+                Err(e) => return Err(e),
+            }
+            let mut base_rt = Vec::new();
+            match rs2md(&self.playground, &base_rs[..], &mut base_rt) {
+                Ok(()) | Err(Error::Warnings(_)) => {}
+                Err(e) => return Err(e),
+            }
+            let base_rt = String::from_utf8_lossy(&base_rt).into_owned();
+
+            let base = merge::segments(&base);
+            let theirs = merge::denormalize(&base, &merge::segments(&base_rt),
+                                            merge::segments(&theirs));
+            let merged = merge::merge3(&base, &merge::segments(&ours), &theirs,
+                                       &self.rel(&m.md).display().to_string(),
+                                       &self.rel(&m.rs).display().to_string());
+            println!("merging {} and {} ({} conflicts)",
+                     m.md.display(), m.rs.display(), merged.conflicts);
+            let merged_md = style.apply(&merged.text);
+            let mut rs = Vec::new();
+            match md2rs(&self.playground, merged_md.as_bytes(), &mut rs).map_err(|e| e.at(&m.md)) {
+                Ok(()) => {}
+                Err(Error::Warnings(ws)) => {
+                    let file = relative_name(&self.base, &m.md);
+                    if judge(ws, &file, self.warnings, &self.warning_kinds, &mut self.diagnostics) {
+                        println!("not merging {} and {}, due to denied warnings", m.md.display(), m.rs.display());
+                        continue;
+                    }
+                }
+                Err(e) => return Err(e),
+            }
+            try!(try!(File::create(&m.md)).write_all(merged_md.as_bytes()));
+            self.written.push(m.md.clone());
+            let written = try!(files::write(&self.base, &m.rs, &m.md, &String::from_utf8_lossy(&rs)));
+            self.written.extend(written);
+            if merged.conflicts > 0 {
+                self.conflicts.push(m.md.clone());
+            }
+        }
+        let merged: Vec<PathBuf> = self.merges.iter().map(|m| m.md.clone()).collect();
+        for md in merged {
+            let t = try!(md.metadata()).timestamp();
+            self.update_newest_time(t);
+        }
+        Ok(())
+    }
+    fn report_conflicts(&mut self) -> Result<()> {
+        if self.conflicts.is_empty() {
+            Ok(())
+        } else {
+            Err(Error::MergeConflicts(self.conflicts.clone()))
+        }
+    }
+    fn update_manifest(&mut self) -> Result<()> {
+        if self.manifest.is_none() && self.orig_stamp.is_some() {
+            println!("migrating `{}` into `{}`", self.rel(&self.stamp).display(), MANIFEST);
+        }
+        // A partial run leaves the records of every pair it did not
+        // look at untouched.
+        let mut manifest = match self.only {
+            Some(_) => self.manifest.take().unwrap_or(Manifest::new(&self.base)),
+            None => Manifest::new(&self.base),
+        };
+        let mut seen = self.seen.clone();
+        for p in &self.seen {
+            if p.rs_extension() {
+                seen.extend(try!(files::companions(p)));
+            }
+        }
+        for p in &seen {
+            if p.exists() {
+                manifest.insert(p.clone(), try!(manifest::Record::of(p)));
+                if p.md_extension() {
+                    let base = self.base_path(p);
+                    if let Some(dir) = base.parent() {
+                        try!(fs::create_dir_all(dir));
+                    }
+                    try!(fs::copy(p, base));
+                }
+            }
+        }
+        try!(manifest.save(self.state.join(MANIFEST)));
+        self.manifest = Some(manifest);
         Ok(())
     }
     fn adjust_stamp_timestamp(&mut self) -> Result<()> {
         if let Some(stamp) = self.newest_stamp {
-            assert!(stamp > 0);
             println!("re-stamping tango.stamp to {}", stamp.date_fulltime_badly());
 
-            match set_file_times(STAMP, stamp.to_filetime(), stamp.to_filetime()) {
+            match set_file_times(&self.stamp, stamp.to_filetime(), stamp.to_filetime()) {
                 Ok(()) => Ok(()),
                 Err(e) => Err(Error::IoError(e)),
             }
@@ -834,26 +1737,183 @@ impl Context {
     }
 }
 
-fn rs2md<R:Read, W:Write>(source: R, target: W) -> Result<()> {
-    let mut converter = rs2md::Converter::new();
-    converter.convert(source, target).map_err(Error::IoError)
+// Converts one `.rs` file's content to that of its `.md`, on its own:
+// no paths, timestamps or stamp. Input it cannot convert is an
+// `Error::InvalidInput`, never a panic. Whatever would not come back
+// the same from the `.md` is an `Error::Warnings`, with the output
+// written all the same.
+pub fn rs2md<R:Read, W:Write>(pg: &Playground, source: R, mut target: W) -> Result<()> {
+    let (style, rs, mixed) = try!(read_text(source));
+    let mut md = Vec::new();
+    try!(rs2md::Converter::new(pg.clone()).convert(rs.as_bytes(), &mut md)
+         .map_err(|e| Error::from(md2rs::Exception::from(e))));
+    try!(target.write_all(style.apply(&String::from_utf8_lossy(&md)).as_bytes()));
+    let mut back = Vec::new();
+    match md2rs::Converter::new(pg.clone()).convert(&md[..], &mut back) {
+        Ok(()) | Err(md2rs::Exception::Warnings(_)) => {}
+        Err(e) => return Err(e.into()),
+    }
+    let warnings: Vec<Diagnostic> = mixed.map(|line| mixed_endings(&rs, line, "md")).into_iter()
+        .chain(round_trip(&rs, &String::from_utf8_lossy(&back), "md", &[]))
+        .collect();
+    if warnings.is_empty() { Ok(()) } else { Err(Error::Warnings(warnings)) }
+}
+
+// Converts one `.md` file's content to that of its `.rs`, on its own;
+// `Error::Warnings` comes with output written all the same.
+pub fn md2rs<R:Read, W:Write>(pg: &Playground, source: R, mut target: W) -> Result<()> {
+    let (style, md, mixed) = try!(read_text(source));
+    let mut rs = Vec::new();
+    let mut warnings = match md2rs::Converter::new(pg.clone()).convert(md.as_bytes(), &mut rs) {
+        Ok(()) => vec![],
+        Err(md2rs::Exception::Warnings(ws)) => ws,
+        Err(e) => return Err(e.into()),
+    };
+    try!(target.write_all(style.apply(&String::from_utf8_lossy(&rs)).as_bytes()));
+    warnings.extend(mixed.map(|line| mixed_endings(&md, line, "rs")));
+    let mut back = Vec::new();
+    try!(rs2md::Converter::new(pg.clone()).convert(&rs[..], &mut back)
+         .map_err(|e| Error::from(md2rs::Exception::from(e))));
+    // A stale link comes back fixed, as its own warning says.
+    let relinked: Vec<usize> = warnings.iter()
+        .filter(|d| d.warning.code() == "encoded-url-mismatch" ||
+                    d.warning.code() == "legacy-url-encoding")
+        .map(|d| d.line).collect();
+    if let Some(d) = round_trip(&md, &String::from_utf8_lossy(&back), "rs", &relinked) {
+        warnings.push(d);
+    }
+    if warnings.is_empty() { Ok(()) } else { Err(Error::Warnings(warnings)) }
+}
+
+// The text of `source`, with `\n` endings and no BOM, and the style
+// and mixed endings (if any) that `encoding::strip` finds in it.
+fn read_text<R: Read>(mut source: R) -> Result<(encoding::Style, String, Option<usize>)> {
+    let mut bytes = Vec::new();
+    try!(source.read_to_end(&mut bytes));
+    let text = try!(encoding::decode(bytes));
+    Ok(encoding::strip(&text))
+}
+
+// The warning for line `line` (from zero) of `text`, whose ending is
+// not that of the first line, as it will be once converted to `.{via}`
+// and back.
+fn mixed_endings(text: &str, line: usize, via: &str) -> Diagnostic {
+    let width = text.lines().nth(line).map_or(0, |l| l.chars().count());
+    Diagnostic {
+        file: PathBuf::new(),
+        line: line + 1,
+        columns: 1..width + 1,
+        severity: Severity::Warning,
+        warning: Warning::LossyRoundTrip {
+            via: via.to_string(),
+            reason: "its line ending becomes that of the first line".to_string(),
+        },
+    }
+}
+
+// Where `back`, converted to `.{via}` from `text` and back again,
+// first differs from `text`, if anywhere, ignoring the lines (from
+// one) in `skip`. Just that first difference is reported, as the
+// lines after it may well be out of step.
+fn round_trip(text: &str, back: &str, via: &str, skip: &[usize]) -> Option<Diagnostic> {
+    if text == back {
+        return None;
+    }
+    let (lines, back_lines): (Vec<&str>, Vec<&str>) = (text.lines().collect(), back.lines().collect());
+    let differs = (0..lines.len() + 1)
+        .find(|&i| !skip.contains(&(i + 1)) && lines.get(i) != back_lines.get(i));
+    let (line, reason) = match differs.map(|i| (i, lines.get(i), back_lines.get(i))) {
+        Some((i, Some(_), Some(b))) => (i, format!("comes back as {:?}", b)),
+        Some((i, Some(_), None)) => (i, "is dropped".to_string()),
+        Some((i, None, Some(b))) => (i, format!("{:?} is added after the last line", b)),
+        _ if skip.is_empty() && text.ends_with('\n') != back.ends_with('\n') => {
+            let change = if text.ends_with('\n') { "dropped" } else { "added" };
+            (lines.len().saturating_sub(1), format!("the final newline is {}", change))
+        }
+        _ if skip.is_empty() => {
+            (lines.len().saturating_sub(1), "the line endings change".to_string())
+        }
+        _ => return None,
+    };
+    let text = lines.get(line).cloned().unwrap_or("");
+    Some(Diagnostic {
+        file: PathBuf::new(),
+        line: line + 1,
+        columns: 1..text.chars().count() + 1,
+        severity: Severity::Warning,
+        warning: Warning::LossyRoundTrip { via: via.to_string(), reason: reason },
+    })
+}
+
+// Sets the severity of each of the warnings `ws` about `file` as the
+// policies say, and keeps those not allowed in `diagnostics`. Returns
+// true if any was denied.
+fn judge(ws: Vec<Diagnostic>, file: &str, default: WarningPolicy,
+         kinds: &BTreeMap<String, WarningPolicy>, diagnostics: &mut Vec<Diagnostic>) -> bool {
+    let mut denied = false;
+    for mut d in ws {
+        d.file = PathBuf::from(file);
+        let policy = match kinds.get(d.warning.code()) {
+            Some(&policy) => policy,
+            None => default,
+        };
+        d.severity = match policy {
+            WarningPolicy::Deny => Severity::Error,
+            WarningPolicy::Warn => Severity::Warning,
+            WarningPolicy::Allow => continue,
+        };
+        denied |= d.severity == Severity::Error;
+        diagnostics.push(d);
+    }
+    denied
+}
+
+// Returns true if converting the markdown at `md` yields exactly the
+// content currently at `rs`.
+fn corresponds(pg: &Playground, endings: LineEndings, md: &Path, rs: &Path) -> Result<bool> {
+    let mut expect = Vec::new();
+    match md2rs(pg, try!(File::open(md)), &mut expect).map_err(|e| e.at(md)) {
+        Ok(()) | Err(Error::Warnings(_)) => {}
+        Err(e) => return Err(e),
+    }
+    let actual = try!(files::read(rs));
+    Ok(encoding::restyle(&String::from_utf8_lossy(&expect), endings) == actual)
 }
 
-fn md2rs<R:Read, W:Write>(source: R, target: W) -> Result<()> {
-    let converter = md2rs::Converter::new();
-    converter.convert(source, target).map_err(From::from)
+// `p` relative to the crate root at `base`, with `/` separators, as
+// the files produced from a document name it.
+fn relative_name(base: &Path, p: &Path) -> String {
+    p.strip_prefix(base).unwrap_or(p).to_string_lossy().replace('\\', "/")
+}
+
+// Like `Manifest::changed`, but an `.rs` also counts as changed when
+// any file it sent code to did, or has gone missing.
+fn changed(manifest: &Manifest, p: &Path) -> Result<Option<bool>> {
+    let result = try!(manifest.changed(p));
+    if result != Some(false) || !p.rs_extension() {
+        return Ok(result);
+    }
+    for c in try!(files::companions(p)) {
+        if !c.exists() || try!(manifest.changed(&c)) != Some(false) {
+            return Ok(Some(true));
+        }
+    }
+    Ok(result)
 }
 
 mod md2rs;
 
 mod rs2md;
 
-fn encode_to_url(code: &str) -> String {
-    use url::percent_encoding as enc;
-    // let new_code: String = enc::utf8_percent_encode(code.trim(), enc::QUERY_ENCODE_SET);
-    let new_code: String = enc::utf8_percent_encode(code.trim(), enc::USERINFO_ENCODE_SET).collect();
-    format!("https://play.rust-lang.org/?code={}&version=nightly", new_code)
-}
+mod chunks;
+
+mod files;
+
+mod playground;
+
+mod encoding;
+
+mod lexer;
 
 #[cfg(test)]
 mod testing;
@@ -1,19 +1,208 @@
 extern crate tango;
 
+use std::env;
 use std::error::Error;
+use std::io;
+use std::process;
+
+use tango::WarningPolicy;
+use tango::report::MessageFormat;
+
+const USAGE: &'static str = "\
+usage: tango [OPTIONS]             sync every .rs/.md pair under the current directory
+       tango --fix-links [OPTIONS] same, after rewriting stale playground links in
+                                   the .md files
+       tango status [OPTIONS]      explain what `tango` would do, without writing
+       tango --dry-run [OPTIONS]   same as `tango status`
+       tango watch [OPTIONS]       keep syncing as files change, until interrupted
+       tango diagnostics [OPTIONS] read `cargo build --message-format=json` on stdin,
+                                   and report it against the .md files
+       tango fix [OPTIONS]         read the same, apply rustc's machine-applicable
+                                   suggestions to the .md files, and sync
+
+options:
+       --message-format=F          write warnings (or the report of `status` or
+                                   `diagnostics`) as `human` text or as `json`;
+                                   `--json` is the same as `--message-format=json`
+       -D KIND, -W KIND, -A KIND   deny, warn about or allow warnings of KIND
+                                   (such as `unused-chunk`), or of every kind
+                                   if KIND is `warnings`
+
+Settings are read from `tango.toml`, or else from `[package.metadata.tango]`
+in `Cargo.toml`.
+
+Warnings are written as rustc writes them, or with `--message-format=json`, as
+one JSON object per line, once every pair has been looked at. A denied warning
+leaves the .rs of its .md as it was, and fails the run.
+
+`tango status` exits with 0 if nothing would be written, and 1 otherwise.";
 
 pub fn main() {
-    tango::process_root().unwrap_or_else(|e| {
-        let mut cause: Option<&Error> = Some(&e);
-        while let Some(c) = cause {
-            let next_cause = c.cause();
-            if next_cause.is_some() {
-                println!("{}, due to", c.description());
-            } else {
-                println!("root error: {}", c.description());
+    let mut args: Vec<String> = env::args().skip(1).collect();
+    let format = message_format(&mut args);
+    let policies = warning_policies(&mut args);
+    match args.first().map(|s| &s[..]) {
+        None => process(format, &policies),
+        Some("--fix-links") if args.len() == 1 => fix_links(format, &policies),
+        Some("status") | Some("--dry-run") => status(format, &policies, &args[1..]),
+        Some("watch") if args.len() == 1 => watch(format, &policies),
+        Some("diagnostics") => diagnostics(format, &args[1..]),
+        Some("fix") if args.len() == 1 => fix(&policies),
+        Some("help") | Some("--help") | Some("-h") => println!("{}", USAGE),
+        Some(_) => {
+            println!("{}", USAGE);
+            process::exit(2);
+        }
+    }
+}
+
+// Takes any `--message-format=...` (or `--json`, which `status` and
+// `diagnostics` once took instead) out of `args`.
+fn message_format(args: &mut Vec<String>) -> MessageFormat {
+    let mut format = MessageFormat::Human;
+    let mut rest = Vec::new();
+    for arg in args.drain(..) {
+        match &arg[..] {
+            "--message-format=human" => format = MessageFormat::Human,
+            "--message-format=json" | "--json" => format = MessageFormat::Json,
+            a if a.starts_with("--message-format") => {
+                println!("{}", USAGE);
+                process::exit(2);
             }
-            cause = next_cause;
+            _ => rest.push(arg),
+        }
+    }
+    *args = rest;
+    format
+}
+
+// Takes any `-D`, `-W` or `-A` (each followed by a kind of warning,
+// or by `warnings`) out of `args`, in order, so that later ones win.
+fn warning_policies(all: &mut Vec<String>) -> Vec<(String, WarningPolicy)> {
+    let mut policies = Vec::new();
+    let mut rest = Vec::new();
+    let mut args = all.drain(..).collect::<Vec<_>>().into_iter();
+    while let Some(arg) = args.next() {
+        let policy = match &arg[..] {
+            "-D" => WarningPolicy::Deny,
+            "-W" => WarningPolicy::Warn,
+            "-A" => WarningPolicy::Allow,
+            _ => {
+                rest.push(arg);
+                continue;
+            }
+        };
+        match args.next() {
+            Some(ref kind) if kind == "warnings" || tango::WARNING_CODES.contains(&&kind[..]) => {
+                policies.push((kind.clone(), policy));
+            }
+            Some(kind) => {
+                println!("error: {:?} is not a known kind of warning; expected `warnings` or one of {}",
+                         kind, tango::WARNING_CODES.join(", "));
+                process::exit(2);
+            }
+            None => {
+                println!("{}", USAGE);
+                process::exit(2);
+            }
+        }
+    }
+    *all = rest;
+    policies
+}
+
+fn config() -> tango::Config {
+    env::current_dir().map_err(tango::Error::IoError)
+        .and_then(tango::Config::load)
+        .unwrap_or_else(|e| fail(e))
+}
+
+// The settings, with the warning `policies` from the command line
+// applied over them.
+fn config_with(policies: &[(String, WarningPolicy)]) -> tango::Config {
+    let mut config = config();
+    for &(ref kind, policy) in policies {
+        if kind == "warnings" {
+            config.warnings(policy);
+            for code in tango::WARNING_CODES {
+                config.warning(code, policy);
+            }
+        } else {
+            config.warning(kind, policy);
+        }
+    }
+    config
+}
+
+fn process(format: MessageFormat, policies: &[(String, WarningPolicy)]) {
+    let mut config = config_with(policies);
+    config.message_format(format);
+    tango::process_root_with_config(config).unwrap_or_else(|e| fail(e))
+}
+
+fn fix_links(format: MessageFormat, policies: &[(String, WarningPolicy)]) {
+    let mut config = config_with(policies);
+    config.message_format(format);
+    let stdout = io::stdout();
+    tango::fix::fix_links(&config, stdout.lock()).unwrap_or_else(|e| fail(e));
+    tango::process_root_with_config(config).unwrap_or_else(|e| fail(e))
+}
+
+fn watch(format: MessageFormat, policies: &[(String, WarningPolicy)]) {
+    let mut config = config_with(policies);
+    config.message_format(format);
+    tango::watch::watch_root_with_config(config).unwrap_or_else(|e| fail(e))
+}
+
+fn status(format: MessageFormat, policies: &[(String, WarningPolicy)], args: &[String]) {
+    if !args.is_empty() {
+        println!("{}", USAGE);
+        process::exit(2);
+    }
+    let status = tango::status_root_with_config(config_with(policies)).unwrap_or_else(|e| fail(e));
+    let stdout = io::stdout();
+    let result = match format {
+        MessageFormat::Json => status.write_json(stdout.lock()),
+        MessageFormat::Human => status.write_human(stdout.lock()),
+    };
+    result.unwrap_or_else(|e| fail(e));
+    process::exit(if status.writes_pending() { 1 } else { 0 });
+}
+
+fn diagnostics(format: MessageFormat, args: &[String]) {
+    if !args.is_empty() {
+        println!("{}", USAGE);
+        process::exit(2);
+    }
+    let format = match format {
+        MessageFormat::Json => tango::diagnostics::Format::Json,
+        MessageFormat::Human => tango::diagnostics::Format::Human,
+    };
+    let config = config();
+    let stdin = io::stdin();
+    let stdout = io::stdout();
+    tango::diagnostics::map_diagnostics(&config, stdin.lock(), stdout.lock(), format)
+        .unwrap_or_else(|e| fail(e));
+}
+
+fn fix(policies: &[(String, WarningPolicy)]) {
+    let config = config_with(policies);
+    let stdin = io::stdin();
+    let stdout = io::stdout();
+    tango::fix::fix(&config, stdin.lock(), stdout.lock()).unwrap_or_else(|e| fail(e));
+}
+
+fn fail<E: Error>(e: E) -> ! {
+    println!("error: {}", e);
+    let mut cause: Option<&Error> = Some(&e);
+    while let Some(c) = cause {
+        let next_cause = c.cause();
+        if next_cause.is_some() {
+            println!("{}, due to", c.description());
+        } else {
+            println!("root error: {}", c.description());
         }
-        panic!("IO error {}", e.description());
-    })
+        cause = next_cause;
+    }
+    panic!("IO error {}", e.description());
 }
@@ -0,0 +1,157 @@
+// The manifest records, for every file that took part in the last
+// successful tango run, a hash of its content and the modification
+// time it had at that point.
+//
+// `Context::check_contents` (in `lib.rs`) consults it to decide
+// whether a source or target was *actually* edited since the last
+// sync, rather than trusting modification times (which `git
+// checkout`, `touch` and `cp -r` all happily rewrite). The recorded
+// mtime is only a fast-path hint: a file whose mtime is unchanged is
+// assumed to be unchanged, and otherwise we hash its content.
+//
+// The on-disk format is line-oriented text, one file per line:
+//
+//     <hash> TAB <secs>.<nsecs> TAB <path>
+//
+// where the hash is 64-bit FNV-1a rendered as 16 hex digits, and
+// the path is relative to the crate root. In memory, records are
+// keyed by the full path (i.e. joined onto `base`), matching the
+// paths that `Context` works with.
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, BufRead, Read, Write};
+use std::path::{Path, PathBuf};
+
+use super::mtime;
+use super::timestamp::{Timestamp, Timestamped};
+
+const HEADER: &'static str = "# tango manifest v1";
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Record {
+    pub hash: u64,
+    pub mtime: mtime,
+}
+
+#[derive(Debug)]
+pub struct Manifest {
+    base: PathBuf,
+    records: BTreeMap<PathBuf, Record>,
+}
+
+impl Manifest {
+    pub fn new(base: &Path) -> Manifest {
+        Manifest { base: base.to_path_buf(), records: BTreeMap::new() }
+    }
+
+    pub fn load<P: AsRef<Path>>(p: P, base: &Path) -> io::Result<Option<Manifest>> {
+        let p = p.as_ref();
+        if !p.exists() {
+            return Ok(None);
+        }
+        let mut m = Manifest::new(base);
+        let f = io::BufReader::new(try!(File::open(p)));
+        for (i, line) in f.lines().enumerate() {
+            let line = try!(line);
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            match parse_line(&line) {
+                Some((path, record)) => { m.records.insert(base.join(path), record); }
+                None => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("malformed line {} in {}", i+1, p.display())));
+                }
+            }
+        }
+        Ok(Some(m))
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, p: P) -> io::Result<()> {
+        let mut f = try!(File::create(p));
+        try!(writeln!(f, "{}", HEADER));
+        for (path, r) in &self.records {
+            let path = path.strip_prefix(&self.base).unwrap_or(path);
+            try!(writeln!(f, "{:016x}\t{}.{:09}\t{}",
+                          r.hash, r.mtime.secs, r.mtime.nsecs, path.display()));
+        }
+        f.flush()
+    }
+
+    pub fn get(&self, p: &Path) -> Option<&Record> {
+        self.records.get(p)
+    }
+
+    pub fn insert(&mut self, p: PathBuf, r: Record) {
+        self.records.insert(p, r);
+    }
+
+    // Returns `Some(true)` if the content of `p` differs from what was
+    // recorded, `Some(false)` if it matches, and `None` if `p` has no
+    // record at all.
+    pub fn changed(&self, p: &Path) -> io::Result<Option<bool>> {
+        let r = match self.records.get(p) {
+            None => return Ok(None),
+            Some(r) => r,
+        };
+        let now = try!(p.metadata()).timestamp();
+        if now == r.mtime {
+            return Ok(Some(false));
+        }
+        let hash = try!(hash_file(p));
+        Ok(Some(hash != r.hash))
+    }
+}
+
+impl Record {
+    pub fn of(p: &Path) -> io::Result<Record> {
+        let hash = try!(hash_file(p));
+        let mtime = try!(p.metadata()).timestamp();
+        Ok(Record { hash: hash, mtime: mtime })
+    }
+}
+
+fn parse_line(line: &str) -> Option<(PathBuf, Record)> {
+    let mut fields = line.splitn(3, '\t');
+    let hash = match fields.next().and_then(|h| u64::from_str_radix(h, 16).ok()) {
+        Some(h) => h,
+        None => return None,
+    };
+    let time = match fields.next() {
+        Some(t) => t,
+        None => return None,
+    };
+    let mut time = time.splitn(2, '.');
+    let secs = time.next().and_then(|s| s.parse().ok());
+    let nsecs = time.next().and_then(|s| s.parse().ok());
+    let path = fields.next();
+    match (secs, nsecs, path) {
+        (Some(secs), Some(nsecs), Some(path)) if !path.is_empty() => {
+            Some((PathBuf::from(path),
+                  Record { hash: hash, mtime: Timestamp::new(secs, nsecs) }))
+        }
+        _ => None,
+    }
+}
+
+pub fn hash_file(p: &Path) -> io::Result<u64> {
+    let mut content = Vec::new();
+    try!(try!(File::open(p)).read_to_end(&mut content));
+    Ok(hash_bytes(&content))
+}
+
+// 64-bit FNV-1a. We need a hash that is stable across Rust releases
+// and platforms (the manifest outlives any one build), which rules
+// out `std::hash::SipHasher` and friends.
+pub fn hash_bytes(bytes: &[u8]) -> u64 {
+    const OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+    const PRIME: u64 = 0x100000001b3;
+    let mut h = OFFSET_BASIS;
+    for &b in bytes {
+        h ^= b as u64;
+        h = h.wrapping_mul(PRIME);
+    }
+    h
+}
@@ -1,44 +1,518 @@
-use std::io::{self, BufRead, Write};
+use std::error;
+use std::fmt;
+use std::io::{self, Write};
+use std::ops::Range;
+use std::path::PathBuf;
+
+use pulldown_cmark::{CodeBlockKind, Event, Options, Parser, Tag};
 
 pub struct Converter {
     state: State,
     blank_line_count: usize,
     buffered_lines: String,
-    warnings: Vec<Warning>,
+    warnings: Vec<Diagnostic>,
+    playground: Playground,
+    // Whether a playground link here would belong to the preceding
+    // (tangled) code block, and what that block says about its link.
+    linkable: bool,
+    link: LinkOptions,
+    // The code of the blocks before this one, and whether this one is
+    // Rust, and the chunk it defines, if any.
+    context: Context,
+    rust: bool,
+    chunk: Option<String>,
+    // Whether no code of the current block has been seen yet, and
+    // whether the block's code is bracketed by a header that `//@@>>`
+    // closes (a named chunk, see `chunks`, or a `file`, see `files`).
+    fresh: bool,
+    bracketed: bool,
+    // Where the code of the current block has left the lexer, if the
+    // block is Rust (see `lexer`).
+    lexer: Lexer,
+    // The indentation of the prose since the last code block (see
+    // `indent_marker`).
+    indent: String,
+    // Whether a plain block has just ended with nothing written since
+    // but blank lines, so that `rs2md` would run the next one into it.
+    ended: bool,
+    // Whether to tag each line of code with the `.md` line it came
+    // from (see `ORIGIN`), and the line being handled.
+    origins: bool,
+    line: usize,
 }
 
-use super::Warning;
+// What separates a line of code from the number (from zero) of the
+// `.md` line it came from, in the output of a converter made
+// `with_origins`. Lines that are not code from the `.md` (blank lines,
+// `//@` prose, markers, chunk references) carry no tag; and the tags do
+// not change how the output is laid out, so each tagged line ends up in
+// the very same place, in the very same file, as it would untagged.
+pub const ORIGIN: &'static str = "\u{0}@";
+
+// The markers for the bounds of a code block that `rs2md` could not
+// find otherwise, for the code to come back exactly as it was: the
+// start of a block whose first line is blank (which would go before
+// the fence) or that directly follows another (which it would run
+// into), or that has no lines at all; and the end of a block whose
+// last lines are blank (which would go after the fence), or that
+// leaves a literal or comment open (which `rs2md` would read on into;
+// see `lexer`).
+pub const START: &'static str = "//@@start";
+pub const END: &'static str = "//@@end";
+
+use super::{Playground, Warning};
+use super::playground::{Context, LinkContext, LinkOptions};
+use super::chunks;
+use super::lexer::{self, Lexer};
+use super::report::{Diagnostic, Severity};
 
 #[derive(Copy, Clone, PartialEq, Eq, Debug)]
 enum State { MarkdownBlank, MarkdownText, MarkdownMeta, Rust, }
 impl Converter {
-    pub fn new() -> Converter {
+    pub fn new(playground: Playground) -> Converter {
         Converter {
             state: State::MarkdownBlank,
             blank_line_count: 0,
             buffered_lines: String::new(),
             warnings: vec![],
+            playground: playground,
+            linkable: true,
+            link: LinkOptions::default(),
+            context: Context::default(),
+            rust: true,
+            chunk: None,
+            fresh: false,
+            bracketed: false,
+            lexer: Lexer::Code,
+            indent: String::new(),
+            ended: false,
+            origins: false,
+            line: 0,
+        }
+    }
+
+    pub fn with_origins(mut self) -> Converter {
+        self.origins = true;
+        self
+    }
+
+    // `code`, tagged with its origin if we are asked to.
+    fn tagged(&self, code: &str) -> String {
+        if self.origins && chunks::reference(code).is_none() {
+            format!("{}{}{}", code, ORIGIN, self.line)
+        } else {
+            code.to_string()
         }
     }
 }
 
 pub enum Exception {
     IoError(io::Error),
-    Warnings(Vec<Warning>),
+    // Each located within the `.md`, but with no file yet.
+    Warnings(Vec<Diagnostic>),
+    Invalid(Invalid),
 }
 
 impl From<io::Error> for Exception {
     fn from(e: io::Error) -> Self {
-        Exception::IoError(e)
+        match Invalid::from_io(e) {
+            Ok(i) => Exception::Invalid(i),
+            Err(e) => Exception::IoError(e),
+        }
+    }
+}
+
+// Input that would take a converter (this one, or `rs2md`'s) into a
+// state it has no way to go on from, at `line` (from one). It travels
+// as the payload of an `io::Error` through the converter's methods,
+// which all write, and is taken back out at the end.
+#[derive(Debug)]
+pub struct Invalid {
+    pub line: usize,
+    pub message: String,
+}
+
+impl Invalid {
+    pub fn error(line: usize, message: String) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidData, Invalid { line: line, message: message })
+    }
+
+    pub fn from_io(e: io::Error) -> Result<Invalid, io::Error> {
+        let is_invalid = e.get_ref().map_or(false, |inner| inner.is::<Invalid>());
+        if !is_invalid {
+            return Err(e);
+        }
+        match e.into_inner().map(|inner| inner.downcast::<Invalid>()) {
+            Some(Ok(i)) => Ok(*i),
+            _ => Err(io::Error::new(io::ErrorKind::InvalidData, "invalid input")),
+        }
+    }
+}
+
+impl fmt::Display for Invalid {
+    fn fmt(&self, w: &mut fmt::Formatter) -> fmt::Result {
+        write!(w, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl error::Error for Invalid {
+    fn description(&self) -> &str { "invalid input" }
+}
+
+// A fenced Rust code block, as found by the CommonMark parser.
+//
+// Most blocks are written the plain way, a "```rust" line at the left
+// margin closed by a "```" line, and come out in the `.rs` exactly as
+// they always have. Anything else (a tilde or longer fence, an
+// indented fence, or a fence inside a list item or blockquote) has its
+// opening line recorded verbatim in a `//@@fence` line, so that
+// `rs2md` can put it back; see `continuation`. A closing line that
+// `rs2md` could not infer from the opening one is recorded in a
+// `//@@close` line (left empty when the block was never closed).
+#[derive(Debug)]
+pub struct Fence {
+    // Line numbers (from zero) of the opening and closing fences.
+    pub open: usize,
+    pub close: Option<usize>,
+    // The last line of the block; the closing fence if there is one.
+    pub last: usize,
+    // What precedes the code on each content line; see `continuation`.
+    pub prefix: String,
+    pub info: Info,
+    plain: bool,
+}
+
+impl Fence {
+    // The lines of the block, fences included.
+    pub fn lines(&self) -> Range<usize> {
+        self.open..self.last + 1
+    }
+}
+
+// Splits the opening fence line `line` into the prefix that content
+// lines carry in its stead and the fence itself (the run of backticks
+// or tildes). The prefix is whatever precedes the fence (blockquote
+// markers and indentation) with any list item marker replaced by
+// spaces; e.g. "1. > ```rust" gives ("   > ", "```").
+pub fn continuation(line: &str) -> (String, String) {
+    let start = line.find(|c| c == '`' || c == '~').unwrap_or(line.len());
+    let prefix = line[..start].chars()
+        .map(|c| if c == '>' || c.is_whitespace() { c } else { ' ' })
+        .collect();
+    let fence_char = line[start..].chars().next().unwrap_or('`');
+    let fence = line[start..].chars().take_while(|&c| c == fence_char).collect();
+    (prefix, fence)
+}
+
+// Removes as much of `prefix` from the front of `line` as it has; a
+// blank content line, for instance, may lack the trailing space of a
+// "> " prefix.
+pub fn strip_prefix<'a>(line: &'a str, prefix: &str) -> &'a str {
+    let n = line.chars().zip(prefix.chars()).take_while(|&(a, b)| a == b)
+        .map(|(a, _)| a.len_utf8()).sum();
+    &line[n..]
+}
+
+// Rustdoc's hidden lines: within a Rust code block, `# code` (or a
+// lone `#`) is compiled but not shown, and `##code` is shown and
+// compiled as `#code`. Returns the marker for such a line, along with
+// the code rustdoc would compile.
+pub fn hidden(line: &str) -> Option<(&'static str, &str)> {
+    let t = line.trim_left();
+    if t.starts_with("##") {
+        Some(("##", &t[1..]))
+    } else if t.starts_with("# ") {
+        Some(("#", &t[2..]))
+    } else if t == "#" {
+        Some(("#", ""))
+    } else {
+        None
+    }
+}
+
+// `//@` prose within nested code keeps its indentation, which the
+// `.md` records on a line of its own before the prose, as in
+//
+//     <!-- tango: indent 8 -->
+//
+// for prose eight spaces in, or `indent 2 tabs` for two tabs. It holds
+// for the prose up to the next code block. Returns that line for the
+// indentation `indent`, if it needs one and has one.
+pub fn indent_marker(indent: &str) -> Option<String> {
+    let n = indent.chars().count();
+    if n > 0 && indent.chars().all(|c| c == ' ') {
+        Some(format!("<!-- tango: indent {} -->", n))
+    } else if n > 0 && indent.chars().all(|c| c == '\t') {
+        Some(format!("<!-- tango: indent {} tabs -->", n))
+    } else {
+        None
+    }
+}
+
+// The indentation that `line` records, if it is an `indent_marker`.
+pub fn marked_indent(line: &str) -> Option<String> {
+    let (start, end) = ("<!-- tango: indent ", " -->");
+    if !line.starts_with(start) || !line.ends_with(end) || line.len() < start.len() + end.len() {
+        return None;
+    }
+    let rest = &line[start.len()..line.len() - end.len()];
+    let (count, unit) = if rest.ends_with(" tabs") {
+        (&rest[..rest.len() - " tabs".len()], "\t")
+    } else {
+        (rest, " ")
+    };
+    match count.parse::<usize>() {
+        Ok(n) if n > 0 => Some(unit.repeat(n)),
+        _ => None,
+    }
+}
+
+// The text following the fence on an opening fence line.
+pub fn fence_info(line: &str) -> &str {
+    let start = line.find(|c| c == '`' || c == '~').unwrap_or(line.len());
+    &line[start + continuation(line).1.len()..]
+}
+
+// What the info string of a fenced code block says about it, read
+// the way rustdoc reads it: a language (`rust`, or its alias `rs`)
+// followed by attributes, separated by commas or spaces, as in
+// "rust,no_run" or "rs should_panic edition2018". Pandoc-style
+// "{.rust ...}" is Rust too.
+//
+// `ignore` (and `ignore-<target>`) and `compile_fail` blocks are not
+// tangled: they stay in the `.rs` as `//@` prose, exactly as written,
+// so that they round-trip without being compiled. `no_run` and
+// `should_panic` blocks compile as usual, and `editionNNNN` (or
+// `edition=NNNN`), `channel=...`, `mode=...`, `backtrace=...`,
+// `context=...` and `chunks=...` set up the block's playground link
+// (see `playground`). A `file`
+// attribute, as in
+// "{.rust file=\"parser/lexer.rs\"}" or "rust,file=parser/lexer.rs",
+// sends the block's code to that file instead (see `files`). Any other
+// attribute is kept but means nothing to tango.
+//
+// A block in any other language is tangled only if it has a `file`
+// attribute (as in "toml file=../Cargo.toml" or "{.sql file=...}"),
+// and then only into that file.
+#[derive(Clone, Debug)]
+pub struct Info {
+    pub tangled: bool,
+    pub rust: bool,
+    pub link: LinkOptions,
+    pub file: Option<String>,
+}
+
+impl Info {
+    // Returns `None` unless `info` marks the block as one to tangle.
+    pub fn parse(info: &str) -> Option<Info> {
+        let info = info.trim();
+        let file = attribute(info, "file");
+        let link = LinkOptions {
+            edition: attribute(info, "edition"),
+            channel: attribute(info, "channel"),
+            mode: attribute(info, "mode"),
+            backtrace: attribute(info, "backtrace").map(|b| b == "true"),
+            context: match (attribute(info, "chunks"), attribute(info, "context")) {
+                (Some(names), _) => Some(LinkContext::Chunks(
+                    names.split(|c: char| c == ',' || c.is_whitespace())
+                        .filter(|n| !n.is_empty()).map(|n| n.to_string()).collect())),
+                (None, Some(ref c)) if c == "preceding" => Some(LinkContext::Preceding),
+                (None, Some(ref c)) if c == "block" => Some(LinkContext::Block),
+                _ => None,
+            },
+        };
+        if info.starts_with("{.rust") {
+            return Some(Info { tangled: true, rust: true, link: link, file: file });
+        }
+        let mut attrs = info.split(|c: char| c == ',' || c.is_whitespace())
+            .filter(|a| !a.is_empty());
+        match attrs.next() {
+            Some("rust") | Some("rs") => {}
+            _ if file.is_some() => {
+                return Some(Info { tangled: true, rust: false, link: link, file: file });
+            }
+            _ => return None,
+        }
+        let mut result = Info { tangled: true, rust: true, link: link, file: file };
+        for attr in attrs {
+            match attr {
+                "ignore" | "compile_fail" => result.tangled = false,
+                a if a.starts_with("ignore-") => result.tangled = false,
+                a if a.starts_with("edition") && a[7..].chars().all(|c| c.is_digit(10)) &&
+                     a.len() > "edition".len() => {
+                    result.link.edition = Some(a["edition".len()..].to_string());
+                }
+                // Other rustdoc flags (`no_run`, `should_panic`, ...)
+                // and unknown attributes pass through untouched.
+                _ => {}
+            }
+        }
+        Some(result)
+    }
+}
+
+// The value of `key=value` or `key="value"` within `info`.
+fn attribute(info: &str, key: &str) -> Option<String> {
+    let pat = format!("{}=", key);
+    let start = info.match_indices(&pat[..])
+        .map(|(i, _)| i)
+        .find(|&i| i == 0 || info[..i].ends_with(|c: char| c == ',' || c == '{' || c.is_whitespace()));
+    let rest = match start {
+        Some(i) => &info[i + pat.len()..],
+        None => return None,
+    };
+    let value = if rest.starts_with('"') {
+        rest[1..].split('"').next().unwrap_or("")
+    } else {
+        rest.split(|c: char| c == ',' || c == '}' || c.is_whitespace()).next().unwrap_or("")
+    };
+    Some(value.to_string())
+}
+
+// Finds every fenced Rust code block in `text` (and every other block
+// that `Info::parse` would tangle), using a CommonMark (plus GitHub
+// extensions) block parser, so that fences are recognised
+// wherever Markdown would recognise them and nowhere else (e.g. not
+// inside a "````markdown" example).
+pub fn rust_fences(text: &str) -> Vec<Fence> {
+    let mut line_starts = vec![0];
+    line_starts.extend(text.match_indices('\n').map(|(i, _)| i + 1));
+    let line_of = |offset: usize| match line_starts.binary_search(&offset) {
+        Ok(i) => i,
+        Err(i) => i - 1,
+    };
+    let lines: Vec<&str> = text.lines().collect();
+    let mut fences = Vec::new();
+    let options = Options::ENABLE_TABLES | Options::ENABLE_FOOTNOTES |
+        Options::ENABLE_STRIKETHROUGH | Options::ENABLE_TASKLISTS;
+    for (event, range) in Parser::new_ext(text, options).into_offset_iter() {
+        let info = match event {
+            Event::Start(Tag::CodeBlock(CodeBlockKind::Fenced(info))) => info,
+            _ => continue,
+        };
+        let info = match Info::parse(&info) {
+            Some(info) => info,
+            None => continue,
+        };
+        let open = line_of(range.start);
+        let last = line_of(::std::cmp::max(range.start, range.end - 1));
+        let (prefix, fence) = continuation(lines[open]);
+        let close = if last > open && closes(lines[last], &prefix, &fence) {
+            Some(last)
+        } else {
+            None
+        };
+        let plain = (lines[open].starts_with("```rust") || lines[open].starts_with("```{.rust")) &&
+            close.map_or(false, |c| lines[c] == "```");
+        fences.push(Fence {
+            open: open, close: close, last: last, prefix: prefix, info: info, plain: plain,
+        });
+    }
+    fences
+}
+
+// True if `line` closes a block opened with `fence`.
+fn closes(line: &str, prefix: &str, fence: &str) -> bool {
+    let rest = strip_prefix(line, prefix).trim_left();
+    let fence_char = fence.chars().next().unwrap_or('`');
+    let run = rest.chars().take_while(|&c| c == fence_char).count();
+    run >= fence.chars().count() && rest.chars().skip(run).all(|c| c.is_whitespace())
+}
+
+// Where, in the `.md`, to report a warning from `chunks::tangle`: the
+// first definition of a chunk that is used too little or too much, and
+// the first reference to one that is undefined, or that is made from
+// within a definition, for one that refers to itself. Returns the line
+// (from zero) and the columns of the `<<...>>` on it.
+fn locate_chunk(warning: &Warning, lines: &[&str], roles: &[Role],
+                fences: &[Fence]) -> (usize, Range<usize>) {
+    let (name, definition) = match *warning {
+        Warning::UnusedChunk { ref name } | Warning::ReusedChunk { ref name } => (name, true),
+        Warning::UndefinedChunk { ref name } | Warning::RecursiveChunk { ref name } => (name, false),
+        Warning::EncodedUrlMismatch { .. } | Warning::LegacyUrlEncoding { .. } |
+        Warning::LossyRoundTrip { .. } => return (0, 1..1),
+    };
+    let recursive = match *warning { Warning::RecursiveChunk { .. } => true, _ => false };
+    let mut found = None;
+    let mut defining = false;
+    for (i, (line, role)) in lines.iter().zip(roles).enumerate() {
+        let code = match *role {
+            Role::Code(f) => strip_prefix(line, &fences[f].prefix),
+            Role::Open(_) => {
+                defining = false;
+                continue;
+            }
+            _ => continue,
+        };
+        let header = chunks::header(code);
+        if header.is_some() {
+            defining = true;
+        }
+        let hit = if definition {
+            header.map_or(false, |(n, _)| n == name)
+        } else {
+            chunks::reference(code) == Some(&name[..]) && (!recursive || defining)
+        };
+        if hit {
+            found = Some(i);
+            break;
+        }
+        if !definition && found.is_none() && chunks::reference(code) == Some(&name[..]) {
+            found = Some(i);
+        }
     }
+    let line = found.unwrap_or(0);
+    let text = lines.get(line).cloned().unwrap_or("");
+    let start = text.find("<<").map_or(0, |i| text[..i].chars().count());
+    (line, start + 1..text.trim_right().chars().count() + 1)
 }
 
+// The part a line plays in the document.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+enum Role { Markdown, Open(usize), Code(usize), Close, Untangled }
+
 impl Converter {
-    pub fn convert<R:io::Read, W:io::Write>(mut self, r:R, mut w:W) -> Result<(), Exception> {
-        let source = io::BufReader::new(r);
-        for line in source.lines() {
-            let line = try!(line);
-            try!(self.handle(&line, &mut w));
+    pub fn convert<R:io::Read, W:io::Write>(mut self, mut r:R, mut out:W) -> Result<(), Exception> {
+        let mut text = String::new();
+        try!(r.read_to_string(&mut text));
+        let lines: Vec<&str> = text.lines().collect();
+        let fences = rust_fences(&text);
+        let mut roles = vec![Role::Markdown; lines.len()];
+        for (i, f) in fences.iter().enumerate() {
+            if !f.info.tangled {
+                for l in f.lines() {
+                    roles[l] = Role::Untangled;
+                }
+                continue;
+            }
+            for l in f.lines() {
+                roles[l] = Role::Code(i);
+            }
+            roles[f.open] = Role::Open(i);
+            if let Some(c) = f.close {
+                roles[c] = Role::Close;
+            }
+        }
+        // The narrative order is written first, and then rearranged
+        // for any named chunks.
+        let mut w = Vec::new();
+        for (i, (line, &role)) in lines.iter().zip(&roles).enumerate() {
+            self.line = i;
+            try!(self.handle(line, role, &fences, &lines, &mut w));
+        }
+        if self.state == State::Rust {
+            try!(self.transition(&mut w, State::MarkdownBlank));
+        }
+        try!(self.finish_section(&mut w));
+        let (mut tangled, warnings) = chunks::tangle(&String::from_utf8_lossy(&w));
+        // The `.rs` ends in a newline just when the `.md` does.
+        if !text.ends_with('\n') && tangled.ends_with('\n') {
+            tangled.pop();
+        }
+        try!(out.write_all(tangled.as_bytes()));
+        for warning in warnings {
+            let (line, columns) = locate_chunk(&warning, &lines, &roles, &fences);
+            self.warn(warning, line, columns);
         }
         if self.warnings.is_empty() {
             Ok(())
@@ -47,47 +521,113 @@ impl Converter {
         }
     }
 
-    pub fn handle(&mut self, line: &str, w: &mut Write) -> io::Result<()> {
-        let str9 = line.chars().take(9).collect::<String>();
-        let str7 = line.chars().take(7).collect::<String>();
-        match (self.state, &str7[..], &str9[..]) {
-            (State::MarkdownBlank, "```rust", _) |
-            (State::MarkdownText, "```rust", _) => {
-                self.buffered_lines = String::new();
-                let rest =  &line.chars().skip(7).collect::<String>();
+    fn handle(&mut self, line: &str, role: Role, fences: &[Fence], lines: &[&str],
+              w: &mut Write) -> io::Result<()> {
+        // A block left unclosed (say, by the end of its blockquote)
+        // ends where the Markdown resumes.
+        match role {
+            Role::Markdown | Role::Open(_) | Role::Untangled if self.state == State::Rust =>
+                try!(self.transition(w, State::MarkdownBlank)),
+            _ => {}
+        }
+        match role {
+            Role::Open(i) if fences[i].plain => {
+                self.start_block();
+                self.linkable = true;
+                self.rust = true;
+                self.link = fences[i].info.link.clone();
+                let (skip, brace) = if line.starts_with("```rust") { (7, "") } else { (9, " {") };
+                let rest = &line[skip..];
                 if rest != "" {
                     try!(self.transition(w, State::MarkdownMeta));
-                    try!(self.meta_note(&rest, w));
+                    try!(self.meta_note(&format!("{}{}", brace, rest), w));
                 }
-                self.transition(w, State::Rust)
+                try!(self.transition(w, State::Rust));
+                self.open_file(&fences[i].info, w)
             }
-            (State::MarkdownBlank, _, "```{.rust") |
-            (State::MarkdownText, _, "```{.rust") => {
-                self.buffered_lines = String::new();
-                let rest =  &line.chars().skip(9).collect::<String>();
-                if rest != "" {
-                    try!(self.transition(w, State::MarkdownMeta));
-                    try!(self.meta_note(&format!(" {{{}", rest), w));
+            Role::Open(i) => {
+                self.start_block();
+                let f = &fences[i];
+                self.linkable = f.info.rust;
+                self.rust = f.info.rust;
+                self.link = f.info.link.clone();
+                try!(self.transition(w, State::MarkdownMeta));
+                try!(self.meta_note(&format!("fence {}", line), w));
+                let natural = format!("{}{}", f.prefix, continuation(line).1);
+                match f.close {
+                    Some(c) if lines[c] == natural => {}
+                    Some(c) => try!(self.meta_note(&format!("close {}", lines[c]), w)),
+                    None => try!(self.meta_note("close", w)),
+                }
+                try!(self.transition(w, State::Rust));
+                self.open_file(&f.info, w)
+            }
+            Role::Code(i) => {
+                let code = strip_prefix(line, &fences[i].prefix);
+                if self.fresh {
+                    self.fresh = false;
+                    if let Some((name, _)) = chunks::header(code) {
+                        self.chunk = Some(name.to_string());
+                        self.bracketed = true;
+                        return self.marker_line(code.trim(), w);
+                    }
+                    if code.trim().is_empty() || (self.ended && fences[i].plain) {
+                        try!(self.marker_line(&START[4..], w));
+                    }
+                    self.ended = false;
+                }
+                if let (true, Some((marker, rest))) = (fences[i].info.rust, hidden(code)) {
+                    // The code keeps the line's indentation.
+                    let indent = &code[..code.len() - code.trim_left().len()];
+                    let code = format!("{}{}", indent, rest);
+                    self.lexer = self.lexer.after(&code);
+                    self.hidden_line(marker, &code, w)
+                } else if code.is_empty() {
+                    self.blank_line(w)
+                } else {
+                    if self.lexer.needs_escape(code) {
+                        try!(self.marker_line(&lexer::ESCAPE[4..], w));
+                    }
+                    if self.rust {
+                        self.lexer = self.lexer.after(code);
+                    }
+                    self.nonblank_line(code, w)
                 }
-                self.transition(w, State::Rust)
             }
-            (State::Rust, "```", _) => {
+            Role::Close => {
                 self.transition(w, State::MarkdownBlank)
             }
 
-            // FIXME: accum blank lines and only emit them with
-            // prefix if there's no state transition; otherwise
-            // emit them with no prefix. (This is in part the
-            // motivation for the `fn finish_section` design.)
-            (_, "", _) => {
+            // Blank lines are held back until what follows them says
+            // how to write them (see `finish_section`, `START` and
+            // `END`).
+            Role::Markdown if line == "" => {
                 self.blank_line(w)
             }
 
-            _ => {
+            // An untangled block is kept as prose, and any link after
+            // it is left alone.
+            Role::Untangled => {
+                self.linkable = false;
+                if line == "" {
+                    self.blank_line(w)
+                } else {
+                    self.nonblank_line(line, w)
+                }
+            }
+
+            Role::Markdown if marked_indent(line).is_some() => {
+                self.indent = marked_indent(line).unwrap();
+                Ok(())
+            }
+
+            Role::Markdown => {
                 // HACK: if we find anything that looks like a markdown-named playpen link ...
                 let open_pat = "[";
-                let close_pat = "]: https://play.rust-lang.org/?code=";
-                if let (Some(open), Some(close)) = (line.find(open_pat), line.find(close_pat)) {
+                let close_pat = format!("]: {}", self.playground.prefix());
+                if let (true, Some(open), Some(close)) =
+                    (self.linkable, line.find(open_pat), line.find(&close_pat[..]))
+                {
                     // ... then we assume it is associated with the (hopefully immediately preceding)
                     // code block, so we emit a `//@@@` named tag for that code block.
 
@@ -95,13 +635,19 @@ impl Converter {
                     // up with emitted url. If non-match, then warn
                     // the user, and suggest they re-run `tango` after
                     // touching the file to generate matching url.
-                    let expect = super::encode_to_url(&self.buffered_lines);
+                    let expect = self.playground.link(&self.link, &self.context,
+                                                      &self.buffered_lines);
                     let actual = &line[(close+3)..];
                     if expect != actual {
-                        self.warnings.push(Warning::EncodedUrlMismatch {
-                            actual: actual.to_string(),
-                            expect: expect
-                        })
+                        let start = line[..close+3].chars().count() + 1;
+                        let end = line.chars().count() + 1;
+                        let line = self.line;
+                        let warning = if self.playground.legacy(&expect) == actual {
+                            Warning::LegacyUrlEncoding { actual: actual.to_string(), expect: expect }
+                        } else {
+                            Warning::EncodedUrlMismatch { actual: actual.to_string(), expect: expect }
+                        };
+                        self.warn(warning, line, start..end);
                     }
                     self.name_block(line, &line[open+1..close], w)
                 } else {
@@ -111,22 +657,68 @@ impl Converter {
         }
     }
 
+    // Adds the code of the block before to the `context` of later
+    // links, and starts on the next.
+    fn start_block(&mut self) {
+        if self.rust {
+            self.context.add(self.chunk.as_ref().map(|c| &c[..]), &self.buffered_lines);
+        }
+        self.buffered_lines = String::new();
+        self.chunk = None;
+        self.lexer = Lexer::Code;
+    }
+
+    // Records `warning` about line `line` (from zero).
+    fn warn(&mut self, warning: Warning, line: usize, columns: Range<usize>) {
+        self.warnings.push(Diagnostic {
+            file: PathBuf::new(),
+            line: line + 1,
+            columns: columns,
+            severity: Severity::Warning,
+            warning: warning,
+        });
+    }
+
     pub fn meta_note(&mut self, note: &str, w: &mut Write) -> io::Result<()> {
-        assert!(note != "");
+        if note == "" {
+            return Err(self.invalid("empty note on a code block"));
+        }
         self.nonblank_line(note, w)
     }
 
     pub fn name_block(&mut self, _line: &str, name: &str, w: &mut Write) -> io::Result<()> {
-        assert!(name != "");
+        if name == "" {
+            return Err(self.invalid("playground link with no name"));
+        }
+        self.ended = false;
+        let blank_prefix = if self.state == State::MarkdownText { "//@" } else { "" };
+        for _ in 0..self.blank_line_count {
+            try!(writeln!(w, "{}", blank_prefix));
+        }
+        self.blank_line_count = 0;
         writeln!(w, "//@@@ {}", name)
     }
 
+    fn invalid(&self, message: &str) -> io::Error {
+        Invalid::error(self.line + 1, message.to_string())
+    }
+
+    // Fails unless the converter is in state `expect` (or, if `not`,
+    // any other state), before going into state `to`.
+    fn expect_state(&self, expect: State, not: bool, to: State) -> io::Result<()> {
+        if (self.state == expect) != not {
+            return Ok(());
+        }
+        Err(self.invalid(&format!("cannot go from {:?} to {:?}", self.state, to)))
+    }
+
     pub fn nonblank_line(&mut self, line: &str, w: &mut Write) -> io::Result<()> {
+        let indent = &self.indent;
         let (blank_prefix, line_prefix) = match self.state {
-            State::MarkdownBlank => ("", "//@ "),
-            State::MarkdownText => ("//@", "//@ "),
-            State::MarkdownMeta => ("//@", "//@@"),
-            State::Rust => ("", ""),
+            State::MarkdownBlank => (String::new(), format!("{}//@ ", indent)),
+            State::MarkdownText => (format!("{}//@", indent), format!("{}//@ ", indent)),
+            State::MarkdownMeta => ("//@".to_string(), "//@@".to_string()),
+            State::Rust => (String::new(), String::new()),
         };
         for _ in 0..self.blank_line_count {
             try!(writeln!(w, "{}", blank_prefix));
@@ -135,19 +727,57 @@ impl Converter {
         self.blank_line_count = 0;
 
         match self.state {
-            State::MarkdownBlank =>
-                try!(self.transition(w, State::MarkdownText)),
-            State::MarkdownMeta |
-            State::MarkdownText => {}
+            State::MarkdownBlank => {
+                self.ended = false;
+                try!(self.transition(w, State::MarkdownText));
+            }
+            State::MarkdownMeta => {}
+            State::MarkdownText => self.ended = false,
             State::Rust => {
                 self.buffered_lines.push_str("\n");
                 self.buffered_lines.push_str(line);
+                return writeln!(w, "{}", self.tagged(line));
             }
         }
 
         writeln!(w, "{}{}", line_prefix, line)
     }
 
+    // A line that rustdoc would hide or unescape is tangled as the code
+    // rustdoc compiles, after a `//@@#` (or `//@@##`) marker that tells
+    // rs2md to put the `#` back.
+    fn hidden_line(&mut self, marker: &str, code: &str, w: &mut Write) -> io::Result<()> {
+        try!(self.marker_line(marker, w));
+        self.buffered_lines.push_str("\n");
+        self.buffered_lines.push_str(code);
+        writeln!(w, "{}", self.tagged(code))
+    }
+
+    // Marks the code of a block with a `file` attribute, which `files`
+    // will move out to that file.
+    fn open_file(&mut self, info: &Info, w: &mut Write) -> io::Result<()> {
+        match info.file {
+            Some(ref file) => {
+                self.fresh = false;
+                self.bracketed = true;
+                self.marker_line(&format!("file {}", file), w)
+            }
+            None => Ok(()),
+        }
+    }
+
+    // A `//@@` line within code, which is not itself code.
+    fn marker_line(&mut self, marker: &str, w: &mut Write) -> io::Result<()> {
+        if self.state != State::Rust {
+            return Err(self.invalid(&format!("marker `//@@{}` outside of code", marker)));
+        }
+        for _ in 0..self.blank_line_count {
+            try!(writeln!(w, ""));
+        }
+        self.blank_line_count = 0;
+        writeln!(w, "//@@{}", marker)
+    }
+
     fn blank_line(&mut self, _w: &mut Write) -> io::Result<()> {
         match self.state {
             State::Rust => {
@@ -172,20 +802,39 @@ impl Converter {
     fn transition(&mut self, w: &mut Write, s: State) -> io::Result<()> {
         match s {
             State::MarkdownMeta => {
-                assert!(self.state != State::Rust);
+                try!(self.expect_state(State::Rust, true, s));
                 try!(self.finish_section(w));
             }
             State::Rust => {
-                assert!(self.state != State::Rust);
-                self.buffered_lines = String::new();
+                try!(self.expect_state(State::Rust, true, s));
+                try!(self.finish_section(w));
+                self.indent = String::new();
+                self.start_block();
+                self.fresh = true;
             }
             State::MarkdownText => {
-                assert_eq!(self.state, State::MarkdownBlank);
+                try!(self.expect_state(State::MarkdownBlank, false, s));
                 try!(self.finish_section(w));
             }
             State::MarkdownBlank => {
-                assert_eq!(self.state, State::Rust);
+                try!(self.expect_state(State::Rust, false, s));
+                let (fresh, trailing) = (self.fresh, self.blank_line_count > 0);
+                let bracketed = self.bracketed;
+                // A block that leaves a literal or comment open needs
+                // its end marked, or `rs2md` would read on into it.
+                let open = self.lexer != Lexer::Code;
+                if fresh {
+                    try!(self.marker_line(&START[4..], w));
+                }
                 try!(self.finish_section(w));
+                if self.bracketed {
+                    self.bracketed = false;
+                    try!(writeln!(w, "//@@>>"));
+                } else if trailing || open {
+                    try!(writeln!(w, "{}", END));
+                }
+                self.fresh = false;
+                self.ended = !bracketed && !trailing && !open;
             }
         }
         self.state = s;
@@ -0,0 +1,181 @@
+// Three-way merge of a literate document whose `.md` and `.rs` were
+// both edited since the last tango run.
+//
+// The merge works on the Markdown rendering of each side. A document
+// is cut into *segments*: every Rust code block (fences included) is
+// one segment, and the prose between code blocks is cut into
+// headings and paragraphs, each carrying the blank lines that follow
+// it.
+// We then run a classic diff3 over the three segment sequences
+// (base, ours = the edited `.md`, theirs = `rs2md` of the edited
+// `.rs`), so that a segment edited on only one side is taken from
+// that side, and conflict markers are written only around segments
+// that were edited differently on both.
+
+use super::md2rs;
+
+pub struct Merged {
+    pub text: String,
+    pub conflicts: usize,
+}
+
+pub fn segments(md: &str) -> Vec<String> {
+    let lines: Vec<&str> = md.split_terminator('\n').collect();
+    let mut code_end = vec![None; lines.len()];
+    for f in md2rs::rust_fences(md) {
+        code_end[f.open] = Some(f.last);
+    }
+    let mut segs = Vec::new();
+    let mut cur = String::new();
+    let mut after_blank = false;
+    let mut i = 0;
+    while i < lines.len() {
+        if let Some(last) = code_end[i] {
+            if !cur.is_empty() {
+                segs.push(cur);
+                cur = String::new();
+            }
+            for line in &lines[i..last+1] {
+                cur.push_str(line);
+                cur.push('\n');
+            }
+            segs.push(cur);
+            cur = String::new();
+            after_blank = false;
+            i = last + 1;
+            continue;
+        }
+        let line = lines[i];
+        i += 1;
+        let blank = line.trim().is_empty();
+        let heading = line.starts_with('#');
+        if !blank && (after_blank || heading) && !cur.is_empty() {
+            segs.push(cur);
+            cur = String::new();
+        }
+        // A heading is a segment of its own, so anything after it
+        // starts afresh.
+        after_blank = blank || heading;
+        cur.push_str(line);
+        cur.push('\n');
+    }
+    if !cur.is_empty() {
+        segs.push(cur);
+    }
+    segs
+}
+
+// Longest common subsequence of `a` and `b`, as the list of matched
+// index pairs in increasing order.
+fn lcs<T: PartialEq>(a: &[T], b: &[T]) -> Vec<(usize, usize)> {
+    let (n, m) = (a.len(), b.len());
+    let mut table = vec![vec![0usize; m + 1]; n + 1];
+    for i in (0..n).rev() {
+        for j in (0..m).rev() {
+            table[i][j] = if a[i] == b[j] {
+                table[i+1][j+1] + 1
+            } else {
+                ::std::cmp::max(table[i+1][j], table[i][j+1])
+            };
+        }
+    }
+    let mut result = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < n && j < m {
+        if a[i] == b[j] {
+            result.push((i, j));
+            i += 1;
+            j += 1;
+        } else if table[i+1][j] >= table[i][j+1] {
+            i += 1;
+        } else {
+            j += 1;
+        }
+    }
+    result
+}
+
+// `theirs` was produced by round-tripping through the `.rs`, which
+// can normalise details that the original Markdown had (a playground
+// link that no longer matches its code comes back fixed). `base_rt` is the base put through the same round
+// trip; any segment of `theirs` that matches an unchanged `base_rt`
+// segment is replaced by the corresponding original `base` segment,
+// so that the normalisation is not mistaken for an edit.
+pub fn denormalize(base: &[String], base_rt: &[String], theirs: Vec<String>) -> Vec<String> {
+    if base.len() != base_rt.len() {
+        return theirs;
+    }
+    let mut theirs = theirs;
+    for (i, j) in lcs(base_rt, &theirs) {
+        theirs[j] = base[i].clone();
+    }
+    theirs
+}
+
+pub fn merge3(base: &[String], ours: &[String], theirs: &[String],
+              ours_label: &str, theirs_label: &str) -> Merged {
+    let mut to_ours = vec![None; base.len()];
+    for (i, j) in lcs(base, ours) { to_ours[i] = Some(j); }
+    let mut to_theirs = vec![None; base.len()];
+    for (i, j) in lcs(base, theirs) { to_theirs[i] = Some(j); }
+
+    let mut merged = Merged { text: String::new(), conflicts: 0 };
+    let (mut o, mut a, mut b) = (0, 0, 0);
+    loop {
+        // Next base segment that survives unchanged on both sides.
+        let stable = (o..base.len()).filter_map(|i| {
+            match (to_ours[i], to_theirs[i]) {
+                (Some(j), Some(k)) if j >= a && k >= b => Some((i, j, k)),
+                _ => None,
+            }
+        }).next();
+        let (i, j, k) = match stable {
+            Some(s) => s,
+            None => (base.len(), ours.len(), theirs.len()),
+        };
+        if (i, j, k) != (o, a, b) {
+            merged.chunk(&base[o..i], &ours[a..j], &theirs[b..k],
+                         ours_label, theirs_label);
+        }
+        if i == base.len() {
+            break;
+        }
+        merged.text.push_str(&base[i]);
+        o = i + 1;
+        a = j + 1;
+        b = k + 1;
+    }
+    merged
+}
+
+impl Merged {
+    fn chunk(&mut self, base: &[String], ours: &[String], theirs: &[String],
+             ours_label: &str, theirs_label: &str) {
+        if base.len() > 1 && ours.len() == base.len() && theirs.len() == base.len() {
+            // Adjacent edits, e.g. prose changed in the `.md` right
+            // before a code block changed in the `.rs`. Since nothing
+            // was inserted or removed, settle each segment separately.
+            for i in 0..base.len() {
+                self.chunk(&base[i..i+1], &ours[i..i+1], &theirs[i..i+1],
+                           ours_label, theirs_label);
+            }
+        } else if ours == base || ours == theirs {
+            self.push_all(theirs);
+        } else if theirs == base {
+            self.push_all(ours);
+        } else {
+            self.conflicts += 1;
+            self.text.push_str(&format!("<<<<<<< {}\n", ours_label));
+            self.push_all(ours);
+            self.text.push_str("=======\n");
+            self.push_all(theirs);
+            self.text.push_str(&format!(">>>>>>> {}\n", theirs_label));
+        }
+    }
+
+    fn push_all(&mut self, segs: &[String]) {
+        for s in segs {
+            self.text.push_str(s);
+        }
+    }
+}
@@ -0,0 +1,252 @@
+// The links that accompany named code blocks, to run (or look at) the
+// block's code elsewhere: by default on the Rust playground,
+//
+//     [name]: https://play.rust-lang.org/?code=...&version=nightly&edition=2021
+//
+// or on a self-hosted playground (a different `url`), or on Compiler
+// Explorer, whose links carry the whole state of the page:
+//
+//     [name]: https://godbolt.org/clientstate/eyJzZXNzaW9ucyI6...
+//
+// The project sets the defaults (see `settings`), and a block's info
+// string can override the edition (`edition2018`, or `edition=2018`),
+// `channel=beta`, `mode=release` and `backtrace=true` for its own link.
+//
+// A link carries just the code of its block, unless the block needs
+// more to compile: with `context=preceding`, it carries the code of
+// every Rust block before it in the same `.md` too, and with
+// `chunks="types helpers"`, the code of those chunks. Either way,
+// chunk references are expanded, and the whole is wrapped in
+// `fn main() { ... }` if it has no `main` of its own.
+
+use std::collections::BTreeMap;
+
+use url::percent_encoding as enc;
+
+use super::chunks;
+
+pub const PLAYGROUND_URL: &'static str = "https://play.rust-lang.org/";
+pub const COMPILER_EXPLORER_URL: &'static str = "https://godbolt.org/";
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum LinkFormat { Playground, CompilerExplorer }
+
+// What code a link carries, besides that of its own block.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum LinkContext { Block, Preceding, Chunks(Vec<String>) }
+
+// How to form the playground links that accompany named code blocks.
+#[derive(Clone, Debug)]
+pub struct Playground {
+    pub url: String,
+    pub channel: String,
+    pub edition: Option<String>,
+    pub mode: Option<String>,
+    pub backtrace: Option<bool>,
+    pub format: LinkFormat,
+    pub context: LinkContext,
+    // The Compiler Explorer compiler id (such as "r1800"); by default,
+    // the one that follows `channel`, for "beta" and "nightly".
+    pub compiler: Option<String>,
+}
+
+impl Default for Playground {
+    fn default() -> Playground {
+        Playground {
+            url: PLAYGROUND_URL.to_string(),
+            channel: "nightly".to_string(),
+            edition: None,
+            mode: None,
+            backtrace: None,
+            format: LinkFormat::Playground,
+            context: LinkContext::Block,
+            compiler: None,
+        }
+    }
+}
+
+// What the info string of one code block says about its link; each
+// setting left out is the project's.
+#[derive(Clone, Default, Debug)]
+pub struct LinkOptions {
+    pub edition: Option<String>,
+    pub channel: Option<String>,
+    pub mode: Option<String>,
+    pub backtrace: Option<bool>,
+    pub context: Option<LinkContext>,
+}
+
+// The code of the Rust blocks that a converter has been through so
+// far, for `LinkContext`s other than `Block`.
+#[derive(Default, Debug)]
+pub struct Context {
+    // Blocks other than chunk definitions, one after another.
+    code: String,
+    chunks: BTreeMap<String, String>,
+}
+
+impl Context {
+    // Adds the code of a block that has ended; `chunk` is the name of
+    // the chunk it defines (or adds to), if any.
+    pub fn add(&mut self, chunk: Option<&str>, code: &str) {
+        let code = code.trim();
+        if code.is_empty() {
+            return;
+        }
+        let text = match chunk {
+            Some(name) => self.chunks.entry(name.to_string()).or_insert_with(String::new),
+            None => &mut self.code,
+        };
+        if !text.is_empty() {
+            text.push('\n');
+        }
+        text.push_str(code);
+    }
+
+    // Expands the chunk references in `code`; `active` holds the
+    // chunks being expanded already.
+    fn expand(&self, code: &str, active: &mut Vec<String>) -> String {
+        let mut result = Vec::new();
+        for line in code.lines() {
+            match chunks::reference(line) {
+                Some(name) if !active.iter().any(|a| a == name) && self.chunks.contains_key(name) => {
+                    active.push(name.to_string());
+                    result.push(self.expand(&self.chunks[name], active));
+                    active.pop();
+                }
+                _ => result.push(line.to_string()),
+            }
+        }
+        result.join("\n")
+    }
+}
+
+// Percent-encodes what `USERINFO_ENCODE_SET` does, and also what would
+// end the value of a query parameter early, or change it. (Older
+// versions of tango encoded just what `USERINFO_ENCODE_SET` does; see
+// `Playground::legacy`.)
+#[derive(Copy, Clone)]
+struct CodeEncodeSet;
+
+impl enc::EncodeSet for CodeEncodeSet {
+    fn contains(&self, byte: u8) -> bool {
+        match byte {
+            b'&' | b'+' | b'%' => true,
+            _ => enc::USERINFO_ENCODE_SET.contains(byte),
+        }
+    }
+}
+
+impl Playground {
+    // What every link this makes starts with, so that `md2rs` can tell
+    // them from other links.
+    pub fn prefix(&self) -> String {
+        match self.format {
+            LinkFormat::Playground => format!("{}?code=", self.url),
+            LinkFormat::CompilerExplorer => format!("{}clientstate/", self.url),
+        }
+    }
+
+    // The link for the block with the given `options` and `code`,
+    // with as much of `context` as they call for.
+    pub fn link(&self, options: &LinkOptions, context: &Context, code: &str) -> String {
+        let before = match *options.context.as_ref().unwrap_or(&self.context) {
+            LinkContext::Block => return self.url(options, code),
+            LinkContext::Preceding => context.code.clone(),
+            LinkContext::Chunks(ref names) => names.iter()
+                .filter_map(|n| context.chunks.get(n))
+                .map(|c| &c[..]).collect::<Vec<_>>().join("\n"),
+        };
+        let code = context.expand(&format!("{}\n{}", before, code.trim()), &mut vec![]);
+        let code = code.trim();
+        if code.lines().any(has_main) {
+            self.url(options, code)
+        } else {
+            self.url(options, &format!("fn main() {{\n{}\n}}", code))
+        }
+    }
+
+    // The playground link `link` as older versions of tango wrote it,
+    // with `&`, `+` and `%` in its code left unencoded. Such a link is
+    // reported as `legacy-url-encoding` (just a warning, by default)
+    // rather than as a mismatch, so that a project does not stop
+    // building on upgrade; `--fix-links` updates it.
+    pub fn legacy(&self, link: &str) -> String {
+        if self.format != LinkFormat::Playground || !link.starts_with(&self.prefix()) {
+            return link.to_string();
+        }
+        // The code is all that comes before the first `&`.
+        let end = link.find('&').unwrap_or(link.len());
+        let code = link[..end].replace("%26", "&").replace("%2B", "+").replace("%25", "%");
+        format!("{}{}", code, &link[end..])
+    }
+
+    // The link for `code`, from a block with the given `options`.
+    pub fn url(&self, options: &LinkOptions, code: &str) -> String {
+        let edition = options.edition.as_ref().or(self.edition.as_ref());
+        let channel = options.channel.as_ref().unwrap_or(&self.channel);
+        let mode = options.mode.as_ref().or(self.mode.as_ref());
+        let backtrace = options.backtrace.or(self.backtrace);
+        match self.format {
+            LinkFormat::Playground => {
+                let code: String = enc::utf8_percent_encode(code.trim(), CodeEncodeSet).collect();
+                let mut url = format!("{}{}&version={}", self.prefix(), code, channel);
+                if let Some(e) = edition {
+                    url.push_str(&format!("&edition={}", e));
+                }
+                if let Some(m) = mode {
+                    url.push_str(&format!("&mode={}", m));
+                }
+                if let Some(b) = backtrace {
+                    url.push_str(if b { "&backtrace=1" } else { "&backtrace=0" });
+                }
+                url
+            }
+            LinkFormat::CompilerExplorer => {
+                let mut flags = Vec::new();
+                if let Some(e) = edition {
+                    flags.push(format!("--edition {}", e));
+                }
+                if mode.map_or(false, |m| m == "release") {
+                    flags.push("-C opt-level=3".to_string());
+                }
+                let compiler = match self.compiler {
+                    Some(ref id) => Some(&id[..]),
+                    None if channel == "beta" || channel == "nightly" => Some(&channel[..]),
+                    None => None,
+                };
+                let compilers = match compiler {
+                    Some(id) => json!([{ "id": id, "options": flags.join(" ") }]),
+                    None => json!([]),
+                };
+                // The keys keep the order written here (serde_json's
+                // `preserve_order`), so that the links stay as they were.
+                let state = json!({ "sessions": [{
+                    "id": 1, "language": "rust", "source": code.trim(), "compilers": compilers,
+                }] });
+                format!("{}{}", self.prefix(), base64(state.to_string().as_bytes()))
+            }
+        }
+    }
+}
+
+// URL-safe base64, without padding, which Compiler Explorer accepts.
+fn base64(bytes: &[u8]) -> String {
+    const DIGITS: &'static [u8] =
+        b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
+    let mut result = String::new();
+    for group in bytes.chunks(3) {
+        let n = group.iter().enumerate().fold(0u32, |n, (i, &b)| n | (b as u32) << (16 - 8 * i));
+        for i in 0..group.len() + 1 {
+            result.push(DIGITS[(n >> (18 - 6 * i) & 63) as usize] as char);
+        }
+    }
+    result
+}
+
+// Whether `line` starts the definition of a `main` function.
+fn has_main(line: &str) -> bool {
+    let line = line.trim_left();
+    let rest = if line.starts_with("pub ") { &line[4..] } else { line };
+    rest.starts_with("fn main(") || rest.starts_with("fn main (")
+}
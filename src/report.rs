@@ -0,0 +1,75 @@
+// Reporting what tango finds wrong with a file, located precisely
+// enough for an editor or a CI job to annotate it: each `Diagnostic`
+// carries the file, line and columns, a severity, and a stable code
+// naming its kind of `Warning`.
+//
+// As text, a diagnostic looks like rustc's:
+//
+//     warning[unused-chunk]: chunk <<helpers>> is never used; ...
+//       --> src/lib.md:14:1
+//
+// and as JSON, it is one object on a line of its own:
+//
+//     {"file":"src/lib.md","line":14,"column_start":1,"column_end":12,
+//      "severity":"warning","code":"unused-chunk","message":"chunk ..."}
+
+use std::fmt;
+use std::io::{self, Write};
+use std::ops::Range;
+use std::path::PathBuf;
+
+use super::Warning;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum MessageFormat { Human, Json }
+
+// How much a `Diagnostic` matters, as the `WarningPolicy` decides:
+// an `Error` fails the run.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Severity { Error, Warning }
+
+impl Severity {
+    fn name(&self) -> &'static str {
+        match *self {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        }
+    }
+}
+
+// A `Warning` about `file`. The `line`, and the half-open range of
+// `columns` within it, count from one, as rustc's do.
+#[derive(Debug)]
+pub struct Diagnostic {
+    pub file: PathBuf,
+    pub line: usize,
+    pub columns: Range<usize>,
+    pub severity: Severity,
+    pub warning: Warning,
+}
+
+impl Diagnostic {
+    pub fn write<W: Write>(&self, format: MessageFormat, mut w: W) -> io::Result<()> {
+        match format {
+            MessageFormat::Human => writeln!(w, "{}", self),
+            MessageFormat::Json => {
+                writeln!(w, "{}", json!({
+                    "file": self.file.display().to_string(),
+                    "line": self.line,
+                    "column_start": self.columns.start,
+                    "column_end": self.columns.end,
+                    "severity": self.severity.name(),
+                    "code": self.warning.code(),
+                    "message": self.warning.to_string(),
+                }))
+            }
+        }
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, w: &mut fmt::Formatter) -> fmt::Result {
+        write!(w, "{}[{}]: {}\n  --> {}:{}:{}", self.severity.name(), self.warning.code(),
+               self.warning, self.file.display(), self.line, self.columns.start)
+    }
+}
@@ -1,5 +1,9 @@
-use std::io::{self, BufRead, Write};
-use super::encode_to_url;
+use std::io::{self, Read, Write};
+use super::Playground;
+use super::playground::{Context, LinkOptions};
+use super::md2rs;
+use super::chunks;
+use super::lexer::{self, Lexer};
 
 #[derive(Debug)]
 pub struct Converter {
@@ -7,15 +11,57 @@ pub struct Converter {
     blank_line_count: usize,
     buffered_code: String,
     meta_note: Option<String>,
+    // Set by `//@@fence` and `//@@close` for a code block written some
+    // other way than the plain "```rust" and "```" (see `md2rs::Fence`).
+    fence: Option<String>,
+    close: Option<Option<String>>,
+    // While within such a block, what each line of code is prefixed
+    // with, and the fence that closes it.
+    code_prefix: Option<(String, String)>,
+    // What the current code block's info string says about its link,
+    // the code of the blocks before it, and whether it is Rust, and the
+    // chunk it defines, if any.
+    link: LinkOptions,
+    context: Context,
+    rust: bool,
+    chunk: Option<String>,
+    // Set by `//@@#` or `//@@##` for the line of code that follows it
+    // (see `md2rs::hidden`).
+    hidden: Option<String>,
+    // Set by `//@@code` for the line of code that follows it, and where
+    // the code of the current block has left the lexer, if the block is
+    // Rust (see `lexer`).
+    escaped: bool,
+    lexer: Lexer,
+    // The indentation of the first line of prose since the last code
+    // block, once there is one (see `md2rs::indent_marker`).
+    indent: Option<String>,
+    playground: Playground,
+    // The line being handled, from zero, in the `.rs` as `untangle`
+    // leaves it.
+    line: usize,
 }
 #[derive(Copy, Clone, PartialEq, Eq, Debug)]
 enum State { MarkdownFirstLine, MarkdownLines, Rust, }
 impl Converter {
-    pub fn new() -> Converter {
+    pub fn new(playground: Playground) -> Converter {
         Converter { output_state: State::MarkdownFirstLine,
                     blank_line_count: 0,
                     buffered_code: String::new(),
-                    meta_note: None, }
+                    meta_note: None,
+                    fence: None,
+                    close: None,
+                    code_prefix: None,
+                    link: LinkOptions::default(),
+                    context: Context::default(),
+                    rust: true,
+                    chunk: None,
+                    hidden: None,
+                    escaped: false,
+                    lexer: Lexer::Code,
+                    indent: None,
+                    playground: playground,
+                    line: 0, }
     }
 }
 
@@ -37,31 +83,55 @@ enum EffectContext<'a> {
 
 impl Converter {
     pub fn convert<R:io::Read, W:io::Write>(&mut self, r:R, mut w:W) -> io::Result<()> {
-        let source = io::BufReader::new(r);
-        for line in source.lines() {
-            let line = try!(line);
-            try!(self.handle(&line, &mut w));
+        // Named chunks are put back in narrative order first.
+        let mut text = String::new();
+        try!(io::BufReader::new(r).read_to_string(&mut text));
+        let mut md = Vec::new();
+        for (i, line) in chunks::untangle(&text).lines().enumerate() {
+            self.line = i;
+            try!(self.handle(line, &mut md));
         }
-        self.finalize(&mut w)
+        try!(self.finalize(&mut md));
+        // The `.md` ends in a newline just when the `.rs` does.
+        if !text.ends_with('\n') && md.last() == Some(&b'\n') {
+            md.pop();
+        }
+        w.write_all(&md)
     }
 
     pub fn finalize(&mut self, w: &mut Write) -> io::Result<()> {
-        match self.output_state {
-            State::Rust =>
-                self.effect(EffectContext::Finalize, Effect::FinisCodeBlock, w),
-            State::MarkdownFirstLine |
-            State::MarkdownLines =>
-                Ok(())
+        if self.output_state == State::Rust {
+            try!(self.effect(EffectContext::Finalize, Effect::FinisCodeBlock, w));
+        }
+        for _ in 0..self.blank_line_count {
+            try!(self.effect(EffectContext::Finalize, Effect::BlankLitComment, w));
         }
+        self.blank_line_count = 0;
+        Ok(())
     }
 
     pub fn handle(&mut self, line: &str, w: &mut Write) -> io::Result<()> {
         let line_right = line.trim_left();
-        if line_right.is_empty() {
+        let indent = &line[..line.len() - line_right.len()];
+        // A line of just whitespace is code within code (`md2rs` keeps
+        // it as it is), and blank anywhere else.
+        let blank = line.is_empty() || (line_right.is_empty() && self.output_state != State::Rust);
+        // So is a line after a marker for it, and one that the lines
+        // before left within a literal or a comment, whatever it looks
+        // like.
+        // The end of a block is marked even within a literal or comment
+        // that the block left open (see `md2rs::END`).
+        if line_right == "//@@>>" || line_right == md2rs::END {
+            self.lexer = Lexer::Code;
+        }
+        let code = self.hidden.is_some() || self.escaped || self.lexer != Lexer::Code;
+        if blank && self.hidden.is_none() && !self.escaped {
             self.blank_line(w)
+        } else if code {
+            self.code_line(line, w)
         } else if line_right.starts_with("//@ ") {
             let line = &line_right[4..];
-            if line.trim().is_empty() {
+            if line.is_empty() {
                 try!(self.blank_line(w))
             }
             match self.output_state {
@@ -72,9 +142,10 @@ impl Converter {
                 State::MarkdownLines =>
                     {}
             }
-            if line.trim().is_empty() {
+            if line.is_empty() {
                 Ok(())
             } else {
+                try!(self.prose_indent(indent, w));
                 self.nonblank_line(line, w)
             }
         } else if line_right.starts_with("//@@@") {
@@ -95,10 +166,59 @@ impl Converter {
                 }
             }
             Ok(())
+        } else if line_right.starts_with("//@@fence ") {
+            // A fence always starts a new code block, even right after
+            // another one.
+            if self.output_state == State::Rust {
+                try!(self.transition(w, State::MarkdownFirstLine));
+            }
+            self.fence = Some(line_right["//@@fence ".len()..].to_string());
+            Ok(())
+        } else if line_right == "//@@close" || line_right.starts_with("//@@close ") {
+            let close = &line_right["//@@close".len()..];
+            self.close = Some(if close.is_empty() { None } else { Some(close[1..].to_string()) });
+            Ok(())
+        } else if line_right.starts_with("//@@file ") {
+            // The code of a block that `files` keeps in a file of its
+            // own follows, up to `//@@>>`.
+            if self.output_state == State::Rust {
+                try!(self.transition(w, State::MarkdownFirstLine));
+            }
+            self.transition(w, State::Rust)
+        } else if line_right.starts_with("//@@<<") {
+            // A chunk definition is a code block of its own, whose
+            // first line is the header.
+            if self.output_state == State::Rust {
+                try!(self.transition(w, State::MarkdownFirstLine));
+            }
+            try!(self.transition(w, State::Rust));
+            self.chunk = chunks::header(&line_right[4..]).map(|(name, _)| name.to_string());
+            self.effect(EffectContext::NonblankLine(line), Effect::WriteLn(&line_right[4..]), w)
+        } else if line_right == md2rs::START {
+            if self.output_state == State::Rust {
+                try!(self.transition(w, State::MarkdownFirstLine));
+            }
+            self.transition(w, State::Rust)
+        } else if line_right == "//@@>>" || line_right == md2rs::END {
+            if self.output_state == State::Rust {
+                for _ in 0..self.blank_line_count {
+                    try!(self.effect(EffectContext::Transition(State::MarkdownFirstLine),
+                                     Effect::BlankLn, w));
+                }
+                self.blank_line_count = 0;
+                try!(self.transition(w, State::MarkdownFirstLine));
+            }
+            Ok(())
+        } else if line_right == lexer::ESCAPE {
+            self.escaped = true;
+            Ok(())
+        } else if line_right == "//@@#" || line_right == "//@@##" {
+            self.hidden = Some(line_right[4..].to_string());
+            Ok(())
         } else if line_right.starts_with("//@@") {
             let line = &line_right[4..];
-            if !line.trim().is_empty() {
-                self.set_meta_note(line.trim());
+            if !line.is_empty() {
+                self.set_meta_note(line);
             }
             Ok(())
         } else if line_right.starts_with("//@") {
@@ -114,21 +234,57 @@ impl Converter {
             if line.trim().is_empty() {
                 self.blank_line(w)
             } else {
+                try!(self.prose_indent(indent, w));
                 self.nonblank_line(line, w)
             }
         } else {
-            match self.output_state {
-                State::MarkdownFirstLine |
-                State::MarkdownLines =>
-                    try!(self.transition(w, State::Rust)),
-                _ => {}
-            }
-            self.nonblank_line(line, w)
+            self.code_line(line, w)
+        }
+    }
+
+    fn code_line(&mut self, line: &str, w: &mut Write) -> io::Result<()> {
+        match self.output_state {
+            State::MarkdownFirstLine |
+            State::MarkdownLines =>
+                try!(self.transition(w, State::Rust)),
+            _ => {}
+        }
+        self.escaped = false;
+        if self.rust {
+            self.lexer = self.lexer.after(line);
+        }
+        // A hidden line's `#` goes after its indentation.
+        let line_right = line.trim_left();
+        let indent = &line[..line.len() - line_right.len()];
+        match self.hidden.take() {
+            Some(ref marker) if marker == "##" =>
+                self.shown_line(line, &format!("{}#{}", indent, line_right), w),
+            Some(_) if line_right.is_empty() => self.shown_line(line, &format!("{}#", indent), w),
+            Some(_) => self.shown_line(line, &format!("{}# {}", indent, line_right), w),
+            None => self.nonblank_line(line, w),
+        }
+    }
+
+    // Records the indentation of the first line of prose since the
+    // last code block, for `md2rs` to put back.
+    fn prose_indent(&mut self, indent: &str, w: &mut Write) -> io::Result<()> {
+        if self.indent.is_some() {
+            return Ok(());
+        }
+        self.indent = Some(indent.to_string());
+        match md2rs::indent_marker(indent) {
+            Some(marker) => self.nonblank_line(&marker, w),
+            None => Ok(()),
         }
     }
 
     fn emit_named_code(&mut self, name: &str, w: &mut Write) -> io::Result<()> {
-        writeln!(w, "[{}]: {}", name, encode_to_url(&self.buffered_code))
+        let url = self.playground.link(&self.link, &self.context, &self.buffered_code);
+        for _ in 0..self.blank_line_count {
+            try!(self.effect(EffectContext::NonblankLine(name), Effect::BlankLitComment, w));
+        }
+        self.blank_line_count = 0;
+        writeln!(w, "[{}]: {}", name, url)
     }
 
     fn set_meta_note(&mut self, note: &str) {
@@ -141,21 +297,48 @@ impl Converter {
     fn effect(&mut self, _c: EffectContext, e: Effect, w: &mut Write) -> io::Result<()> {
         // println!("effect _c: {:?} e: {:?}", _c, e);
         match e {
-            Effect::BlankLn => writeln!(w, ""),
-            Effect::WriteLn(line) => writeln!(w, "{}", line),
+            Effect::BlankLn => match self.code_prefix {
+                Some((ref prefix, _)) => writeln!(w, "{}", prefix.trim_right()),
+                None => writeln!(w, ""),
+            },
+            Effect::WriteLn(line) => match self.code_prefix {
+                Some((ref prefix, _)) => writeln!(w, "{}{}", prefix, line),
+                None => writeln!(w, "{}", line),
+            },
             Effect::StartCodeBlock => {
-                if let Some(ref note) = self.meta_note {
-                    assert_eq!(note.chars().next(), Some('{'));
-                    try!(writeln!(w, "```{{.rust{}", &note[1..]));
+                // The note is what followed "```rust" on the fence
+                // line, or, for "```{.rust", the rest of the attributes.
+                let open = if let Some(fence) = self.fence.take() {
+                    self.code_prefix = Some(md2rs::continuation(&fence));
+                    fence
+                } else if let Some(ref note) = self.meta_note {
+                    if note.trim_left().starts_with('{') {
+                        format!("```{{.rust{}", &note.trim_left()[1..])
+                    } else {
+                        format!("```rust{}", note)
+                    }
                 } else {
-                    try!(writeln!(w, "```rust"));
+                    "```rust".to_string()
+                };
+                try!(writeln!(w, "{}", open));
+                if self.rust {
+                    self.context.add(self.chunk.as_ref().map(|c| &c[..]), &self.buffered_code);
                 }
+                let info = md2rs::Info::parse(md2rs::fence_info(&open));
+                self.rust = info.as_ref().map_or(true, |info| info.rust);
+                self.link = info.map_or(LinkOptions::default(), |info| info.link);
+                self.chunk = None;
                 self.meta_note = None;
                 self.buffered_code = String::new();
                 Ok(())
             }
             Effect::FinisCodeBlock => {
-                try!(writeln!(w, "```"));
+                match (self.code_prefix.take(), self.close.take()) {
+                    (Some(_), Some(None)) => {}
+                    (Some(_), Some(Some(close))) => try!(writeln!(w, "{}", close)),
+                    (Some((prefix, fence)), None) => try!(writeln!(w, "{}{}", prefix, fence)),
+                    (None, _) => try!(writeln!(w, "```")),
+                }
                 Ok(())
             }
             Effect::BlankLitComment => writeln!(w, ""),
@@ -163,6 +346,12 @@ impl Converter {
     }
 
     fn nonblank_line(&mut self, line: &str, w: &mut Write) -> io::Result<()> {
+        self.shown_line(line, line, w)
+    }
+
+    // Writes `shown` for `line`; they differ only for a hidden line,
+    // where the playground still gets the code itself.
+    fn shown_line(&mut self, line: &str, shown: &str, w: &mut Write) -> io::Result<()> {
         for _ in 0..self.blank_line_count {
             try!(self.effect(EffectContext::NonblankLine(line), Effect::BlankLn, w));
         }
@@ -170,7 +359,7 @@ impl Converter {
             self.buffered_code = format!("{}\n{}", self.buffered_code, line);
         }
         self.blank_line_count = 0;
-        self.effect(EffectContext::NonblankLine(line), Effect::WriteLn(line), w)
+        self.effect(EffectContext::NonblankLine(line), Effect::WriteLn(shown), w)
     }
 
     fn blank_line(&mut self, _w: &mut Write) -> io::Result<()> {
@@ -185,10 +374,21 @@ impl Converter {
         Ok(())
     }
 
+    // Fails unless the converter is in state `expect` (or, if `not`,
+    // any other state), before going into state `to`.
+    fn expect_state(&self, expect: State, not: bool, to: State) -> io::Result<()> {
+        if (self.output_state == expect) != not {
+            return Ok(());
+        }
+        Err(md2rs::Invalid::error(self.line + 1, format!("cannot go from {:?} to {:?}",
+                                                         self.output_state, to)))
+    }
+
     fn transition(&mut self, w: &mut Write, s: State) -> io::Result<()> {
         match s {
             State::MarkdownFirstLine => {
-                assert_eq!(self.output_state, State::Rust);
+                try!(self.expect_state(State::Rust, false, s));
+                self.lexer = Lexer::Code;
                 try!(self.effect(EffectContext::Transition(s), Effect::FinisCodeBlock, w));
                 for _ in 0..self.blank_line_count {
                     try!(self.effect(EffectContext::Transition(s), Effect::BlankLn, w));
@@ -196,15 +396,16 @@ impl Converter {
                 self.blank_line_count = 0;
             }
             State::MarkdownLines => {
-                assert_eq!(self.output_state, State::MarkdownFirstLine);
+                try!(self.expect_state(State::MarkdownFirstLine, false, s));
                 for _ in 0..self.blank_line_count {
                     try!(self.effect(EffectContext::Transition(s), Effect::BlankLitComment, w));
                 }
                 self.blank_line_count = 0;
             }
             State::Rust => {
-                assert!(self.output_state != State::Rust);
+                try!(self.expect_state(State::Rust, true, s));
                 try!(self.finish_section(w));
+                self.indent = None;
                 for _ in 0..self.blank_line_count {
                     try!(self.effect(EffectContext::Transition(s), Effect::BlankLn, w));
                 }
@@ -0,0 +1,309 @@
+// Project configuration, read either from a standalone `tango.toml`
+// at the crate root or from the `[package.metadata.tango]` table of
+// `Cargo.toml`. (If both are present, `tango.toml` wins, and we say
+// so.) For example:
+//
+//     roots = ["src", "tests", "build.rs"]
+//     src-dir = "src"
+//     lit-dir = "lit"
+//     include = ["src/**"]
+//     exclude = ["src/generated/**"]
+//     stamp = "target/tango.stamp"
+//     line-endings = "lf"
+//     warnings = "warn"
+//
+// or, to set the policy for some kinds of warnings apart from the rest
+// (see `WARNING_CODES`; `lossy-round-trip` and `legacy-url-encoding`
+// are just warnings unless set by name):
+//
+//     [warnings]
+//     default = "warn"
+//     unused-chunk = "allow"
+//     encoded-url-mismatch = "deny"
+//
+//     [playground]
+//     url = "https://play.rust-lang.org/"
+//     channel = "stable"
+//     edition = "2021"
+//     mode = "release"
+//     backtrace = true
+//     context = "preceding"
+//
+// where `format = "compiler-explorer"` makes Compiler Explorer links
+// instead (at https://godbolt.org/, unless `url` says otherwise), with
+// `compiler` naming the compiler they open; and `line-endings` makes
+// every file tango writes end its lines one way ("lf" or "crlf") rather
+// than the way the file it came from does ("preserve", the default).
+// The manifest and the merge bases are kept in the directory of the
+// `stamp`.
+//
+// Every key is optional; anything left out keeps the default that
+// `Config::new` chose. Each problem found is reported against the
+// full key path as written in the file it came from (e.g.
+// `package.metadata.tango.playground.url`), so that the user can find
+// it.
+
+use std::fs::File;
+use std::io::Read;
+use std::path::{Component, Path, PathBuf};
+
+use toml;
+
+use super::{Config, Error, Result, WarningPolicy, CARGO_TOML, TANGO_TOML, WARNING_CODES};
+use super::{LineEndings, LinkContext, LinkFormat, COMPILER_EXPLORER_URL};
+
+const CARGO_PREFIX: &'static str = "package.metadata.tango";
+
+// Applies whatever configuration the crate at `root` carries on top
+// of `config`.
+pub fn load(root: &Path, config: &mut Config) -> Result<()> {
+    let tango_toml = root.join(TANGO_TOML);
+    let cargo_toml = root.join(CARGO_TOML);
+    let metadata = if cargo_toml.exists() {
+        let doc = try!(read(&cargo_toml));
+        let table = ["package", "metadata", "tango"].iter()
+            .fold(Some(&doc), |v, k| v.and_then(|v| v.get(k)));
+        table.cloned()
+    } else {
+        None
+    };
+    if tango_toml.exists() {
+        if metadata.is_some() {
+            println!("warning: ignoring `[{}]` in {}; {} takes precedence",
+                     CARGO_PREFIX, CARGO_TOML, TANGO_TOML);
+        }
+        let doc = try!(read(&tango_toml));
+        apply(&doc, &tango_toml, "", config)
+    } else if let Some(table) = metadata {
+        apply(&table, &cargo_toml, CARGO_PREFIX, config)
+    } else {
+        Ok(())
+    }
+}
+
+fn read(file: &Path) -> Result<toml::Value> {
+    let mut text = String::new();
+    try!(try!(File::open(file)).read_to_string(&mut text));
+    parse(&text, file)
+}
+
+pub fn parse(text: &str, file: &Path) -> Result<toml::Value> {
+    text.parse::<toml::Value>().map_err(|e| Error::ConfigError {
+        file: file.to_path_buf(),
+        key: String::new(),
+        message: e.to_string(),
+    })
+}
+
+// Reads the table `value` (found at `prefix` within `file`) into
+// `config`.
+pub fn apply(value: &toml::Value, file: &Path, prefix: &str, config: &mut Config) -> Result<()> {
+    let cx = Cx { file: file, prefix: prefix };
+    let table = match value.as_table() {
+        Some(t) => t,
+        None => return Err(cx.error("", format!("must be a table, not a {}", value.type_str()))),
+    };
+    for (key, value) in table {
+        match &key[..] {
+            "roots" => { config.roots(try!(cx.paths(key, value))); }
+            "src-dir" => { config.src_dir(try!(cx.path(key, value))); }
+            "lit-dir" => { config.lit_dir(try!(cx.path(key, value))); }
+            "include" => config.include = try!(cx.strings(key, value)),
+            "exclude" => config.exclude = try!(cx.strings(key, value)),
+            "stamp" => config.stamp = try!(cx.path(key, value)),
+            "line-endings" => {
+                config.line_endings(match try!(cx.one_of(key, value, &["preserve", "lf", "crlf"])) {
+                    "lf" => LineEndings::Lf,
+                    "crlf" => LineEndings::Crlf,
+                    _ => LineEndings::Preserve,
+                });
+            }
+            "warnings" if value.is_table() => try!(cx.warnings(value, config)),
+            "warnings" => { config.warnings(try!(cx.policy(key, value))); }
+            "playground" => try!(cx.playground(value, config)),
+            _ => return Err(cx.error(key, "is not a known setting".to_string())),
+        }
+    }
+    Ok(())
+}
+
+struct Cx<'a> {
+    file: &'a Path,
+    prefix: &'a str,
+}
+
+impl<'a> Cx<'a> {
+    fn error(&self, key: &str, message: String) -> Error {
+        let key = match (self.prefix.is_empty(), key.is_empty()) {
+            (true, _) => key.to_string(),
+            (false, true) => self.prefix.to_string(),
+            (false, false) => format!("{}.{}", self.prefix, key),
+        };
+        Error::ConfigError { file: self.file.to_path_buf(), key: key, message: message }
+    }
+
+    fn string<'v>(&self, key: &str, value: &'v toml::Value) -> Result<&'v str> {
+        value.as_str().ok_or_else(|| {
+            self.error(key, format!("must be a string, not a {}", value.type_str()))
+        })
+    }
+
+    // A string that must be one of `choices`.
+    fn one_of<'v>(&self, key: &str, value: &'v toml::Value, choices: &[&str]) -> Result<&'v str> {
+        let s = try!(self.string(key, value));
+        if choices.contains(&s) {
+            return Ok(s);
+        }
+        let quoted: Vec<String> = choices.iter().map(|c| format!("{:?}", c)).collect();
+        let (last, rest) = quoted.split_last().unwrap();
+        Err(self.error(key, format!("must be one of {} or {}, not {:?}", rest.join(", "), last, s)))
+    }
+
+    fn strings(&self, key: &str, value: &toml::Value) -> Result<Vec<String>> {
+        let array = match value.as_array() {
+            Some(a) => a,
+            None => return Err(self.error(key, format!("must be an array of strings, not a {}",
+                                                       value.type_str()))),
+        };
+        let mut result = Vec::new();
+        for (i, v) in array.iter().enumerate() {
+            result.push(try!(self.string(&format!("{}[{}]", key, i), v)).to_string());
+        }
+        Ok(result)
+    }
+
+    // A path relative to the crate root, which must stay within it.
+    fn path(&self, key: &str, value: &toml::Value) -> Result<PathBuf> {
+        let p = PathBuf::from(try!(self.string(key, value)));
+        let escapes = p.components().any(|c| match c {
+            Component::Normal(_) | Component::CurDir => false,
+            Component::ParentDir | Component::RootDir | Component::Prefix(_) => true,
+        });
+        if escapes || p.as_os_str().is_empty() {
+            return Err(self.error(key, format!(
+                "must be a relative path within the crate, not {:?}", p)));
+        }
+        Ok(p)
+    }
+
+    fn paths(&self, key: &str, value: &toml::Value) -> Result<Vec<PathBuf>> {
+        let strings = try!(self.strings(key, value));
+        let mut result = Vec::new();
+        for (i, s) in strings.into_iter().enumerate() {
+            result.push(try!(self.path(&format!("{}[{}]", key, i), &toml::Value::String(s))));
+        }
+        Ok(result)
+    }
+
+    fn policy(&self, key: &str, value: &toml::Value) -> Result<WarningPolicy> {
+        match try!(self.one_of(key, value, &["deny", "warn", "allow"])) {
+            "deny" => Ok(WarningPolicy::Deny),
+            "warn" => Ok(WarningPolicy::Warn),
+            _ => Ok(WarningPolicy::Allow),
+        }
+    }
+
+    fn warnings(&self, value: &toml::Value, config: &mut Config) -> Result<()> {
+        for (key, value) in value.as_table().into_iter().flat_map(|t| t) {
+            let full = format!("warnings.{}", key);
+            let policy = try!(self.policy(&full, value));
+            if key == "default" {
+                config.warnings(policy);
+            } else if WARNING_CODES.contains(&&key[..]) {
+                config.warning(key, policy);
+            } else {
+                return Err(self.error(&full, "is not a known kind of warning".to_string()));
+            }
+        }
+        Ok(())
+    }
+
+    fn playground(&self, value: &toml::Value, config: &mut Config) -> Result<()> {
+        let table = match value.as_table() {
+            Some(t) => t,
+            None => return Err(self.error("playground", format!(
+                "must be a table, not a {}", value.type_str()))),
+        };
+        for (key, value) in table {
+            let full = format!("playground.{}", key);
+            match &key[..] {
+                "url" => {
+                    let url = try!(self.string(&full, value));
+                    if !url.starts_with("http://") && !url.starts_with("https://") {
+                        return Err(self.error(&full, format!(
+                            "must be an http:// or https:// URL, not {:?}", url)));
+                    }
+                    config.playground.url = url.to_string();
+                }
+                "channel" => {
+                    let channel = try!(self.one_of(&full, value, &["stable", "beta", "nightly"]));
+                    config.playground.channel = channel.to_string();
+                }
+                "edition" => {
+                    let edition = try!(self.one_of(&full, value, &["2015", "2018", "2021", "2024"]));
+                    config.playground.edition = Some(edition.to_string());
+                }
+                "mode" => {
+                    let mode = try!(self.one_of(&full, value, &["debug", "release"]));
+                    config.playground.mode = Some(mode.to_string());
+                }
+                "backtrace" => match value.as_bool() {
+                    Some(b) => config.playground.backtrace = Some(b),
+                    None => return Err(self.error(&full, format!(
+                        "must be a boolean, not a {}", value.type_str()))),
+                },
+                "format" => {
+                    match try!(self.one_of(&full, value, &["playground", "compiler-explorer"])) {
+                        "playground" => config.playground.format = LinkFormat::Playground,
+                        _ => {
+                            config.playground.format = LinkFormat::CompilerExplorer;
+                            if !table.contains_key("url") {
+                                config.playground.url = COMPILER_EXPLORER_URL.to_string();
+                            }
+                        }
+                    }
+                }
+                "context" => {
+                    config.playground.context = match try!(self.one_of(&full, value,
+                                                                       &["block", "preceding"])) {
+                        "block" => LinkContext::Block,
+                        _ => LinkContext::Preceding,
+                    };
+                }
+                "compiler" => {
+                    config.playground.compiler = Some(try!(self.string(&full, value)).to_string());
+                }
+                _ => return Err(self.error(&full, "is not a known setting".to_string())),
+            }
+        }
+        Ok(())
+    }
+}
+
+// Matches `path` (relative to the crate root, with `/` separators)
+// against a glob `pattern`, where `*` matches within a single path
+// component, `**` matches across components, and `?` matches any one
+// character other than `/`.
+pub fn glob_match(pattern: &str, path: &str) -> bool {
+    fn go(p: &[u8], s: &[u8]) -> bool {
+        match p.first() {
+            None => s.is_empty(),
+            Some(&b'*') if p.get(1) == Some(&b'*') => {
+                // `**/` may also match nothing at all.
+                let rest = &p[2..];
+                if rest.first() == Some(&b'/') && go(&rest[1..], s) {
+                    return true;
+                }
+                (0..s.len() + 1).any(|i| go(rest, &s[i..]))
+            }
+            Some(&b'*') => {
+                let rest = &p[1..];
+                let stop = s.iter().position(|&c| c == b'/').unwrap_or(s.len());
+                (0..stop + 1).any(|i| go(rest, &s[i..]))
+            }
+            Some(&b'?') => !s.is_empty() && s[0] != b'/' && go(&p[1..], &s[1..]),
+            Some(&c) => !s.is_empty() && s[0] == c && go(&p[1..], &s[1..]),
+        }
+    }
+    go(pattern.as_bytes(), path.as_bytes())
+}
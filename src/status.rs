@@ -0,0 +1,164 @@
+// `tango status`: a dry run of `gather_inputs` that explains, for
+// every `.rs`/`.md` pair, what a real run would do and why, without
+// `generate_content` writing anything.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::{self, Write};
+use std::path::PathBuf;
+
+use serde_json::Value;
+
+// One directional decision made by `gather_inputs`, i.e. the outcome
+// of checking a single `Transform`.
+#[derive(Debug)]
+pub struct Planned {
+    pub source: PathBuf,
+    pub target: PathBuf,
+    pub rs_to_md: bool,
+    pub outcome: Outcome,
+    pub basis: String,
+}
+
+#[derive(Debug)]
+pub enum Outcome {
+    Needed,
+    Unneeded,
+    Orphaned,
+    Failed { kind: String, diverged: bool },
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum PairState { InSync, RsToMd, MdToRs, Diverged, Orphaned, Error }
+
+impl PairState {
+    pub fn writes(&self) -> bool {
+        match *self {
+            PairState::InSync | PairState::Error => false,
+            PairState::RsToMd | PairState::MdToRs |
+            PairState::Diverged | PairState::Orphaned => true,
+        }
+    }
+    fn name(&self) -> &'static str {
+        match *self {
+            PairState::InSync => "in-sync",
+            PairState::RsToMd => "rs->md",
+            PairState::MdToRs => "md->rs",
+            PairState::Diverged => "diverged",
+            PairState::Orphaned => "orphaned",
+            PairState::Error => "error",
+        }
+    }
+}
+
+impl fmt::Display for PairState {
+    fn fmt(&self, w: &mut fmt::Formatter) -> fmt::Result {
+        write!(w, "{}", self.name())
+    }
+}
+
+#[derive(Debug)]
+pub struct PairStatus {
+    pub rs: PathBuf,
+    pub md: PathBuf,
+    pub state: PairState,
+    pub checks: Vec<Check>,
+}
+
+// One transform that was checked for a pair: what was compared, and
+// the resulting `TransformNeed` or `check::ErrorKind`.
+#[derive(Debug)]
+pub struct Check {
+    pub source: PathBuf,
+    pub target: PathBuf,
+    pub basis: String,
+    pub decision: String,
+}
+
+#[derive(Debug)]
+pub struct Status {
+    pub pairs: Vec<PairStatus>,
+}
+
+impl Status {
+    pub fn from_plan(plan: Vec<Planned>) -> Status {
+        let mut pairs: BTreeMap<(PathBuf, PathBuf), PairStatus> = BTreeMap::new();
+        for p in plan {
+            let (rs, md) = if p.rs_to_md {
+                (p.source.clone(), p.target.clone())
+            } else {
+                (p.target.clone(), p.source.clone())
+            };
+            let state = match p.outcome {
+                Outcome::Needed if p.rs_to_md => PairState::RsToMd,
+                Outcome::Needed => PairState::MdToRs,
+                Outcome::Unneeded => PairState::InSync,
+                Outcome::Orphaned => PairState::Orphaned,
+                Outcome::Failed { diverged: true, .. } => PairState::Diverged,
+                Outcome::Failed { diverged: false, .. } => PairState::Error,
+            };
+            let decision = match p.outcome {
+                Outcome::Needed => "TransformNeed::Needed".to_string(),
+                Outcome::Unneeded => "TransformNeed::Unneeded".to_string(),
+                Outcome::Orphaned => "orphaned".to_string(),
+                Outcome::Failed { ref kind, .. } => format!("check::ErrorKind::{}", kind),
+            };
+            let entry = pairs.entry((rs.clone(), md.clone())).or_insert(PairStatus {
+                rs: rs, md: md, state: PairState::InSync, checks: Vec::new(),
+            });
+            // The two directions of a pair agree unless one of them
+            // found something to do; keep the most significant.
+            if rank(state) > rank(entry.state) {
+                entry.state = state;
+            }
+            entry.checks.push(Check {
+                source: p.source, target: p.target, basis: p.basis, decision: decision,
+            });
+        }
+        Status { pairs: pairs.into_iter().map(|(_, v)| v).collect() }
+    }
+
+    // True if a real run would write any file.
+    pub fn writes_pending(&self) -> bool {
+        self.pairs.iter().any(|p| p.state.writes())
+    }
+
+    pub fn write_human<W: Write>(&self, mut w: W) -> io::Result<()> {
+        for p in &self.pairs {
+            try!(writeln!(w, "{:>9} {} <-> {}", p.state, p.rs.display(), p.md.display()));
+            for c in &p.checks {
+                try!(writeln!(w, "          {} -> {}: {} => {}",
+                              c.source.display(), c.target.display(), c.basis, c.decision));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn write_json<W: Write>(&self, mut w: W) -> io::Result<()> {
+        let pairs: Vec<Value> = self.pairs.iter().map(|p| {
+            let checks: Vec<Value> = p.checks.iter().map(|c| json!({
+                "source": c.source.display().to_string(),
+                "target": c.target.display().to_string(),
+                "basis": c.basis,
+                "decision": c.decision,
+            })).collect();
+            json!({
+                "rs": p.rs.display().to_string(),
+                "md": p.md.display().to_string(),
+                "state": p.state.name(),
+                "checks": checks,
+            })
+        }).collect();
+        writeln!(w, "{}", json!({ "writes_pending": self.writes_pending(), "pairs": pairs }))
+    }
+}
+
+fn rank(s: PairState) -> u8 {
+    match s {
+        PairState::InSync => 0,
+        PairState::RsToMd | PairState::MdToRs => 1,
+        PairState::Orphaned => 2,
+        PairState::Diverged => 3,
+        PairState::Error => 4,
+    }
+}
@@ -1,4 +1,7 @@
-use super::{md2rs, rs2md};
+use super::{md2rs, rs2md, check_path, files, merge, settings, Config, Error, Playground};
+use super::diagnostics::SourceMap;
+use super::report::{MessageFormat, Severity};
+use std::path::Path;
 mod test_snippets;
 
 struct DifferingLines<'a> {
@@ -105,7 +108,7 @@ fn panic_if_different<'a>(name_a: &str, a: &'a str, name_b: &str, b: &'a str) {
 #[cfg(test)]
 fn core_test_md2rs(md: &str, rs: &str) {
     let mut output = Vec::new();
-    md2rs(md.as_bytes(), &mut output).unwrap();
+    md2rs(&Playground::default(), md.as_bytes(), &mut output).unwrap();
     let output = String::from_utf8(output).unwrap();
     panic_if_different("actual", &output, "expect", rs);
 }
@@ -113,7 +116,7 @@ fn core_test_md2rs(md: &str, rs: &str) {
 #[cfg(test)]
 fn warn_test_md2rs(md: &str, rs: &str) {
     let mut output = Vec::new();
-    match md2rs(md.as_bytes(), &mut output) {
+    match md2rs(&Playground::default(), md.as_bytes(), &mut output) {
         Err(super::Error::Warnings(_)) => {}
         Ok(_) => panic!("expected successful conversion with warning"),
         Err(_) => panic!("error in converion"),
@@ -125,11 +128,36 @@ fn warn_test_md2rs(md: &str, rs: &str) {
 #[cfg(test)]
 fn core_test_rs2md(rs: &str, md: &str) {
     let mut output = Vec::new();
-    rs2md(rs.as_bytes(), &mut output).unwrap();
+    rs2md(&Playground::default(), rs.as_bytes(), &mut output).unwrap();
     let output = String::from_utf8(output).unwrap();
     panic_if_different("actual", &output, "expect", md);
 }
 
+#[cfg(test)]
+fn config_error(file: &str, prefix: &str, text: &str) -> Option<(String, String)> {
+    let mut config = Config::new();
+    let result = settings::parse(text, Path::new(file))
+        .and_then(|v| settings::apply(&v, Path::new(file), prefix, &mut config));
+    match result {
+        Ok(()) => None,
+        Err(Error::ConfigError { key, message, .. }) => Some((key, message)),
+        Err(e) => panic!("unexpected error {:?}", e),
+    }
+}
+
+#[cfg(test)]
+fn core_test_merge(base_md: &str, ours_md: &str, theirs_rs: &str,
+                   merged_md: &str, conflicts: usize) {
+    let mut theirs = Vec::new();
+    rs2md(&Playground::default(), theirs_rs.as_bytes(), &mut theirs).unwrap();
+    let theirs = String::from_utf8(theirs).unwrap();
+    let base = merge::segments(base_md);
+    let merged = merge::merge3(&base, &merge::segments(ours_md), &merge::segments(&theirs),
+                               "ours", "theirs");
+    assert_eq!(merged.conflicts, conflicts);
+    panic_if_different("actual", &merged.text, "expect", merged_md);
+}
+
 #[test]
 fn test_onetext_md2rs() {
     core_test_md2rs(test_snippets::ONE_TEXT_LINE_MD,
@@ -201,7 +229,7 @@ fn test_prodigal5_md2rs() {
 
 #[test]
 fn test_prodigal5return_md2rs() {
-   core_test_rs2md(test_snippets::HARVEST5_RS, test_snippets::RETURN5_MD);
+   core_test_rs2md(test_snippets::HARVEST5_RS, test_snippets::PRODIGAL5_MD);
 }
 
 #[test]
@@ -281,3 +309,501 @@ fn test_hello12_link_to_play_rs2md() {
     core_test_rs2md(test_snippets::HELLO12_LINK_TO_PLAY_MARKDOWN_FOLLOW_RS,
                     test_snippets::HELLO12_LINK_TO_PLAY_MARKDOWN_FOLLOW_MD);
 }
+
+#[test]
+fn test_fences_md2rs() {
+    core_test_md2rs(test_snippets::FENCES_MD, test_snippets::FENCES_RS);
+}
+
+#[test]
+fn test_fences_rs2md() {
+    core_test_rs2md(test_snippets::FENCES_RS, test_snippets::FENCES_MD);
+}
+
+#[test]
+fn test_fences_close_md2rs() {
+    core_test_md2rs(test_snippets::FENCES_CLOSE_MD, test_snippets::FENCES_CLOSE_RS);
+}
+
+#[test]
+fn test_fences_close_rs2md() {
+    core_test_rs2md(test_snippets::FENCES_CLOSE_RS, test_snippets::FENCES_CLOSE_MD);
+}
+
+#[test]
+fn test_merge_prose_and_code_edits() {
+    core_test_merge(test_snippets::MERGE_BASE_MD,
+                    test_snippets::MERGE_OURS_MD,
+                    test_snippets::MERGE_THEIRS_RS,
+                    test_snippets::MERGE_RESULT_MD,
+                    0);
+}
+
+#[test]
+fn test_merge_conflicting_prose_edits() {
+    core_test_merge(test_snippets::MERGE_BASE_MD,
+                    test_snippets::MERGE_OURS_MD,
+                    test_snippets::MERGE_CONFLICT_THEIRS_RS,
+                    test_snippets::MERGE_CONFLICT_RESULT_MD,
+                    1);
+}
+
+#[test]
+fn test_config_accepts_every_setting() {
+    assert_eq!(config_error("tango.toml", "", test_snippets::CONFIG_TANGO_TOML), None);
+    assert_eq!(config_error("tango.toml", "",
+                            "[warnings]\ndefault = \"warn\"\nunused-chunk = \"allow\"\n"), None);
+}
+
+#[test]
+fn test_config_names_offending_key() {
+    let (key, message) = config_error("Cargo.toml", "package.metadata.tango",
+                                      test_snippets::CONFIG_BAD_CHANNEL_TOML).unwrap();
+    assert_eq!(key, "package.metadata.tango.playground.channel");
+    assert!(message.contains("\"weekly\""), "message: {}", message);
+
+    let (key, _) = config_error("tango.toml", "", "roots = [\"src\", \"..\"]\n").unwrap();
+    assert_eq!(key, "roots[1]");
+    let (key, _) = config_error("tango.toml", "", "stamp = \"../tango.stamp\"\n").unwrap();
+    assert_eq!(key, "stamp");
+    let (key, _) = config_error("tango.toml", "", "[playground]\nbase = 1\n").unwrap();
+    assert_eq!(key, "playground.base");
+    let (key, _) = config_error("tango.toml", "", "[warnings]\nbogus = \"allow\"\n").unwrap();
+    assert_eq!(key, "warnings.bogus");
+    let (key, _) = config_error("tango.toml", "", "[warnings]\nunused-chunk = \"never\"\n").unwrap();
+    assert_eq!(key, "warnings.unused-chunk");
+}
+
+#[test]
+fn test_config_glob_match() {
+    assert!(settings::glob_match("src/**", "src/a/b.rs"));
+    assert!(settings::glob_match("src/**/*.md", "src/b.md"));
+    assert!(settings::glob_match("src/*.rs", "src/lib.rs"));
+    assert!(!settings::glob_match("src/*.rs", "src/a/lib.rs"));
+    assert!(settings::glob_match("tests/?.rs", "tests/a.rs"));
+    assert!(!settings::glob_match("tests/?.rs", "tests/ab.rs"));
+}
+
+#[test]
+fn test_playground_url_md2rs() {
+    let pg = Playground { url: "https://play.example.org/".to_string(),
+                          channel: "stable".to_string(),
+                          ..Playground::default() };
+    let mut output = Vec::new();
+    md2rs(&pg, test_snippets::HELLO13_LINK_TO_SELF_HOSTED_PLAY_MD.as_bytes(), &mut output).unwrap();
+    let output = String::from_utf8(output).unwrap();
+    panic_if_different("actual", &output, "expect",
+                       test_snippets::HELLO13_LINK_TO_SELF_HOSTED_PLAY_RS);
+    let mut output = Vec::new();
+    rs2md(&pg, test_snippets::HELLO13_LINK_TO_SELF_HOSTED_PLAY_RS.as_bytes(), &mut output).unwrap();
+    let output = String::from_utf8(output).unwrap();
+    panic_if_different("actual", &output, "expect",
+                       test_snippets::HELLO13_LINK_TO_SELF_HOSTED_PLAY_MD);
+}
+
+#[test]
+fn test_info_strings_md2rs() {
+    core_test_md2rs(test_snippets::INFO_STRINGS_MD, test_snippets::INFO_STRINGS_RS);
+}
+
+#[test]
+fn test_info_strings_rs2md() {
+    core_test_rs2md(test_snippets::INFO_STRINGS_RS, test_snippets::INFO_STRINGS_MD);
+}
+
+#[test]
+fn test_info_edition_link_md2rs() {
+    core_test_md2rs(test_snippets::INFO_EDITION_LINK_MD, test_snippets::INFO_EDITION_LINK_RS);
+}
+
+#[test]
+fn test_info_edition_link_rs2md() {
+    core_test_rs2md(test_snippets::INFO_EDITION_LINK_RS, test_snippets::INFO_EDITION_LINK_MD);
+}
+
+#[test]
+fn test_info_link_options_md2rs() {
+    core_test_md2rs(test_snippets::INFO_LINK_OPTIONS_MD, test_snippets::INFO_LINK_OPTIONS_RS);
+}
+
+#[test]
+fn test_info_link_options_rs2md() {
+    core_test_rs2md(test_snippets::INFO_LINK_OPTIONS_RS, test_snippets::INFO_LINK_OPTIONS_MD);
+}
+
+#[test]
+fn test_compiler_explorer_url() {
+    let mut config = Config::new();
+    let value = settings::parse("[playground]\nformat = \"compiler-explorer\"\n\
+                                 edition = \"2021\"\nmode = \"release\"\n",
+                                Path::new("tango.toml")).unwrap();
+    settings::apply(&value, Path::new("tango.toml"), "", &mut config).unwrap();
+    let url = config.playground.url(&Default::default(), "fn main() { }\n");
+    assert_eq!(url, "https://godbolt.org/clientstate/eyJzZXNzaW9ucyI6W3siaWQiOjEsImxhbmd1YWdlIjoi\
+                     cnVzdCIsInNvdXJjZSI6ImZuIG1haW4oKSB7IH0iLCJjb21waWxlcnMiOlt7ImlkIjoibmlnaHRs\
+                     eSIsIm9wdGlvbnMiOiItLWVkaXRpb24gMjAyMSAtQyBvcHQtbGV2ZWw9MyJ9XX1dfQ");
+}
+
+#[test]
+fn test_context_links_md2rs() {
+    core_test_md2rs(test_snippets::CONTEXT_LINKS_MD, test_snippets::CONTEXT_LINKS_RS);
+}
+
+#[test]
+fn test_context_links_rs2md() {
+    core_test_rs2md(test_snippets::CONTEXT_LINKS_RS, test_snippets::CONTEXT_LINKS_MD);
+}
+
+#[test]
+fn test_hidden_lines_md2rs() {
+    core_test_md2rs(test_snippets::HIDDEN_LINES_MD, test_snippets::HIDDEN_LINES_RS);
+}
+
+#[test]
+fn test_hidden_lines_rs2md() {
+    core_test_rs2md(test_snippets::HIDDEN_LINES_RS, test_snippets::HIDDEN_LINES_MD);
+}
+
+#[test]
+fn test_chunks_md2rs() {
+    warn_test_md2rs(test_snippets::CHUNKS_MD, test_snippets::CHUNKS_RS);
+}
+
+#[test]
+fn test_chunks_rs2md() {
+    core_test_rs2md(test_snippets::CHUNKS_RS, test_snippets::CHUNKS_MD);
+}
+
+#[test]
+fn test_files_split_and_join() {
+    let mut output = Vec::new();
+    md2rs(&Playground::default(), test_snippets::FILES_MD.as_bytes(), &mut output).unwrap();
+    let output = String::from_utf8(output).unwrap();
+    let (primary, produced) = files::split(&output, "src/lib.md").unwrap();
+    panic_if_different("actual", &primary, "expect", test_snippets::FILES_RS);
+    assert_eq!(produced.iter().map(|p| &p.0[..]).collect::<Vec<_>>(),
+               vec!["parser/lexer.rs", "parser.rs"]);
+    panic_if_different("actual", &produced[0].1, "expect", test_snippets::FILES_LEXER_RS);
+    panic_if_different("actual", &produced[1].1, "expect", test_snippets::FILES_PARSER_RS);
+
+    // An edit to a produced file goes back to its own block.
+    let joined = files::join(test_snippets::FILES_RS, |file| Ok(match file {
+        "parser/lexer.rs" => test_snippets::FILES_LEXER_RS.replace("helper", "edited"),
+        _ => test_snippets::FILES_PARSER_RS.to_string(),
+    })).unwrap();
+    let mut md = Vec::new();
+    rs2md(&Playground::default(), joined.as_bytes(), &mut md).unwrap();
+    let md = String::from_utf8(md).unwrap();
+    panic_if_different("actual", &md, "expect",
+                       &test_snippets::FILES_MD.replace("helper", "edited"));
+}
+
+#[test]
+fn test_files_assets() {
+    let mut output = Vec::new();
+    md2rs(&Playground::default(), test_snippets::ASSETS_MD.as_bytes(), &mut output).unwrap();
+    let output = String::from_utf8(output).unwrap();
+    let (primary, produced) = files::split(&output, "src/lib.md").unwrap();
+    panic_if_different("actual", &primary, "expect", test_snippets::ASSETS_RS);
+    assert_eq!(produced.len(), 1);
+    assert_eq!(produced[0].0, "../migrations/001_init.sql");
+    panic_if_different("actual", &produced[0].1, "expect", test_snippets::ASSETS_SQL);
+
+    let edited = test_snippets::ASSETS_SQL.replace("id INTEGER", "id BIGINT");
+    let joined = files::join(test_snippets::ASSETS_RS, |_| Ok(edited.clone())).unwrap();
+    let mut md = Vec::new();
+    rs2md(&Playground::default(), joined.as_bytes(), &mut md).unwrap();
+    let md = String::from_utf8(md).unwrap();
+    panic_if_different("actual", &md, "expect",
+                       &test_snippets::ASSETS_MD.replace("id INTEGER", "id BIGINT"));
+
+    // Unlike a `.rs` file, an asset holds the code of just one block.
+    let twice = format!("{}{}", output, "//@@file ../migrations/001_init.sql\nDROP TABLE users;\n//@@>>\n");
+    match files::split(&twice, "src/lib.md") {
+        Err((ref f, _)) if f == "../migrations/001_init.sql" => {}
+        other => panic!("second block accepted: {:?}", other),
+    }
+}
+
+#[test]
+fn test_files_reject_escaping_paths() {
+    for file in &["../lib.rs", "/etc/passwd.rs", "parser/../../x.rs", "./x.rs", "x-y.rs", "/etc/passwd"] {
+        let text = format!("//@@file {}\nfn f() {{}}\n//@@>>\n", file);
+        match files::split(&text, "src/lib.md") {
+            Err((ref f, _)) if f == file => {}
+            other => panic!("{} accepted: {:?}", file, other),
+        }
+    }
+}
+
+#[test]
+fn test_source_map() {
+    let config = Config::new();
+    let mut rs = Vec::new();
+    md2rs(&Playground::default(), test_snippets::ORIGINS_MD.as_bytes(), &mut rs).unwrap();
+    let rs = String::from_utf8(rs).unwrap();
+    let map = SourceMap::new(&config, Path::new("src/lib.md"), "src/lib.md",
+                             test_snippets::ORIGINS_MD, None, &rs).unwrap();
+    let rs_line = |code: &str| rs.lines().position(|l| l.contains(code)).unwrap() + 1;
+
+    // A hidden line inside a blockquote: `fn` is past the `> # `.
+    assert_eq!(map.locate(rs_line("fn hidden"), 1), Some((4, 5)));
+    assert_eq!(map.locate(rs_line("pub fn f"), 8), Some((5, 10)));
+    // Code from a chunk, indented where it is used.
+    assert_eq!(map.locate(rs_line("let y"), 9), Some((14, 5)));
+    assert_eq!(map.locate(rs_line("let y") + 1, 1), Some((15, 1)));
+    assert_eq!(map.locate(rs_line("y + 1"), 5), Some((16, 1)));
+    assert_eq!(map.offset(14, 5), test_snippets::ORIGINS_MD.find("y = 2").unwrap());
+    // Prose and markers come from no line of code.
+    assert_eq!(map.locate(1, 1), None);
+    assert_eq!(map.locate(rs_line("//@@<<body>>"), 1), None);
+
+    // A `.rs` that has since been edited is not mapped at all.
+    assert!(SourceMap::new(&config, Path::new("src/lib.md"), "src/lib.md",
+                           test_snippets::ORIGINS_MD, None, &rs.replace("y + 1", "y")).is_none());
+}
+
+#[test]
+fn test_source_map_with_bom() {
+    // The `.rs` is laid out like the `.md`, but the map still counts
+    // lines and columns as if neither had a BOM or `\r\n` endings.
+    let config = Config::new();
+    let md = format!("\u{feff}{}", test_snippets::ORIGINS_MD.replace('\n', "\r\n"));
+    let mut rs = Vec::new();
+    md2rs(&Playground::default(), md.as_bytes(), &mut rs).unwrap();
+    let rs = String::from_utf8(rs).unwrap();
+    assert!(rs.starts_with('\u{feff}'));
+    let map = SourceMap::new(&config, Path::new("src/lib.md"), "src/lib.md", &md, None, &rs).unwrap();
+    let rs_line = |code: &str| rs.lines().position(|l| l.contains(code)).unwrap() + 1;
+    assert_eq!(map.locate(rs_line("let y"), 9), Some((14, 5)));
+    assert_eq!(map.offset(14, 5), md.find("y = 2").unwrap());
+    assert_eq!(map.offset(1, 1), '\u{feff}'.len_utf8());
+}
+
+#[test]
+fn test_legacy_url_encoding() {
+    let pg = Playground::default();
+    let md = |link: &str| format!("```rust\nfn main() {{ 1 & 2 + 3 % 4; }}\n```\n[x]: {}\n", link);
+    let warning = |md: &str| match md2rs(&pg, md.as_bytes(), &mut Vec::new()) {
+        Err(Error::Warnings(mut ws)) => ws.remove(0).warning,
+        other => panic!("expected warnings, not {:?}", other),
+    };
+    let expect = match warning(&md("https://play.rust-lang.org/?code=stale&version=nightly")) {
+        super::Warning::EncodedUrlMismatch { expect, .. } => expect,
+        w => panic!("unexpected {:?}", w),
+    };
+    let legacy = pg.legacy(&expect);
+    assert_eq!(legacy, "https://play.rust-lang.org/?code=fn%20main()%20%7B%201%20&%202%20+%203%20%%204%3B%20%7D\
+                        &version=nightly");
+    assert_eq!(warning(&md(&legacy)).code(), "legacy-url-encoding");
+    assert!(md2rs(&pg, md(&expect).as_bytes(), &mut Vec::new()).is_ok());
+    assert_eq!(Config::new().warning_kinds.get("legacy-url-encoding"), Some(&super::WarningPolicy::Warn));
+}
+
+#[test]
+fn test_warnings_are_located() {
+    let warnings = |md: &str| match md2rs(&Playground::default(), md.as_bytes(), &mut Vec::new()) {
+        Err(Error::Warnings(ws)) => ws,
+        other => panic!("expected warnings, not {:?}", other),
+    };
+
+    let ws = warnings(test_snippets::CHUNKS_MD);
+    assert_eq!(ws.len(), 1);
+    assert_eq!(ws[0].warning.code(), "unused-chunk");
+    assert_eq!((ws[0].line, ws[0].columns.clone()), (35, 1..11));
+    assert_eq!(ws[0].severity, Severity::Warning);
+
+    let md = format!("{}\n```rust\nfn f() {{\n    <<missing>>\n}}\n```\n", test_snippets::CHUNKS_MD);
+    let ws = warnings(&md);
+    assert_eq!(ws.iter().map(|w| (w.warning.code(), w.line)).collect::<Vec<_>>(),
+               vec![("undefined-chunk", 41), ("unused-chunk", 35)]);
+    assert_eq!(ws[0].columns, 5..16);
+
+    let mut ws = warnings(test_snippets::HELLO9_LINK_TO_PLAY_MD_WARN);
+    assert_eq!(ws[0].warning.code(), "encoded-url-mismatch");
+    assert_eq!((ws[0].line, ws[0].columns.clone()), (7, 11..74));
+
+    ws[0].file = Path::new("src/hello.md").to_path_buf();
+    let mut human = Vec::new();
+    ws[0].write(MessageFormat::Human, &mut human).unwrap();
+    assert!(String::from_utf8(human).unwrap()
+            .starts_with("warning[encoded-url-mismatch]: mismatch between encoded url"));
+    let mut json = Vec::new();
+    ws[0].write(MessageFormat::Json, &mut json).unwrap();
+    let json = String::from_utf8(json).unwrap();
+    assert!(json.starts_with("{\"file\":\"src/hello.md\",\"line\":7,\"column_start\":11,\
+                              \"column_end\":74,\"severity\":\"warning\",\
+                              \"code\":\"encoded-url-mismatch\",\"message\":"), "{}", json);
+    assert!(json.ends_with("}\n") && json.lines().count() == 1);
+
+    ws[0].file = Path::new("src/\"quoted\"\\hello.md").to_path_buf();
+    let mut json = Vec::new();
+    ws[0].write(MessageFormat::Json, &mut json).unwrap();
+    let value: ::serde_json::Value = ::serde_json::from_slice(&json).unwrap();
+    assert_eq!(value["file"], "src/\"quoted\"\\hello.md");
+}
+
+#[test]
+fn test_converters_never_panic() {
+    // A deterministic stand-in for `fuzz/`: documents pieced together
+    // from fragments that the converters treat specially.
+    const FRAGMENTS: &'static [&'static str] = &[
+        "```rust", "```", "~~~", "~~~rust", "````", "> ```rust", "1. ```rs", "- ```rust",
+        "  - > ```rust", "  ```", "```{.rust}", "```{.rust file=x.rs}", "```toml file=a.toml",
+        "```rust,ignore", "```rust edition2018", "```rust edition=", "```rust context=preceding",
+        "```rust chunks=a", "```rust chunks=\"\"", "```rust file=\u{e9}.rs", "# x", "#",
+        "<<a>>=", "<<a>>", "<<a>>+=", "<<\u{e9}>>", "[x]: https://play.rust-lang.org/?code=",
+        "[\u{e9}]: https://play.rust-lang.org/?code=\u{e9}", "[", "]: ", "//@ ", "//@",
+        "//@ \u{e9}", "//@@", "//@@@", "//@@@ x", "//@@fence", "//@@fence ```rust",
+        "//@@fence \u{e9}", "//@@close", "//@@close ```", "//@@close \u{e9}", "//@@file",
+        "//@@file a.rs", "//@@<<a>>=", "//@@<<a>>+=", "//@@<<a>>", "//@@>>", "//@@#", "//@@{",
+        "", "   ", "\t", "\r", "\u{e9}", "\u{0}@3", ">", "> ", ">>", "* ", "\"", "<<", ">>=",
+        "fn main() {}",
+    ];
+    let mut seed: u64 = 1;
+    let mut next = |n: usize| {
+        seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+        ((seed >> 33) as usize) % n
+    };
+    for _ in 0..5000 {
+        let mut text = String::new();
+        for _ in 0..next(30) {
+            text.push_str(FRAGMENTS[next(FRAGMENTS.len())]);
+            if next(4) != 0 {
+                text.push('\n');
+            }
+        }
+        let _ = md2rs(&Playground::default(), text.as_bytes(), &mut Vec::new());
+        let _ = rs2md(&Playground::default(), text.as_bytes(), &mut Vec::new());
+    }
+}
+
+#[test]
+fn test_check_path_errors() {
+    assert!(check_path(Path::new("src/lib.rs"), "rs", Path::new("src")).is_ok());
+    match check_path(Path::new("src/lib.txt"), "rs", Path::new("src")) {
+        Err(Error::InvalidPath { ref message, .. }) => assert_eq!(message, "expected a `.rs` file"),
+        r => panic!("unexpected {:?}", r),
+    }
+    match check_path(Path::new("tests/lib.rs"), "rs", Path::new("src")) {
+        Err(Error::InvalidPath { ref message, .. }) => assert_eq!(message, "not under `src`"),
+        r => panic!("unexpected {:?}", r),
+    }
+}
+
+#[test]
+fn test_lossless_md2rs() {
+    core_test_md2rs(test_snippets::LOSSLESS_MD, test_snippets::LOSSLESS_RS);
+}
+
+#[test]
+fn test_lossless_rs2md() {
+    core_test_rs2md(test_snippets::LOSSLESS_RS, test_snippets::LOSSLESS_MD);
+}
+
+#[test]
+fn test_lossy_round_trip_is_reported() {
+    let mut output = Vec::new();
+    let ws = match rs2md(&Playground::default(), test_snippets::LOSSY_RS.as_bytes(), &mut output) {
+        Err(Error::Warnings(ws)) => ws,
+        r => panic!("unexpected {:?}", r),
+    };
+    assert_eq!(String::from_utf8(output).unwrap(), "Prose.\n\nMore prose.\n```rust\nfn main() {}\n```\n");
+    assert_eq!(ws.len(), 1);
+    assert_eq!(ws[0].line, 2);
+    assert_eq!(ws[0].warning.to_string(),
+               "not preserved by converting to .md and back: comes back as \"//@\"");
+}
+
+#[test]
+fn test_lexer() {
+    use super::lexer::Lexer;
+    assert_eq!(Lexer::Code.after("let s = \"//@ a"), Lexer::Str);
+    assert_eq!(Lexer::Str.after("b\\\" c\";"), Lexer::Code);
+    assert_eq!(Lexer::Code.after("let s = r##\"a\"#"), Lexer::RawStr(2));
+    assert_eq!(Lexer::RawStr(2).after("\"##; // \""), Lexer::Code);
+    assert_eq!(Lexer::Code.after("let c = '\"'; fn f<'a>(x: &'a str) {} // /*"), Lexer::Code);
+    assert_eq!(Lexer::Code.after("let c = '\\''; /* /* */"), Lexer::Comment(1));
+    assert_eq!(Lexer::Comment(1).after("*/ let r#type = br\"\\"), Lexer::RawStr(0));
+}
+
+#[test]
+fn test_escaped_md2rs() {
+    core_test_md2rs(test_snippets::ESCAPED_MD, test_snippets::ESCAPED_RS);
+}
+
+#[test]
+fn test_escaped_rs2md() {
+    core_test_rs2md(test_snippets::ESCAPED_RS, test_snippets::ESCAPED_MD);
+}
+
+#[test]
+fn test_snippets_md2rs() {
+    core_test_md2rs(test_snippets::SNIPPETS_MD, test_snippets::SNIPPETS_RS);
+}
+
+#[test]
+fn test_snippets_rs2md() {
+    core_test_rs2md(test_snippets::SNIPPETS_RS, test_snippets::SNIPPETS_MD);
+}
+
+#[test]
+fn test_nested_md2rs() {
+    core_test_md2rs(test_snippets::NESTED_MD, test_snippets::NESTED_RS);
+}
+
+#[test]
+fn test_nested_rs2md() {
+    core_test_rs2md(test_snippets::NESTED_RS, test_snippets::NESTED_MD);
+}
+
+#[test]
+fn test_indent_markers() {
+    use super::md2rs::{indent_marker, marked_indent};
+    assert_eq!(indent_marker(""), None);
+    assert_eq!(indent_marker(" \t"), None);
+    for indent in &["    ", "\t\t"] {
+        assert_eq!(marked_indent(&indent_marker(indent).unwrap()), Some(indent.to_string()));
+    }
+    assert_eq!(marked_indent("<!-- tango: indent 0 -->"), None);
+    assert_eq!(marked_indent("<!-- tango: indent -->"), None);
+}
+
+#[test]
+fn test_line_endings_and_bom_are_preserved() {
+    let md = "\u{feff}Prose.\r\n```rust\r\nfn main() {}\r\n```\r\n";
+    let mut rs = Vec::new();
+    md2rs(&Playground::default(), md.as_bytes(), &mut rs).unwrap();
+    assert_eq!(String::from_utf8(rs.clone()).unwrap(), "\u{feff}//@ Prose.\r\nfn main() {}\r\n");
+    let mut back = Vec::new();
+    rs2md(&Playground::default(), &rs[..], &mut back).unwrap();
+    assert_eq!(String::from_utf8(back).unwrap(), md);
+}
+
+#[test]
+fn test_mixed_line_endings_are_reported() {
+    let mut output = Vec::new();
+    let ws = match md2rs(&Playground::default(), "a\r\nb\nc\r\n".as_bytes(), &mut output) {
+        Err(Error::Warnings(ws)) => ws,
+        r => panic!("unexpected {:?}", r),
+    };
+    assert_eq!(String::from_utf8(output).unwrap(), "//@ a\r\n//@ b\r\n//@ c\r\n");
+    assert_eq!(ws.len(), 1);
+    assert_eq!(ws[0].line, 2);
+}
+
+#[test]
+fn test_invalid_encoding_is_located() {
+    let mut output = Vec::new();
+    match md2rs(&Playground::default(), &b"Prose.\n\nab\xffc\n"[..], &mut output) {
+        Err(Error::InvalidInput { line, ref message, .. }) => {
+            assert_eq!(line, 3);
+            assert_eq!(message, "is not valid UTF-8 (byte 0xff at column 3)");
+        }
+        r => panic!("unexpected {:?}", r),
+    }
+    match rs2md(&Playground::default(), &b"\xff\xfe/\x00/\x00"[..], &mut output) {
+        Err(Error::InvalidInput { line: 1, ref message, .. }) => assert!(message.contains("UTF-16")),
+        r => panic!("unexpected {:?}", r),
+    }
+}
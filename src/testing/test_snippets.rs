@@ -106,15 +106,6 @@ let code_fragment;
 //@ but note the tab
 "#;
 
-pub const RETURN5_MD: &'static str = r#"# Hello World
-```rust
-let code_fragment;
-```
-
-This looks like it has a nice para break before its starts,
-but note the tab
-"#;
-
 pub const HELLO6_METADATA_MD: &'static str = r#"# Hello World
 
 ```{.rust .css_class_metadata }
@@ -221,3 +212,660 @@ Hi
 
 //@ Content
 "#;
+
+pub const HELLO13_LINK_TO_SELF_HOSTED_PLAY_MD: &'static str = r#"# Hello World
+
+```rust
+Hi
+```
+[hello13]: https://play.example.org/?code=Hi&version=stable
+"#;
+
+pub const HELLO13_LINK_TO_SELF_HOSTED_PLAY_RS: &'static str = r#"//@ # Hello World
+
+Hi
+//@@@ hello13
+"#;
+
+pub const FENCES_MD: &'static str = r#"# Fences
+
+~~~rust
+fn tilde() {}
+~~~
+
+  ```rust
+  fn indented() {
+      body();
+  }
+  ```
+
+`````rust
+fn longer() {}
+`````
+
+- A list item:
+
+  ```rust
+  fn listed() {}
+
+  fn also_listed() {}
+  ```
+
+> Quoted:
+>
+> ```rust
+> fn quoted() {}
+> ```
+
+````markdown
+```rust
+fn not_rust_code() {}
+```
+````
+
+```rust
+fn plain() {}
+```
+"#;
+
+pub const FENCES_RS: &'static str = r#"//@ # Fences
+
+//@@fence ~~~rust
+fn tilde() {}
+
+//@@fence   ```rust
+fn indented() {
+    body();
+}
+
+//@@fence `````rust
+fn longer() {}
+
+//@ - A list item:
+
+//@@fence   ```rust
+fn listed() {}
+
+fn also_listed() {}
+
+//@ > Quoted:
+//@ >
+//@@fence > ```rust
+fn quoted() {}
+
+//@ ````markdown
+//@ ```rust
+//@ fn not_rust_code() {}
+//@ ```
+//@ ````
+
+fn plain() {}
+"#;
+
+pub const FENCES_CLOSE_MD: &'static str = r#"> ```rust
+> fn unclosed() {}
+
+After.
+
+~~~rust
+fn closed_long() {}
+~~~~~
+"#;
+
+pub const FENCES_CLOSE_RS: &'static str = r#"//@@fence > ```rust
+//@@close
+fn unclosed() {}
+
+//@ After.
+
+//@@fence ~~~rust
+//@@close ~~~~~
+fn closed_long() {}
+"#;
+
+pub const CONFIG_TANGO_TOML: &'static str = r#"roots = ["src", "tests", "build.rs"]
+src-dir = "src"
+lit-dir = "lit"
+include = ["src/**", "tests/**", "build.*"]
+exclude = ["src/generated/**"]
+stamp = "target/tango.stamp"
+line-endings = "crlf"
+warnings = "warn"
+
+[playground]
+url = "https://play.example.org/"
+channel = "stable"
+edition = "2021"
+mode = "release"
+backtrace = false
+format = "playground"
+context = "block"
+compiler = "r1800"
+"#;
+
+pub const CONFIG_BAD_CHANNEL_TOML: &'static str = r#"warnings = "allow"
+
+[playground]
+channel = "weekly"
+"#;
+
+pub const MERGE_BASE_MD: &'static str = r#"# Hello World
+This is the introduction.
+
+```rust
+fn main() { hello() }
+```
+
+And some closing words.
+"#;
+
+pub const MERGE_OURS_MD: &'static str = r#"# Hello World
+This is the introduction, revised in the Markdown.
+
+```rust
+fn main() { hello() }
+```
+
+And some closing words.
+"#;
+
+pub const MERGE_THEIRS_RS: &'static str = r#"//@ # Hello World
+//@ This is the introduction.
+
+fn main() { hello(); goodbye() }
+
+//@ And some closing words.
+"#;
+
+pub const MERGE_RESULT_MD: &'static str = r#"# Hello World
+This is the introduction, revised in the Markdown.
+
+```rust
+fn main() { hello(); goodbye() }
+```
+
+And some closing words.
+"#;
+
+pub const MERGE_CONFLICT_THEIRS_RS: &'static str = r#"//@ # Hello World
+//@ This is the introduction, revised in the IDE.
+
+fn main() { hello() }
+
+//@ And some closing words.
+"#;
+
+pub const MERGE_CONFLICT_RESULT_MD: &'static str = r#"# Hello World
+<<<<<<< ours
+This is the introduction, revised in the Markdown.
+
+=======
+This is the introduction, revised in the IDE.
+
+>>>>>>> theirs
+```rust
+fn main() { hello() }
+```
+
+And some closing words.
+"#;
+
+pub const INFO_STRINGS_MD: &'static str = r#"Some prose.
+
+```rust,no_run
+fn serve() { loop {} }
+```
+
+This one is only an illustration:
+
+```rust,ignore
+let x = ...;
+```
+
+```rust,compile_fail
+let s: String = 3;
+```
+
+```rs
+fn short() {}
+```
+"#;
+
+pub const INFO_STRINGS_RS: &'static str = r#"//@ Some prose.
+
+//@@,no_run
+fn serve() { loop {} }
+
+//@ This one is only an illustration:
+//@
+//@ ```rust,ignore
+//@ let x = ...;
+//@ ```
+//@
+//@ ```rust,compile_fail
+//@ let s: String = 3;
+//@ ```
+
+//@@fence ```rs
+fn short() {}
+"#;
+
+pub const INFO_EDITION_LINK_MD: &'static str = r#"```rust,edition2018
+Hi
+```
+[edition]: https://play.rust-lang.org/?code=Hi&version=nightly&edition=2018
+"#;
+
+pub const INFO_EDITION_LINK_RS: &'static str = r#"//@@,edition2018
+Hi
+//@@@ edition
+"#;
+
+pub const INFO_LINK_OPTIONS_MD: &'static str = r#"```rust,edition2021 channel=beta mode=release backtrace=true
+a && b
+```
+[options]: https://play.rust-lang.org/?code=a%20%26%26%20b&version=beta&edition=2021&mode=release&backtrace=1
+"#;
+
+pub const INFO_LINK_OPTIONS_RS: &'static str = r#"//@@,edition2021 channel=beta mode=release backtrace=true
+a && b
+//@@@ options
+"#;
+
+pub const CONTEXT_LINKS_MD: &'static str = r#"A point, and its norm:
+
+```rust
+<<point>>=
+struct Point { x: i32 }
+```
+
+```rust
+<<point>>
+fn norm(p: &Point) -> i32 { p.x }
+```
+
+Everything so far goes into the link:
+
+```rust,context=preceding
+let p = Point { x: 1 };
+assert_eq!(norm(&p), 1);
+```
+[norm]: https://play.rust-lang.org/?code=fn%20main()%20%7B%0Astruct%20Point%20%7B%20x%3A%20i32%20%7D%0Afn%20norm(p%3A%20%26Point)%20-%3E%20i32%20%7B%20p.x%20%7D%0Alet%20p%20%3D%20Point%20%7B%20x%3A%201%20%7D%3B%0Aassert_eq!(norm(%26p),%201)%3B%0A%7D&version=nightly
+
+And just the one chunk into this one:
+
+```rust chunks=point
+let p = Point { x: 2 };
+```
+[point]: https://play.rust-lang.org/?code=fn%20main()%20%7B%0Astruct%20Point%20%7B%20x%3A%20i32%20%7D%0Alet%20p%20%3D%20Point%20%7B%20x%3A%202%20%7D%3B%0A%7D&version=nightly
+"#;
+
+pub const CONTEXT_LINKS_RS: &'static str = r#"//@ A point, and its norm:
+
+//@@<<point>>=
+
+//@@<<point>>
+struct Point { x: i32 }
+//@@>>
+fn norm(p: &Point) -> i32 { p.x }
+
+//@ Everything so far goes into the link:
+
+//@@,context=preceding
+let p = Point { x: 1 };
+assert_eq!(norm(&p), 1);
+//@@@ norm
+
+//@ And just the one chunk into this one:
+
+//@@ chunks=point
+let p = Point { x: 2 };
+//@@@ point
+"#;
+
+pub const HIDDEN_LINES_MD: &'static str = r#"Only the call is shown:
+
+```rust
+# use std::io;
+# fn main() {
+##![allow(unused)]
+let _ = io::stdout();
+#
+# }
+```
+[hidden]: https://play.rust-lang.org/?code=use%20std%3A%3Aio%3B%0Afn%20main()%20%7B%0A%23!%5Ballow(unused)%5D%0Alet%20_%20%3D%20io%3A%3Astdout()%3B%0A%0A%7D&version=nightly
+"#;
+
+pub const HIDDEN_LINES_RS: &'static str = r#"//@ Only the call is shown:
+
+//@@#
+use std::io;
+//@@#
+fn main() {
+//@@##
+#![allow(unused)]
+let _ = io::stdout();
+//@@#
+
+//@@#
+}
+//@@@ hidden
+"#;
+
+pub const CHUNKS_MD: &'static str = r#"# Chunks
+
+The program reads, then writes:
+
+```rust
+fn main() {
+    <<read the input>>
+    <<write the output>>
+}
+```
+
+Reading is simple:
+
+```rust
+<<read the input>>=
+let input = read();
+```
+
+Writing takes two steps.
+
+```rust
+<<write the output>>=
+let output = render(&input);
+
+```
+
+```rust
+<<write the output>>+=
+write(&output);
+```
+
+Nobody asked for this:
+
+```rust
+<<spare>>=
+fn spare() {}
+```
+"#;
+
+pub const CHUNKS_RS: &'static str = r#"//@ # Chunks
+//@
+//@ The program reads, then writes:
+
+fn main() {
+    //@@<<read the input>>
+    let input = read();
+    //@@>>
+    //@@<<write the output>>
+    let output = render(&input);
+
+    //@@+
+    write(&output);
+    //@@>>
+}
+
+//@ Reading is simple:
+
+//@@<<read the input>>=
+
+//@ Writing takes two steps.
+
+//@@<<write the output>>=
+
+//@@<<write the output>>+=
+
+//@ Nobody asked for this:
+
+//@@<<spare>>=
+fn spare() {}
+//@@>>
+"#;
+
+pub const FILES_MD: &'static str = r#"# Parser
+
+```rust
+pub use parser::parse;
+```
+
+The lexer:
+
+```{.rust file="parser/lexer.rs"}
+pub fn lex() {}
+```
+
+The parser module itself:
+
+```rust,file=parser.rs
+pub fn parse() {}
+```
+
+More lexing:
+
+```{.rust file="parser/lexer.rs"}
+fn helper() {}
+```
+"#;
+
+pub const FILES_RS: &'static str = r#"//@ # Parser
+
+pub use parser::parse;
+
+//@ The lexer:
+
+//@@ { file="parser/lexer.rs"}
+//@@file parser/lexer.rs
+
+//@ The parser module itself:
+
+//@@,file=parser.rs
+//@@file parser.rs
+
+//@ More lexing:
+
+//@@ { file="parser/lexer.rs"}
+//@@file parser/lexer.rs
+//@@mods
+#[path = "parser.rs"]
+mod parser;
+//@@>>
+"#;
+
+pub const FILES_LEXER_RS: &'static str = r#"//@@tangled from src/lib.md
+pub fn lex() {}
+//@@+
+fn helper() {}
+"#;
+
+pub const FILES_PARSER_RS: &'static str = r#"//@@tangled from src/lib.md
+pub fn parse() {}
+//@@mods
+#[path = "parser/lexer.rs"]
+mod lexer;
+//@@>>
+"#;
+
+pub const ASSETS_MD: &'static str = r#"# Storage
+
+The schema:
+
+```sql file="../migrations/001_init.sql"
+CREATE TABLE users (
+    id INTEGER PRIMARY KEY
+);
+```
+
+And the code that uses it:
+
+```rust
+pub const SCHEMA: &'static str = include_str!("../migrations/001_init.sql");
+```
+"#;
+
+pub const ASSETS_RS: &'static str = r#"//@ # Storage
+//@
+//@ The schema:
+
+//@@fence ```sql file="../migrations/001_init.sql"
+//@@file ../migrations/001_init.sql
+
+//@ And the code that uses it:
+
+pub const SCHEMA: &'static str = include_str!("../migrations/001_init.sql");
+"#;
+
+pub const ASSETS_SQL: &'static str = r#"CREATE TABLE users (
+    id INTEGER PRIMARY KEY
+);
+"#;
+
+pub const ORIGINS_MD: &'static str = r#"# Origins
+
+> ```rust
+> # fn hidden() {}
+> pub fn f() -> u32 {
+>     <<body>>
+> }
+> ```
+
+The body:
+
+```rust
+<<body>>=
+let y = 2;
+
+y + 1
+```
+"#;
+
+pub const LOSSLESS_MD: &'static str = "# Lossless\n\
+\t\n\
+Trailing whitespace stays.  \n\
+\n\
+\n\
+```rust\n\
+\n\
+fn main() {\n\
+    # let hidden = 1;\n\
+   \n\
+}\n\
+\n\
+```\n\
+```rust\n\
+```\n\
+\n\
+```rust \n\
+let adjacent = ();\n\
+```\n\
+\n\
+\n\
+No final newline.";
+
+pub const LOSSLESS_RS: &'static str = "//@ # Lossless\n\
+//@ \t\n\
+//@ Trailing whitespace stays.  \n\
+\n\
+\n\
+//@@start\n\
+\n\
+fn main() {\n\
+//@@#\n\
+    let hidden = 1;\n\
+   \n\
+}\n\
+\n\
+//@@end\n\
+//@@start\n\
+\n\
+//@@ \n\
+//@@start\n\
+let adjacent = ();\n\
+\n\
+\n\
+//@ No final newline.";
+
+pub const LOSSY_RS: &'static str = r#"//@ Prose.
+
+//@ More prose.
+fn main() {}
+"#;
+
+pub const ESCAPED_MD: &'static str = r#"A block with comments that look like prose.
+
+```rust
+//@ Not prose, but a comment.
+fn main() {
+    let s = "
+//@ Not prose either, but part of a string.
+";
+    /* Nor
+    //@ this, in a comment. */
+}
+```
+"#;
+
+pub const ESCAPED_RS: &'static str = r#"//@ A block with comments that look like prose.
+
+//@@code
+//@ Not prose, but a comment.
+fn main() {
+    let s = "
+//@ Not prose either, but part of a string.
+";
+    /* Nor
+    //@ this, in a comment. */
+}
+"#;
+
+pub const SNIPPETS_RS: &'static str = r##"//@ Snippets, as in this very file.
+pub const PROSE_RS: &'static str = r#"//@ Prose.
+
+//@ More prose.
+fn main() {}
+"#;
+"##;
+
+pub const SNIPPETS_MD: &'static str = r##"Snippets, as in this very file.
+```rust
+pub const PROSE_RS: &'static str = r#"//@ Prose.
+
+//@ More prose.
+fn main() {}
+"#;
+```
+"##;
+
+pub const NESTED_RS: &'static str = r#"//@ A method, with commentary.
+impl Counter {
+    fn next(&mut self) -> usize {
+        let n = self.count;
+        //@ The count goes up only after we have read it,
+        //@ so the first call returns zero.
+        //@
+        //@ That is all.
+        self.count += 1;
+        n
+    }
+}
+"#;
+
+pub const NESTED_MD: &'static str = r#"A method, with commentary.
+```rust
+impl Counter {
+    fn next(&mut self) -> usize {
+        let n = self.count;
+```
+<!-- tango: indent 8 -->
+The count goes up only after we have read it,
+so the first call returns zero.
+
+That is all.
+```rust
+        self.count += 1;
+        n
+    }
+}
+```
+"#;
@@ -0,0 +1,157 @@
+// `tango watch`: keep every `.rs`/`.md` pair in sync while the user
+// edits either side.
+//
+// We poll rather than subscribe to filesystem events; tango already
+// reasons entirely in terms of modification times and content, and a
+// poll of the source tree is cheap compared to an editor save.
+//
+// Each round compares a snapshot of `(mtime, length)` for every
+// candidate file against the previous one. Once a change is seen we
+// keep polling until the tree has been quiet for `QUIET_MS`, so that a
+// burst of saves (or an editor's write-rename-chmod dance) becomes a
+// single run, and then hand just the changed paths to
+// `process_paths_writing`.
+//
+// Tango's own writes must not be mistaken for edits, or we would
+// chase our tail. Two things prevent that: after every run, the files
+// tango wrote (with the backdated mtimes that `generate_content` gives
+// them) are taken into the snapshot from before the run, which becomes
+// the new baseline; and even if one slips through, `tango.manifest`
+// shows its content unchanged, so the resulting run is a no-op. Only
+// those files are: anything else saved while tango was running shows
+// up as changed in the next round.
+
+use std::collections::BTreeMap;
+use std::env;
+use std::path::PathBuf;
+use std::thread;
+use std::time::Duration;
+
+use walkdir::WalkDir;
+
+use super::{Config, Error, Result, Root};
+use super::files;
+use super::process_paths_writing;
+use super::timestamp::{Timestamp, Timestamped};
+
+const POLL_MS: u64 = 250;
+const QUIET_MS: u64 = 400;
+
+type Snapshot = BTreeMap<PathBuf, (Timestamp, u64)>;
+
+pub fn watch_root() -> Result<()> {
+    watch_root_with_config(try!(Config::load(try!(env::current_dir()))))
+}
+
+pub fn watch_root_with_config(config: Config) -> Result<()> {
+    println!("tango watching for changes");
+    let roots = Root::all(&config);
+    let snapshot = || snapshot(&roots);
+    let mut last = try!(snapshot());
+    loop {
+        thread::sleep(Duration::from_millis(POLL_MS));
+        let now = try!(snapshot());
+        let mut changed = changes(&last, &now);
+        if changed.is_empty() {
+            continue;
+        }
+
+        // Debounce: wait for the burst of saves to settle.
+        let mut settled = now;
+        loop {
+            thread::sleep(Duration::from_millis(QUIET_MS));
+            let next = try!(snapshot());
+            let more = changes(&settled, &next);
+            settled = next;
+            if more.is_empty() {
+                break;
+            }
+            for p in more {
+                if !changed.contains(&p) {
+                    changed.push(p);
+                }
+            }
+        }
+
+        for p in &changed {
+            println!("changed: {}", p.display());
+        }
+        last = settled;
+        let mut written = Vec::new();
+        let outcome = process_paths_writing(&changed, &config, &mut written);
+        for p in written {
+            if let Ok(m) = p.metadata() {
+                last.insert(p, (m.timestamp(), m.len()));
+            }
+        }
+        match outcome {
+            Ok(()) => {}
+            Err(Error::ConcurrentUpdate { path_buf, old_time, new_time }) => {
+                // The file was edited again while we were converting
+                // it; forget its snapshot so that the next round picks
+                // it up afresh.
+                println!("concurrent update to {} during sync ({} became {}); will retry",
+                         path_buf.display(),
+                         old_time.date_fulltime_badly(),
+                         new_time.date_fulltime_badly());
+                last.remove(&path_buf);
+            }
+            Err(e) => {
+                println!("tango: {}", e);
+            }
+        }
+    }
+}
+
+fn snapshot(roots: &[Root]) -> Result<Snapshot> {
+    let mut snap = BTreeMap::new();
+    let mut dirs = Vec::new();
+    for root in roots {
+        dirs.push(&root.src);
+        if root.lit != root.src {
+            dirs.push(&root.lit);
+        }
+    }
+    for dir in dirs {
+        if !dir.exists() {
+            continue;
+        }
+        for ent in WalkDir::new(dir).into_iter() {
+            let ent = try!(ent);
+            let p = ent.path();
+            let interesting = match (p.file_name().and_then(|x| x.to_str()),
+                                     p.extension().and_then(|x| x.to_str())) {
+                (Some(name), Some("rs")) |
+                (Some(name), Some("md")) => !name.starts_with('.'),
+                _ => false,
+            };
+            if !interesting {
+                continue;
+            }
+            // The file may vanish between listing and `metadata`.
+            if let Ok(m) = p.metadata() {
+                snap.insert(p.to_path_buf(), (m.timestamp(), m.len()));
+            }
+            // Files that a `.md` sends code to (see `files`) may be
+            // anywhere in the crate, and count as edits to the `.rs`.
+            if p.extension().and_then(|x| x.to_str()) == Some("rs") {
+                for c in files::companions(p).unwrap_or_default() {
+                    if let Ok(m) = c.metadata() {
+                        snap.insert(c, (m.timestamp(), m.len()));
+                    }
+                }
+            }
+        }
+    }
+    Ok(snap)
+}
+
+// Paths that were added or modified between `old` and `new`. (Deleted
+// files are not reported; tango never deletes the other side of a
+// pair.)
+fn changes(old: &Snapshot, new: &Snapshot) -> Vec<PathBuf> {
+    new.iter()
+        .filter(|&(p, stat)| old.get(p) != Some(stat))
+        .map(|(p, _)| p.clone())
+        .collect()
+}
@@ -19,7 +19,7 @@ use std::fmt;
 use std::fs::{self, File};
 use std::io::{self, Read, Write};
 use std::path::{PathBuf};
-use std::process::{Command};
+use std::process::{Command, Output, Stdio};
 
 const BINARY_FILENAME: &'static str = "tango";
 const PRESERVE_TEMP_DIRS: bool = false;
@@ -322,6 +322,43 @@ fn run_tango() -> Result<(), TangoRunError> {
     })
 }
 
+// Runs `tango` with `args`, handing back its exit status and output
+// rather than interpreting them.
+fn run_tango_with_args(args: &[&str]) -> Result<Output, TangoRunError> {
+    CURRENT_DIR_PREFIX.with(|p| -> Result<Output, TangoRunError> {
+        let p = p.borrow_mut();
+        let output = try!(Command::new(infer_target_binary())
+                          .args(args)
+                          .current_dir(&*p)
+                          .output());
+        for line in String::from_utf8_lossy(&output.stdout).lines() {
+            println!("stdout: {}", line);
+        }
+        for line in String::from_utf8_lossy(&output.stderr).lines() {
+            println!("stderr: {}", line);
+        }
+        Ok(output)
+    })
+}
+
+fn run_tango_with_input(args: &[&str], input: &str) -> Result<Output, TangoRunError> {
+    CURRENT_DIR_PREFIX.with(|p| -> Result<Output, TangoRunError> {
+        let p = p.borrow_mut();
+        let mut child = try!(Command::new(infer_target_binary())
+                             .args(args)
+                             .current_dir(&*p)
+                             .stdin(Stdio::piped())
+                             .stdout(Stdio::piped())
+                             .spawn());
+        try!(child.stdin.take().unwrap().write_all(input.as_bytes()));
+        let output = try!(child.wait_with_output());
+        for line in String::from_utf8_lossy(&output.stdout).lines() {
+            println!("stdout: {}", line);
+        }
+        Ok(output)
+    })
+}
+
 fn report_dir_contents(prefix: &str) {
     if !REPORT_DIR_CONTENTS { return; }
     CURRENT_DIR_PREFIX.with(|p| {
@@ -544,11 +581,13 @@ fn stamped_then_touch_lit() {
         },
         run: run_tango,
         post: || {
+            // Touching does not change content, so `tango.manifest`
+            // tells us there is nothing to regenerate.
             assert!(Target::Lit.path_buf("foo.md").exists());
             assert!(Target::Src.path_buf("foo.rs").exists());
             let rs_t = try!(Target::Src.path_buf("foo.rs").metadata()).timestamp();
             let md_t = try!(Target::Lit.path_buf("foo.md").metadata()).timestamp();
-            assert!(TIME_B2 == rs_t, "rs_t: {:?} TIME_B2: {:?}", rs_t, TIME_B2);
+            assert!(TIME_B1 == rs_t, "rs_t: {:?} TIME_B1: {:?}", rs_t, TIME_B1);
             assert!(TIME_B2 == md_t, "md_t: {:?} TIME_B2: {:?}", md_t, TIME_B2);
             // TODO: check contents
             Ok(())
@@ -585,7 +624,7 @@ fn stamped_then_touch_src() {
             let rs_t = try!(Target::Src.path_buf("foo.rs").metadata()).timestamp();
             let md_t = try!(Target::Lit.path_buf("foo.md").metadata()).timestamp();
             assert!(TIME_B2 == rs_t, "rs_t: {:?} TIME_B2: {:?}", rs_t, TIME_B2);
-            assert!(TIME_B2 == md_t, "md_t: {:?} TIME_B2: {:?}", md_t, TIME_B2);
+            assert!(TIME_B1 == md_t, "md_t: {:?} TIME_B1: {:?}", md_t, TIME_B1);
             // TODO: check contents
             Ok(())
         }
@@ -640,3 +679,974 @@ fn stamped_then_update_src() {
         }
     }).unwrap_or_panic("test error")
 }
+
+#[test]
+fn stamped_then_checkout_scrambles_mtimes() {
+    framework(Test {
+        name: "stamped_then_checkout_scrambles_mtimes",
+        setup: || {
+            try!(create_file(Target::Lit, "foo.md", HELLO_WORLD_MD, TIME_A1));
+            try!(run_tango());
+            // Simulate a `git checkout` that rewrites both files with
+            // unchanged content, in the "wrong" order.
+            try!(touch_file(Target::Src, "foo.rs", TIME_C1));
+            try!(touch_file(Target::Lit, "foo.md", TIME_B1));
+            touch_file(Target::Root, tango::STAMP, TIME_A2)
+        },
+        pre: || {
+            assert!(Target::Root.path_buf(tango::MANIFEST).exists());
+            Ok(())
+        },
+        run: run_tango,
+        post: || {
+            let rs_t = try!(Target::Src.path_buf("foo.rs").metadata()).timestamp();
+            let md_t = try!(Target::Lit.path_buf("foo.md").metadata()).timestamp();
+            assert!(TIME_C1 == rs_t, "rs_t: {:?} TIME_C1: {:?}", rs_t, TIME_C1);
+            assert!(TIME_B1 == md_t, "md_t: {:?} TIME_B1: {:?}", md_t, TIME_B1);
+            let mut s = String::new();
+            try!(try!(File::open(Target::Lit.path_buf("foo.md"))).read_to_string(&mut s));
+            assert!(s == HELLO_WORLD_MD);
+            // The run succeeded (rather than reporting divergence), so
+            // the manifest now records the new modification times.
+            let mut s = String::new();
+            try!(try!(File::open(Target::Root.path_buf(tango::MANIFEST))).read_to_string(&mut s));
+            assert!(s.contains(&format!("{}.{:09}", TIME_C1.secs, TIME_C1.nsecs)), "manifest: {}", s);
+            Ok(())
+        }
+    }).unwrap_or_panic("test error")
+}
+
+#[test]
+fn bare_stamp_migrates_to_manifest() {
+    framework(Test {
+        name: "bare_stamp_migrates_to_manifest",
+        setup: || {
+            try!(create_file(Target::Root, tango::STAMP, "", TIME_A1));
+            try!(create_file(Target::Src, "foo.rs", HELLO_WORLD_RS, TIME_B1));
+            Ok(())
+        },
+        pre: || {
+            assert!(!Target::Root.path_buf(tango::MANIFEST).exists());
+            Ok(())
+        },
+        run: run_tango,
+        post: || {
+            assert!(Target::Root.path_buf(tango::STAMP).exists());
+            let mut s = String::new();
+            try!(try!(File::open(Target::Root.path_buf(tango::MANIFEST))).read_to_string(&mut s));
+            assert!(s.contains("src/foo.rs"), "manifest: {}", s);
+            assert!(s.contains("src/foo.md"), "manifest: {}", s);
+            Ok(())
+        }
+    }).unwrap_or_panic("test error")
+}
+
+const MERGE_BASE_MD: &'static str = "Intro.
+
+```rust
+fn main() { }
+```
+";
+
+const MERGE_OURS_MD: &'static str = "Intro, edited in the markdown.
+
+```rust
+fn main() { }
+```
+";
+
+const MERGE_THEIRS_RS: &'static str = "//@ Intro.
+
+fn main() { println!(\"edited in the IDE\"); }
+";
+
+const MERGE_RESULT_MD: &'static str = "Intro, edited in the markdown.
+
+```rust
+fn main() { println!(\"edited in the IDE\"); }
+```
+";
+
+#[test]
+fn stamped_then_update_both() {
+    framework(Test {
+        name: "stamped_then_update_both",
+        setup: || {
+            try!(create_file(Target::Lit, "foo.md", MERGE_BASE_MD, TIME_A1));
+            try!(run_tango());
+            let mut f = try!(File::create(Target::Lit.path_buf("foo.md")));
+            try!(write!(f, "{}", MERGE_OURS_MD));
+            drop(f);
+            try!(touch_file(Target::Lit, "foo.md", TIME_B1));
+            let mut f = try!(File::create(Target::Src.path_buf("foo.rs")));
+            try!(write!(f, "{}", MERGE_THEIRS_RS));
+            drop(f);
+            touch_file(Target::Src, "foo.rs", TIME_B2)
+        },
+        pre: || {
+            assert!(Target::Root.path_buf(tango::BASE_DIR).exists());
+            Ok(())
+        },
+        run: run_tango,
+        post: || {
+            let mut s = String::new();
+            try!(try!(File::open(Target::Lit.path_buf("foo.md"))).read_to_string(&mut s));
+            assert!(s == MERGE_RESULT_MD, "merged md: {}", s);
+            let mut s = String::new();
+            try!(try!(File::open(Target::Src.path_buf("foo.rs"))).read_to_string(&mut s));
+            assert!(s.contains("Intro, edited in the markdown."), "merged rs: {}", s);
+            assert!(s.contains("edited in the IDE"), "merged rs: {}", s);
+            Ok(())
+        }
+    }).unwrap_or_panic("test error")
+}
+
+const MERGE_UNUSED_MD: &'static str = "Intro.
+
+```rust
+fn main() { }
+```
+
+```rust
+<<spare>>=
+fn spare() { }
+```
+";
+
+const MERGE_INTRO_RS: &'static str = "//@ Intro, edited in the IDE.
+
+fn main() { }
+";
+
+#[test]
+fn stamped_then_update_both_with_denied_warning() {
+    framework(Test {
+        name: "stamped_then_update_both_with_denied_warning",
+        setup: || {
+            try!(create_file(Target::Lit, "foo.md", MERGE_BASE_MD, TIME_A1));
+            try!(run_tango());
+            let mut f = try!(File::create(Target::Lit.path_buf("foo.md")));
+            try!(write!(f, "{}", MERGE_UNUSED_MD));
+            drop(f);
+            try!(touch_file(Target::Lit, "foo.md", TIME_B1));
+            let mut f = try!(File::create(Target::Src.path_buf("foo.rs")));
+            try!(write!(f, "{}", MERGE_INTRO_RS));
+            drop(f);
+            touch_file(Target::Src, "foo.rs", TIME_B2)
+        },
+        pre: || Ok(()),
+        run: || {
+            // The merged `.md` defines a chunk it never uses, which is
+            // denied as it would be for a plain generate.
+            let output = try!(run_tango_with_args(&[]));
+            assert!(!output.status.success());
+            let stdout = String::from_utf8_lossy(&output.stdout);
+            assert!(stdout.contains("chunk <<spare>> is never used"), "stdout: {}", stdout);
+            Ok(())
+        },
+        post: || {
+            let mut s = String::new();
+            try!(try!(File::open(Target::Lit.path_buf("foo.md"))).read_to_string(&mut s));
+            assert!(s == MERGE_UNUSED_MD, "md: {}", s);
+            let mut s = String::new();
+            try!(try!(File::open(Target::Src.path_buf("foo.rs"))).read_to_string(&mut s));
+            assert!(s == MERGE_INTRO_RS, "rs: {}", s);
+            Ok(())
+        }
+    }).unwrap_or_panic("test error")
+}
+
+#[test]
+fn stamped_then_status() {
+    framework(Test {
+        name: "stamped_then_status",
+        setup: || {
+            try!(create_file(Target::Lit, "foo.md", HELLO_WORLD_MD, TIME_A1));
+            try!(create_file(Target::Lit, "bar.md", HELLO_WORLD_MD, TIME_A1));
+            try!(run_tango());
+            let mut f = try!(File::create(Target::Lit.path_buf("foo.md")));
+            try!(write!(f, "{}", HELLO_WORLD2_MD));
+            drop(f);
+            touch_file(Target::Lit, "foo.md", TIME_B1)
+        },
+        pre: || Ok(()),
+        run: || {
+            let output = try!(run_tango_with_args(&["status"]));
+            assert_eq!(output.status.code(), Some(1));
+            let stdout = String::from_utf8_lossy(&output.stdout);
+            assert!(stdout.contains("md->rs src/foo.rs <-> src/foo.md"), "{}", stdout);
+            assert!(stdout.contains("in-sync src/bar.rs <-> src/bar.md"), "{}", stdout);
+            assert!(stdout.contains("TransformNeed::Needed"), "{}", stdout);
+
+            let output = try!(run_tango_with_args(&["status", "--message-format=json"]));
+            assert_eq!(output.status.code(), Some(1));
+            let stdout = String::from_utf8_lossy(&output.stdout);
+            assert!(stdout.starts_with("{\"writes_pending\":true,"), "{}", stdout);
+            assert!(stdout.contains("\"state\":\"md->rs\""), "{}", stdout);
+
+            // `--json`, as `status` once took, still means the same.
+            let legacy = try!(run_tango_with_args(&["status", "--json"]));
+            assert_eq!(legacy.stdout, output.stdout);
+            Ok(())
+        },
+        post: || {
+            // `status` must not have regenerated anything.
+            let mut s = String::new();
+            try!(try!(File::open(Target::Src.path_buf("foo.rs"))).read_to_string(&mut s));
+            assert!(s == HELLO_WORLD_RS, "foo.rs: {}", s);
+            try!(run_tango());
+            let output = try!(run_tango_with_args(&["status"]));
+            assert_eq!(output.status.code(), Some(0));
+            Ok(())
+        }
+    }).unwrap_or_panic("test error")
+}
+
+#[test]
+fn watch_then_update_lit() {
+    use std::thread;
+    use std::time::Duration;
+
+    framework(Test {
+        name: "watch_then_update_lit",
+        setup: || {
+            try!(create_file(Target::Lit, "foo.md", HELLO_WORLD_MD, TIME_A1));
+            run_tango()
+        },
+        pre: || Ok(()),
+        run: || {
+            let mut child = try!(CURRENT_DIR_PREFIX.with(|p| {
+                Command::new(infer_target_binary())
+                    .arg("watch")
+                    .current_dir(&*p.borrow())
+                    .stdout(::std::process::Stdio::null())
+                    .spawn()
+            }));
+            thread::sleep(Duration::from_millis(1000));
+            let mut f = try!(File::create(Target::Lit.path_buf("foo.md")));
+            try!(write!(f, "{}", HELLO_WORLD2_MD));
+            drop(f);
+
+            let rs_path = Target::Src.path_buf("foo.rs");
+            let mut synced = false;
+            for _ in 0..40 {
+                thread::sleep(Duration::from_millis(250));
+                let mut s = String::new();
+                try!(try!(File::open(&rs_path)).read_to_string(&mut s));
+                if s == HELLO_WORLD2_RS {
+                    synced = true;
+                    break;
+                }
+            }
+            // Once synced, the watcher must not keep rewriting its own
+            // output.
+            let before = try!(rs_path.metadata()).timestamp();
+            thread::sleep(Duration::from_millis(1500));
+            let after = try!(rs_path.metadata()).timestamp();
+            try!(child.kill());
+            try!(child.wait());
+            assert!(synced, "watcher did not regenerate foo.rs");
+            assert!(before == after, "foo.rs rewritten: {:?} then {:?}", before, after);
+            Ok(())
+        },
+        post: || Ok(()),
+    }).unwrap_or_panic("test error")
+}
+
+#[test]
+fn unstamped_and_tests_and_build_script() {
+    framework(Test {
+        name: "unstamped_and_tests_and_build_script",
+        setup: || {
+            try!(fs::create_dir(Target::Root.path_buf("tests")));
+            try!(create_file(Target::Root, "tests/it.md", HELLO_WORLD_MD, TIME_B1));
+            try!(create_file(Target::Root, "build.rs", HELLO_WORLD_RS, TIME_B1));
+            Ok(())
+        },
+        pre: || {
+            assert!(!Target::Root.path_buf("tests/it.rs").exists());
+            assert!(!Target::Root.path_buf("build.md").exists());
+            Ok(())
+        },
+        run: run_tango,
+        post: || {
+            let mut s = String::new();
+            try!(try!(File::open(Target::Root.path_buf("tests/it.rs"))).read_to_string(&mut s));
+            assert!(s == HELLO_WORLD_RS, "tests/it.rs: {}", s);
+            let mut s = String::new();
+            try!(try!(File::open(Target::Root.path_buf("build.md"))).read_to_string(&mut s));
+            assert!(s == HELLO_WORLD_MD, "build.md: {}", s);
+            Ok(())
+        },
+    }).unwrap_or_panic("test error")
+}
+
+#[test]
+fn unstamped_two_roots_concurrently() {
+    framework(Test {
+        name: "unstamped_two_roots_concurrently",
+        setup: || {
+            try!(create_file(Target::Src, "foo.rs", HELLO_WORLD_RS, TIME_B1));
+            try!(fs::create_dir_all(Target::Root.path_buf("other/src")));
+            try!(create_file(Target::Root, "other/src/bar.md", HELLO_WORLD2_MD, TIME_B1));
+            Ok(())
+        },
+        pre: || Ok(()),
+        run: || {
+            // Drive the library directly, once per root, from two
+            // threads at once; neither may depend on the current
+            // directory.
+            let roots = vec![Target::Root.path_buf(""), Target::Root.path_buf("other")];
+            let threads: Vec<_> = roots.into_iter().map(|root| {
+                std::thread::spawn(move || {
+                    let mut config = tango::Config::new();
+                    config.root(&root);
+                    tango::process_root_with_config(config)
+                        .unwrap_or_else(|e| panic!("tango failed on {:?}: {}", root, e));
+                })
+            }).collect();
+            for t in threads {
+                t.join().expect("tango thread panicked");
+            }
+            Ok(())
+        },
+        post: || {
+            let mut s = String::new();
+            try!(try!(File::open(Target::Lit.path_buf("foo.md"))).read_to_string(&mut s));
+            assert!(s == HELLO_WORLD_MD, "foo.md: {}", s);
+            let mut s = String::new();
+            try!(try!(File::open(Target::Root.path_buf("other/src/bar.rs"))).read_to_string(&mut s));
+            assert!(s == HELLO_WORLD2_RS, "other/src/bar.rs: {}", s);
+            assert!(Target::Root.path_buf("tango.manifest").exists());
+            assert!(Target::Root.path_buf("other/tango.manifest").exists());
+            assert!(!Target::Src.path_buf("bar.rs").exists());
+            Ok(())
+        },
+    }).unwrap_or_panic("test error")
+}
+
+#[test]
+fn unstamped_and_tango_toml() {
+    framework(Test {
+        name: "unstamped_and_tango_toml",
+        setup: || {
+            try!(create_file(Target::Root, "tango.toml",
+                             "lit-dir = \"lit\"\n\
+                              exclude = [\"src/skip.rs\"]\n\
+                              stamp = \"tango.timestamp\"\n",
+                             TIME_A1));
+            try!(fs::create_dir(Target::Root.path_buf("lit")));
+            try!(create_file(Target::Root, "lit/foo.md", HELLO_WORLD_MD, TIME_B1));
+            try!(create_file(Target::Src, "skip.rs", HELLO_WORLD2_RS, TIME_B1));
+            Ok(())
+        },
+        pre: || Ok(()),
+        run: run_tango,
+        post: || {
+            let mut s = String::new();
+            try!(try!(File::open(Target::Src.path_buf("foo.rs"))).read_to_string(&mut s));
+            assert!(s == HELLO_WORLD_RS, "src/foo.rs: {}", s);
+            assert!(!Target::Root.path_buf("lit/skip.md").exists());
+            assert!(!Target::Src.path_buf("skip.md").exists());
+            assert!(Target::Root.path_buf("tango.timestamp").exists());
+            assert!(!Target::Root.path_buf("tango.stamp").exists());
+            Ok(())
+        },
+    }).unwrap_or_panic("test error")
+}
+
+#[test]
+fn state_beside_configured_stamp() {
+    framework(Test {
+        name: "state_beside_configured_stamp",
+        setup: || {
+            try!(create_file(Target::Root, "tango.toml",
+                             "stamp = \"state/tango.stamp\"\n",
+                             TIME_A1));
+            try!(fs::create_dir(Target::Root.path_buf("state")));
+            try!(create_file(Target::Src, "foo.md", HELLO_WORLD_MD, TIME_B1));
+            Ok(())
+        },
+        pre: || Ok(()),
+        run: run_tango,
+        post: || {
+            let state = Target::Root.path_buf("state");
+            assert!(state.join(tango::STAMP).exists());
+            assert!(state.join(tango::MANIFEST).exists());
+            assert!(state.join(tango::BASE_DIR).join("src/foo.md").exists());
+            assert!(!Target::Root.path_buf(tango::MANIFEST).exists());
+            assert!(!Target::Root.path_buf(tango::BASE_DIR).exists());
+            Ok(())
+        },
+    }).unwrap_or_panic("test error")
+}
+
+#[test]
+fn invalid_cargo_metadata_names_key() {
+    framework(Test {
+        name: "invalid_cargo_metadata_names_key",
+        setup: || {
+            try!(create_file(Target::Root, "Cargo.toml",
+                             "[package]\nname = \"demo\"\n\n\
+                              [package.metadata.tango]\nwarnings = \"loud\"\n",
+                             TIME_A1));
+            try!(create_file(Target::Src, "foo.rs", HELLO_WORLD_RS, TIME_B1));
+            Ok(())
+        },
+        pre: || Ok(()),
+        run: || {
+            let output = try!(run_tango_with_args(&[]));
+            assert!(!output.status.success());
+            let stdout = String::from_utf8_lossy(&output.stdout);
+            assert!(stdout.contains("`package.metadata.tango.warnings`"), "stdout: {}", stdout);
+            Ok(())
+        },
+        post: || {
+            assert!(!Target::Src.path_buf("foo.md").exists());
+            Ok(())
+        },
+    }).unwrap_or_panic("test error")
+}
+
+const FILES_LIB_MD: &'static str = "# A crate in one document
+
+```rust
+pub use parser::parse;
+```
+
+```{.rust file=\"parser.rs\"}
+pub fn parse() { lexer::lex() }
+```
+
+```{.rust file=\"parser/lexer.rs\"}
+pub fn lex() { }
+```
+";
+
+const FILES_LEXER_EDITED_RS: &'static str = "//@@tangled from src/lib.md
+pub fn lex() { println!(\"edited in the IDE\"); }
+";
+
+#[test]
+fn stamped_then_update_produced_file() {
+    framework(Test {
+        name: "stamped_then_update_produced_file",
+        setup: || {
+            try!(create_file(Target::Lit, "lib.md", FILES_LIB_MD, TIME_A1));
+            try!(run_tango());
+            let mut f = try!(File::create(Target::Src.path_buf("parser/lexer.rs")));
+            try!(write!(f, "{}", FILES_LEXER_EDITED_RS));
+            drop(f);
+            touch_file(Target::Src, "parser/lexer.rs", TIME_B1)
+        },
+        pre: || {
+            let mut s = String::new();
+            try!(try!(File::open(Target::Src.path_buf("parser.rs"))).read_to_string(&mut s));
+            assert!(s.contains("#[path = \"parser/lexer.rs\"]\nmod lexer;"), "parser.rs: {}", s);
+            let mut s = String::new();
+            try!(try!(File::open(Target::Src.path_buf("lib.rs"))).read_to_string(&mut s));
+            assert!(s.contains("#[path = \"parser.rs\"]\nmod parser;"), "lib.rs: {}", s);
+            assert!(!s.contains("pub fn lex"), "lib.rs: {}", s);
+            Ok(())
+        },
+        run: run_tango,
+        post: || {
+            let mut s = String::new();
+            try!(try!(File::open(Target::Lit.path_buf("lib.md"))).read_to_string(&mut s));
+            assert!(s == FILES_LIB_MD.replace("pub fn lex() { }",
+                                              "pub fn lex() { println!(\"edited in the IDE\"); }"),
+                    "lib.md: {}", s);
+            assert!(!Target::Lit.path_buf("parser/lexer.md").exists());
+            assert!(!Target::Lit.path_buf("parser.md").exists());
+            Ok(())
+        }
+    }).unwrap_or_panic("test error")
+}
+
+const ASSETS_LIB_MD: &'static str = "# Test data
+
+```toml file=\"../fixtures/data.toml\"
+answer = 42
+```
+
+```rust
+pub const DATA: &'static str = include_str!(\"../fixtures/data.toml\");
+```
+";
+
+#[test]
+fn stamped_then_update_asset() {
+    framework(Test {
+        name: "stamped_then_update_asset",
+        setup: || {
+            try!(create_file(Target::Lit, "lib.md", ASSETS_LIB_MD, TIME_A1));
+            try!(run_tango());
+            let mut f = try!(File::create(Target::Root.path_buf("fixtures/data.toml")));
+            try!(write!(f, "answer = 43\n"));
+            drop(f);
+            touch_file(Target::Root, "fixtures/data.toml", TIME_B1)
+        },
+        pre: || {
+            let mut s = String::new();
+            try!(try!(File::open(Target::Src.path_buf("lib.rs"))).read_to_string(&mut s));
+            assert!(!s.contains("answer"), "lib.rs: {}", s);
+            Ok(())
+        },
+        run: run_tango,
+        post: || {
+            let mut s = String::new();
+            try!(try!(File::open(Target::Lit.path_buf("lib.md"))).read_to_string(&mut s));
+            assert!(s == ASSETS_LIB_MD.replace("answer = 42", "answer = 43"), "lib.md: {}", s);
+            Ok(())
+        }
+    }).unwrap_or_panic("test error")
+}
+
+const CARGO_TOML_LIB_MD: &'static str = "# The manifest
+
+```toml file=\"../Cargo.toml\"
+[package]
+name = \"demo\"
+version = \"0.1.0\"
+```
+";
+
+#[test]
+fn unstamped_and_asset_adopted() {
+    framework(Test {
+        name: "unstamped_and_asset_adopted",
+        setup: || {
+            try!(create_file(Target::Root, "Cargo.toml",
+                             "[package]\nname = \"demo\"\nversion = \"0.0.1\"\n", TIME_A1));
+            try!(create_file(Target::Lit, "lib.md", CARGO_TOML_LIB_MD, TIME_A1));
+            Ok(())
+        },
+        pre: || Ok(()),
+        run: || {
+            // A `Cargo.toml` that differs from the block is left alone ...
+            let output = try!(run_tango_with_args(&[]));
+            assert!(!output.status.success());
+            let stdout = String::from_utf8_lossy(&output.stdout);
+            assert!(stdout.contains("already exists with other content"), "stdout: {}", stdout);
+            assert!(!Target::Src.path_buf("lib.rs").exists());
+
+            // ... and one that holds just what the block does is adopted.
+            try!(fs::remove_file(Target::Root.path_buf("Cargo.toml")));
+            try!(create_file(Target::Root, "Cargo.toml",
+                             "[package]\nname = \"demo\"\nversion = \"0.1.0\"\n", TIME_A1));
+            let output = try!(run_tango_with_args(&[]));
+            assert!(output.status.success(), "{:?}", output);
+            Ok(())
+        },
+        post: || {
+            let mut s = String::new();
+            try!(try!(File::open(Target::Src.path_buf("lib.rs"))).read_to_string(&mut s));
+            assert!(s.contains("//@@file ../Cargo.toml"), "lib.rs: {}", s);
+            let mut s = String::new();
+            try!(try!(File::open(Target::Root.path_buf("Cargo.toml"))).read_to_string(&mut s));
+            assert_eq!(s, "[package]\nname = \"demo\"\nversion = \"0.1.0\"\n");
+            Ok(())
+        },
+    }).unwrap_or_panic("test error")
+}
+
+#[test]
+fn unstamped_and_asset_escaping_crate() {
+    framework(Test {
+        name: "unstamped_and_asset_escaping_crate",
+        setup: || {
+            try!(create_file(Target::Lit, "lib.md",
+                             "```toml file=\"../../escaped.toml\"\nx = 1\n```\n", TIME_A1));
+            Ok(())
+        },
+        pre: || Ok(()),
+        run: || {
+            let output = try!(run_tango_with_args(&[]));
+            assert!(!output.status.success());
+            let stdout = String::from_utf8_lossy(&output.stdout);
+            assert!(stdout.contains("../../escaped.toml"), "stdout: {}", stdout);
+            Ok(())
+        },
+        post: || {
+            assert!(!Target::Root.path_buf("../escaped.toml").exists());
+            assert!(!Target::Src.path_buf("lib.rs").exists());
+            Ok(())
+        },
+    }).unwrap_or_panic("test error")
+}
+
+#[test]
+fn unstamped_and_file_block_escaping_crate() {
+    framework(Test {
+        name: "unstamped_and_file_block_escaping_crate",
+        setup: || {
+            try!(create_file(Target::Lit, "lib.md",
+                             "```{.rust file=\"../../escaped.rs\"}\nfn f() { }\n```\n", TIME_A1));
+            Ok(())
+        },
+        pre: || Ok(()),
+        run: || {
+            let output = try!(run_tango_with_args(&[]));
+            assert!(!output.status.success());
+            let stdout = String::from_utf8_lossy(&output.stdout);
+            assert!(stdout.contains("../../escaped.rs"), "stdout: {}", stdout);
+            Ok(())
+        },
+        post: || {
+            assert!(!Target::Root.path_buf("../escaped.rs").exists());
+            assert!(!Target::Src.path_buf("lib.rs").exists());
+            Ok(())
+        },
+    }).unwrap_or_panic("test error")
+}
+
+const DIAGNOSTICS_LIB_MD: &'static str = "# Diagnostics
+
+```rust
+pub fn f() -> u32 {
+    <<body>>
+}
+```
+
+The body:
+
+```rust
+<<body>>=
+let x: u32 = \"a\";
+x
+```
+";
+
+// What `cargo build --message-format=json` says about the `lib.rs`
+// that tango generates from `DIAGNOSTICS_LIB_MD`, less some detail.
+const DIAGNOSTICS_JSON: &'static str = r#"{"reason":"compiler-message","message":{"message":"mismatched types","code":{"code":"E0308","explanation":null},"level":"error","spans":[{"file_name":"src/lib.rs","byte_start":0,"byte_end":0,"line_start":5,"line_end":5,"column_start":18,"column_end":21,"is_primary":true,"text":[{"text":"    let x: u32 = \"a\";","highlight_start":18,"highlight_end":21}],"label":"expected `u32`, found `&str`","suggested_replacement":null,"suggestion_applicability":null,"expansion":null}],"children":[],"rendered":"error[E0308]: mismatched types\n --> src/lib.rs:5:18\n"}}
+{"reason":"build-finished","success":false}
+"#;
+
+#[test]
+fn stamped_then_diagnostics() {
+    framework(Test {
+        name: "stamped_then_diagnostics",
+        setup: || {
+            try!(create_file(Target::Lit, "lib.md", DIAGNOSTICS_LIB_MD, TIME_A1));
+            try!(run_tango());
+            let mut s = String::new();
+            try!(try!(File::open(Target::Src.path_buf("lib.rs"))).read_to_string(&mut s));
+            assert_eq!(s.lines().nth(4), Some("    let x: u32 = \"a\";"));
+            Ok(())
+        },
+        pre: || Ok(()),
+        run: || {
+            let output = try!(run_tango_with_input(&["diagnostics"], DIAGNOSTICS_JSON));
+            assert!(output.status.success());
+            let stdout = String::from_utf8_lossy(&output.stdout);
+            assert!(stdout.starts_with("error[E0308]: mismatched types\n  --> src/lib.md:13:14\n"),
+                    "stdout: {}", stdout);
+            assert!(stdout.contains("13 | let x: u32 = \"a\";\n   |              ^^^ expected"),
+                    "stdout: {}", stdout);
+
+            let output = try!(run_tango_with_input(&["diagnostics", "--message-format=json"], DIAGNOSTICS_JSON));
+            let stdout = String::from_utf8_lossy(&output.stdout);
+            let lines: Vec<&str> = stdout.lines().collect();
+            assert_eq!(lines.len(), 2);
+            for field in &["\"file_name\":\"src/lib.md\"", "\"line_start\":13",
+                           "\"column_start\":14", "\"column_end\":17",
+                           "\"byte_start\":105", "\"text\":\"let x: u32 = \\\"a\\\";\""] {
+                assert!(lines[0].contains(field), "{} not in {}", field, lines[0]);
+            }
+            assert_eq!(lines[1], "{\"reason\":\"build-finished\",\"success\":false}");
+            Ok(())
+        },
+        post: || Ok(()),
+    }).unwrap_or_panic("test error")
+}
+
+const FIX_LIB_MD: &'static str = "# Fixes
+
+```rust
+pub fn f() -> u32 {
+    <<body>>
+}
+```
+
+The body:
+
+```rust
+<<body>>=
+let y = 2;
+3
+```
+";
+
+// The suggestion rustc makes for the unused `y`, at line 5 of the
+// `lib.rs` generated from `FIX_LIB_MD`, and one it makes for a line
+// that no `.md` holds.
+const FIX_JSON: &'static str = r#"{"reason":"compiler-message","message":{"message":"unused variable: `y`","code":{"code":"unused_variables","explanation":null},"level":"warning","spans":[{"file_name":"src/lib.rs","byte_start":0,"byte_end":0,"line_start":5,"line_end":5,"column_start":9,"column_end":10,"is_primary":true,"text":[],"label":null,"suggested_replacement":null,"suggestion_applicability":null,"expansion":null}],"children":[{"message":"if this is intentional, prefix it with an underscore","code":null,"level":"help","spans":[{"file_name":"src/lib.rs","byte_start":0,"byte_end":0,"line_start":5,"line_end":5,"column_start":9,"column_end":10,"is_primary":true,"text":[],"label":null,"suggested_replacement":"_y","suggestion_applicability":"MachineApplicable","expansion":null}],"children":[],"rendered":null}],"rendered":""}}
+{"reason":"compiler-message","message":{"message":"elsewhere","code":null,"level":"warning","spans":[],"children":[{"message":"remove this","code":null,"level":"help","spans":[{"file_name":"src/other.rs","byte_start":0,"byte_end":0,"line_start":1,"line_end":1,"column_start":1,"column_end":2,"is_primary":true,"text":[],"label":null,"suggested_replacement":"","suggestion_applicability":"MachineApplicable","expansion":null}],"children":[],"rendered":null}],"rendered":""}}
+"#;
+
+#[test]
+fn stamped_then_fix() {
+    framework(Test {
+        name: "stamped_then_fix",
+        setup: || {
+            try!(create_file(Target::Lit, "lib.md", FIX_LIB_MD, TIME_A1));
+            run_tango()
+        },
+        pre: || {
+            let mut s = String::new();
+            try!(try!(File::open(Target::Src.path_buf("lib.rs"))).read_to_string(&mut s));
+            assert_eq!(s.lines().nth(4), Some("    let y = 2;"));
+            Ok(())
+        },
+        run: || {
+            let output = try!(run_tango_with_input(&["fix"], FIX_JSON));
+            assert!(output.status.success());
+            let stdout = String::from_utf8_lossy(&output.stdout);
+            assert!(stdout.contains("lib.md:13:5: if this is intentional"), "stdout: {}", stdout);
+            assert!(stdout.contains("applied 1 suggestion to 1 file; skipped 1"), "stdout: {}", stdout);
+            Ok(())
+        },
+        post: || {
+            let mut s = String::new();
+            try!(try!(File::open(Target::Lit.path_buf("lib.md"))).read_to_string(&mut s));
+            assert_eq!(s, FIX_LIB_MD.replace("let y", "let _y"));
+            let mut s = String::new();
+            try!(try!(File::open(Target::Src.path_buf("lib.rs"))).read_to_string(&mut s));
+            assert_eq!(s.lines().nth(4), Some("    let _y = 2;"));
+            // Nothing is left to sync.
+            let output = try!(run_tango_with_args(&["status"]));
+            assert!(output.status.success());
+            Ok(())
+        },
+    }).unwrap_or_panic("test error")
+}
+
+// A stale playground link, among lines that `--fix-links` must leave
+// exactly as they are.
+const STALE_LINK_MD: &'static str = "# Hello World\r
+\t
+```rust
+// Here is some content
+fn main() { }
+```
+[hello]: https://play.rust-lang.org/?code=does_not_match&version=nightly
+
+Trailing \t
+no newline at the end";
+
+#[test]
+fn unstamped_and_fix_links() {
+    framework(Test {
+        name: "unstamped_and_fix_links",
+        setup: || {
+            try!(create_file(Target::Lit, "lib.md", STALE_LINK_MD, TIME_A1));
+            Ok(())
+        },
+        pre: || Ok(()),
+        run: || {
+            let output = try!(run_tango_with_args(&["--fix-links"]));
+            assert!(output.status.success());
+            let stdout = String::from_utf8_lossy(&output.stdout);
+            assert!(stdout.contains("fixed link [hello] at src/lib.md:7\n"), "stdout: {}", stdout);
+            assert!(stdout.contains("fixed 1 link in 1 file\n"), "stdout: {}", stdout);
+            Ok(())
+        },
+        post: || {
+            let mut s = String::new();
+            try!(try!(File::open(Target::Lit.path_buf("lib.md"))).read_to_string(&mut s));
+            assert_eq!(s, STALE_LINK_MD.replace(
+                "does_not_match",
+                "%2F%2F%20Here%20is%20some%20content%0Afn%20main()%20%7B%20%7D"));
+            assert!(Target::Src.path_buf("lib.rs").exists());
+            Ok(())
+        },
+    }).unwrap_or_panic("test error")
+}
+
+#[test]
+fn fix_links_from_elsewhere() {
+    framework(Test {
+        name: "fix_links_from_elsewhere",
+        setup: || {
+            try!(create_file(Target::Lit, "lib.md",
+                             "\u{feff}[empty]: https://play.rust-lang.org/?code=stale&version=nightly\n",
+                             TIME_A1));
+            Ok(())
+        },
+        pre: || Ok(()),
+        run: || {
+            // The crate is not the current directory, and its `.md`
+            // starts with a BOM.
+            let config = tango::Config::load(Target::Root.path_buf("")).unwrap();
+            let mut output = Vec::new();
+            let fixed = tango::fix::fix_links(&config, &mut output).unwrap();
+            assert_eq!(fixed, vec![Target::Lit.path_buf("lib.md")]);
+            let output = String::from_utf8_lossy(&output);
+            assert!(output.contains("fixed link [empty] at src/lib.md:1\n"), "output: {}", output);
+            Ok(())
+        },
+        post: || {
+            let mut s = String::new();
+            try!(try!(File::open(Target::Lit.path_buf("lib.md"))).read_to_string(&mut s));
+            assert_eq!(s, "\u{feff}[empty]: https://play.rust-lang.org/?code=&version=nightly\n");
+            Ok(())
+        },
+    }).unwrap_or_panic("test error")
+}
+
+#[test]
+fn unstamped_and_warnings_as_json() {
+    framework(Test {
+        name: "unstamped_and_warnings_as_json",
+        setup: || {
+            try!(create_file(Target::Root, "tango.toml", "warnings = \"warn\"\n", TIME_A1));
+            try!(create_file(Target::Lit, "lib.md",
+                             "# Spare\n\n```rust\n<<spare>>=\nfn spare() { }\n```\n", TIME_A1));
+            Ok(())
+        },
+        pre: || Ok(()),
+        run: || {
+            let output = try!(run_tango_with_args(&["--message-format=json"]));
+            assert!(output.status.success());
+            let stdout = String::from_utf8_lossy(&output.stdout);
+            let expect = "{\"file\":\"src/lib.md\",\"line\":4,\"column_start\":1,\
+                          \"column_end\":11,\"severity\":\"warning\",\"code\":\"unused-chunk\",";
+            assert!(stdout.lines().any(|l| l.starts_with(expect)), "stdout: {}", stdout);
+            Ok(())
+        },
+        post: || {
+            assert!(Target::Src.path_buf("lib.rs").exists());
+            Ok(())
+        },
+    }).unwrap_or_panic("test error")
+}
+
+#[test]
+fn unstamped_and_warnings_denied() {
+    framework(Test {
+        name: "unstamped_and_warnings_denied",
+        setup: || {
+            try!(create_file(Target::Lit, "lib.md",
+                             "# Spare\n\n```rust\n<<spare>>=\nfn spare() { }\n```\n", TIME_A1));
+            Ok(())
+        },
+        pre: || Ok(()),
+        run: || {
+            let output = try!(run_tango_with_args(&[]));
+            assert!(!output.status.success());
+            let stdout = String::from_utf8_lossy(&output.stdout);
+            assert!(stdout.contains("error[unused-chunk]: chunk <<spare>> is never used; \
+                                     leaving its code where it is defined\n  --> src/lib.md:4:1\n"),
+                    "stdout: {}", stdout);
+            Ok(())
+        },
+        post: || {
+            assert!(!Target::Src.path_buf("lib.rs").exists());
+            Ok(())
+        },
+    }).unwrap_or_panic("test error")
+}
+
+#[test]
+fn unstamped_and_warnings_per_kind() {
+    framework(Test {
+        name: "unstamped_and_warnings_per_kind",
+        setup: || {
+            try!(create_file(Target::Root, "tango.toml",
+                             "[warnings]\ndefault = \"allow\"\nundefined-chunk = \"deny\"\n", TIME_A1));
+            try!(create_file(Target::Lit, "a.md",
+                             "# Missing\n\n```rust\nfn a() {\n    <<missing>>\n    <<body>>\n}\n```\n\n\
+                              ```rust\n<<body>>=\nlet x = 1;\n```\n", TIME_A1));
+            try!(create_file(Target::Lit, "b.md",
+                             "# Spare\n\n```rust\n<<spare>>=\nfn spare() { }\n```\n", TIME_A1));
+            Ok(())
+        },
+        pre: || Ok(()),
+        run: || {
+            let output = try!(run_tango_with_args(&["-W", "unused-chunk"]));
+            assert!(!output.status.success());
+            let stdout = String::from_utf8_lossy(&output.stdout);
+            // Both pairs are looked at before any warning is reported.
+            let generated = stdout.rfind("generating src").unwrap();
+            let warned = stdout.find("warning[unused-chunk]").expect(&stdout);
+            let denied = stdout.find("error[undefined-chunk]").expect(&stdout);
+            assert!(generated < warned && generated < denied, "stdout: {}", stdout);
+            assert!(stdout.contains("--> src/b.md:4:1"), "stdout: {}", stdout);
+            assert!(stdout.contains("--> src/a.md:5:5"), "stdout: {}", stdout);
+            Ok(())
+        },
+        post: || {
+            assert!(!Target::Src.path_buf("a.rs").exists());
+            assert!(Target::Src.path_buf("b.rs").exists());
+            Ok(())
+        },
+    }).unwrap_or_panic("test error")
+}
+
+#[test]
+fn line_endings_setting() {
+    framework(Test {
+        name: "line_endings_setting",
+        setup: || {
+            try!(create_file(Target::Lit, "a.md",
+                             "\u{feff}# Windows\r\n\r\n```rust\r\nfn a() { }\r\n```\r\n", TIME_A1));
+            Ok(())
+        },
+        pre: || Ok(()),
+        run: || {
+            // Left alone, the generated `.rs` is laid out like its `.md`.
+            let output = try!(run_tango_with_args(&[]));
+            assert!(output.status.success(), "{:?}", output);
+            let mut s = String::new();
+            try!(try!(File::open(Target::Src.path_buf("a.rs"))).read_to_string(&mut s));
+            assert_eq!(s, "\u{feff}//@ # Windows\r\n\r\nfn a() { }\r\n");
+
+            try!(create_file(Target::Root, "tango.toml", "line-endings = \"lf\"\n", TIME_A1));
+            try!(fs::remove_file(Target::Src.path_buf("a.rs")));
+            let output = try!(run_tango_with_args(&[]));
+            assert!(output.status.success(), "{:?}", output);
+            let mut s = String::new();
+            try!(try!(File::open(Target::Src.path_buf("a.rs"))).read_to_string(&mut s));
+            assert_eq!(s, "\u{feff}//@ # Windows\n\nfn a() { }\n");
+            Ok(())
+        },
+        post: || Ok(()),
+    }).unwrap_or_panic("test error")
+}
+
+const UNTERMINATED_LIB_MD: &'static str =
+    "Intro.\n\n```rust\nlet s = \"unterminated;\n```\n\nMore prose.\n\n```rust\nfn main() {}\n```\n";
+
+#[test]
+fn unterminated_literal_round_trip() {
+    framework(Test {
+        name: "unterminated_literal_round_trip",
+        setup: || {
+            try!(create_file(Target::Lit, "lib.md", UNTERMINATED_LIB_MD, TIME_A1));
+            Ok(())
+        },
+        pre: || Ok(()),
+        run: || {
+            let output = try!(run_tango_with_args(&[]));
+            assert!(output.status.success(), "{:?}", output);
+            let stdout = String::from_utf8_lossy(&output.stdout);
+            assert!(!stdout.contains("lossy-round-trip"), "stdout: {}", stdout);
+            let mut s = String::new();
+            try!(try!(File::open(Target::Src.path_buf("lib.rs"))).read_to_string(&mut s));
+            assert!(s.contains("let s = \"unterminated;\n//@@end\n"), "lib.rs: {}", s);
+
+            // The `.md` comes back from the `.rs` as it was.
+            try!(fs::remove_file(Target::Lit.path_buf("lib.md")));
+            let output = try!(run_tango_with_args(&[]));
+            assert!(output.status.success(), "{:?}", output);
+            Ok(())
+        },
+        post: || {
+            let mut s = String::new();
+            try!(try!(File::open(Target::Lit.path_buf("lib.md"))).read_to_string(&mut s));
+            assert_eq!(s, UNTERMINATED_LIB_MD);
+            Ok(())
+        },
+    }).unwrap_or_panic("test error")
+}